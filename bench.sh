@@ -6,3 +6,9 @@ cargo build --release
 cat ./naist-jdic/naist-jdic.csv | shuf -n 400000 | nkf > ./short.csv
 
 time ./target/release/namaco compile --dict ./short.csv --matrix ./naist-jdic/matrix.def --output dict
+
+# words of the dictionary joined by 8 as text to analyze
+cut -d, -f1 ./short.csv | shuf -r -n 400000 | paste -d '' - - - - - - - - > ./text.txt
+
+time ./target/release/namaco parse dict ./text.txt > /dev/null
+time ./target/release/namaco parse dict ./text.txt -O wakati > /dev/null
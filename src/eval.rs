@@ -0,0 +1,255 @@
+use std::io;
+use std::io::{BufRead, Read};
+
+#[derive(Fail, Debug)]
+pub enum CorpusLoadError {
+    #[fail(display = "failed to read line at {}", line)]
+    FailedToReadLine { line: usize },
+    #[fail(display = "missing tab separator at {}", line)]
+    InvalidLine { line: usize },
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Morpheme {
+    pub surface: String,
+    pub features: Vec<String>,
+}
+
+impl Morpheme {
+    pub fn new(surface: &str, features: &str) -> Self {
+        Morpheme {
+            surface: String::from(surface),
+            features: features.split(',').map(String::from).collect(),
+        }
+    }
+}
+
+// MeCab output format: one "surface\tfeature,feature,..." per line, "EOS" per sentence.
+// A trailing sentence without EOS is accepted.
+pub fn read_corpus<R: Read>(src: &mut R) -> Result<Vec<Vec<Morpheme>>, CorpusLoadError> {
+    let mut reader = io::BufReader::new(src);
+    let mut buf = String::new();
+    let mut line_cnt = 0usize;
+    let mut corpus = Vec::new();
+    let mut sentence = Vec::new();
+    loop {
+        line_cnt += 1;
+        buf.clear();
+        if reader
+            .read_line(&mut buf)
+            .map_err(|_| CorpusLoadError::FailedToReadLine { line: line_cnt })?
+            == 0
+        {
+            break;
+        }
+        let line = buf.trim_end_matches(&['\n', '\r'][..]);
+        if line == "EOS" {
+            corpus.push(sentence);
+            sentence = Vec::new();
+        } else if !line.is_empty() {
+            let mut splited_line = line.splitn(2, '\t');
+            let surface = splited_line.next().unwrap();
+            let features = splited_line
+                .next()
+                .ok_or(CorpusLoadError::InvalidLine { line: line_cnt })?;
+            sentence.push(Morpheme::new(surface, features));
+        }
+    }
+    if !sentence.is_empty() {
+        corpus.push(sentence);
+    }
+    Ok(corpus)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Diff<'a> {
+    Same(&'a Morpheme),
+    Gold(&'a Morpheme),
+    System(&'a Morpheme),
+}
+
+// (begin, end) in bytes of each morpheme
+fn spans(morphs: &[Morpheme]) -> Vec<(usize, usize)> {
+    let mut begin = 0usize;
+    morphs
+        .iter()
+        .map(|morph| {
+            let span = (begin, begin + morph.surface.len());
+            begin = span.1;
+            span
+        })
+        .collect()
+}
+
+#[derive(Debug, Default)]
+pub struct Evaluation {
+    // number of compared features. 0 compares all of them.
+    depth: usize,
+    pub sentences: usize,
+    pub failed_sentences: usize,
+    pub gold_tokens: usize,
+    pub system_tokens: usize,
+    pub correct_segments: usize,
+    pub correct_pos: usize,
+}
+
+impl Evaluation {
+    pub fn new(depth: usize) -> Self {
+        Evaluation {
+            depth,
+            ..Evaluation::default()
+        }
+    }
+
+    fn same_pos(&self, gold: &Morpheme, system: &Morpheme) -> bool {
+        if self.depth == 0 {
+            gold.features == system.features
+        } else {
+            gold.features
+                .iter()
+                .take(self.depth)
+                .eq(system.features.iter().take(self.depth))
+        }
+    }
+
+    // system is None if the analyzer failed to parse the sentence.
+    pub fn add<'a>(
+        &mut self,
+        gold: &'a [Morpheme],
+        system: Option<&'a [Morpheme]>,
+    ) -> Vec<Diff<'a>> {
+        self.sentences += 1;
+        let system = match system {
+            Some(system) => system,
+            None => {
+                self.failed_sentences += 1;
+                &[]
+            }
+        };
+        self.gold_tokens += gold.len();
+        self.system_tokens += system.len();
+
+        let gold_spans = spans(gold);
+        let system_spans = spans(system);
+        let mut diff = Vec::new();
+        let (mut i, mut j) = (0usize, 0usize);
+        while i < gold.len() || j < system.len() {
+            if i < gold.len() && j < system.len() && gold_spans[i] == system_spans[j] {
+                self.correct_segments += 1;
+                if self.same_pos(&gold[i], &system[j]) {
+                    self.correct_pos += 1;
+                    diff.push(Diff::Same(&gold[i]));
+                } else {
+                    diff.push(Diff::Gold(&gold[i]));
+                    diff.push(Diff::System(&system[j]));
+                }
+                i += 1;
+                j += 1;
+            } else if j >= system.len() || (i < gold.len() && gold_spans[i].0 <= system_spans[j].0)
+            {
+                diff.push(Diff::Gold(&gold[i]));
+                i += 1;
+            } else {
+                diff.push(Diff::System(&system[j]));
+                j += 1;
+            }
+        }
+        diff
+    }
+
+    pub fn precision(&self) -> f64 {
+        ratio(self.correct_segments, self.system_tokens)
+    }
+
+    pub fn recall(&self) -> f64 {
+        ratio(self.correct_segments, self.gold_tokens)
+    }
+
+    pub fn f1(&self) -> f64 {
+        let (p, r) = (self.precision(), self.recall());
+        if p + r == 0.0 {
+            0.0
+        } else {
+            2.0 * p * r / (p + r)
+        }
+    }
+
+    // accuracy of features over correctly segmented tokens
+    pub fn pos_accuracy(&self) -> f64 {
+        ratio(self.correct_pos, self.correct_segments)
+    }
+}
+
+fn ratio(numerator: usize, denominator: usize) -> f64 {
+    if denominator == 0 {
+        0.0
+    } else {
+        numerator as f64 / denominator as f64
+    }
+}
+
+#[cfg(test)]
+mod test_eval {
+    use super::*;
+
+    #[test]
+    fn test_read_corpus() {
+        let src = "東京\t名詞,固有名詞,地域,一般\n\
+                   都\t名詞,接尾,地域,*\n\
+                   EOS\n\
+                   住む\t動詞,自立,*,*\n\
+                   EOS\n";
+        let corpus = read_corpus(&mut src.as_bytes()).unwrap();
+        assert_eq!(corpus.len(), 2);
+        assert_eq!(
+            corpus[0],
+            vec![
+                Morpheme::new("東京", "名詞,固有名詞,地域,一般"),
+                Morpheme::new("都", "名詞,接尾,地域,*"),
+            ]
+        );
+        assert_eq!(corpus[1], vec![Morpheme::new("住む", "動詞,自立,*,*")]);
+        assert!(read_corpus(&mut "東京 名詞\nEOS\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_evaluation() {
+        let gold = vec![
+            Morpheme::new("東京", "名詞,固有名詞,地域,一般"),
+            Morpheme::new("都", "名詞,接尾,地域,*"),
+            Morpheme::new("に", "助詞,格助詞,一般,*"),
+            Morpheme::new("住む", "動詞,自立,*,*"),
+        ];
+        let system = vec![
+            Morpheme::new("東", "名詞,一般,*,*"),
+            Morpheme::new("京都", "名詞,固有名詞,地域,一般"),
+            Morpheme::new("に", "動詞,自立,*,*"),
+            Morpheme::new("住む", "動詞,自立,*,*,五段"),
+        ];
+        let mut eval = Evaluation::new(4);
+        let diff = eval.add(&gold, Some(&system));
+        assert_eq!(
+            diff,
+            vec![
+                Diff::Gold(&gold[0]),
+                Diff::System(&system[0]),
+                Diff::System(&system[1]),
+                Diff::Gold(&gold[1]),
+                Diff::Gold(&gold[2]),
+                Diff::System(&system[2]),
+                Diff::Same(&gold[3]),
+            ]
+        );
+        assert_eq!(eval.correct_segments, 2);
+        assert_eq!(eval.correct_pos, 1);
+        assert_eq!(eval.precision(), 0.5);
+        assert_eq!(eval.recall(), 0.5);
+        assert_eq!(eval.f1(), 0.5);
+        assert_eq!(eval.pos_accuracy(), 0.5);
+
+        eval.add(&gold, None);
+        assert_eq!(eval.failed_sentences, 1);
+        assert_eq!(eval.recall(), 0.25);
+        assert_eq!(eval.precision(), 0.5);
+    }
+}
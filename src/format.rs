@@ -0,0 +1,658 @@
+use super::Token;
+use serde_derive::Serialize;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+use std::io::Read;
+
+#[derive(Fail, Debug)]
+pub enum TemplateError {
+    #[fail(display = "unknown directive %{} in \"{}\"", directive, template)]
+    UnknownDirective { directive: String, template: String },
+    #[fail(display = "invalid feature index list in \"{}\"", template)]
+    InvalidFeatureList { template: String },
+    #[fail(display = "unexpected end of \"{}\"", template)]
+    UnexpectedEnd { template: String },
+}
+
+#[derive(Fail, Debug)]
+pub enum FormatError {
+    #[fail(display = "failed to read dicrc")]
+    FailedToRead,
+    #[fail(display = "unknown format type {}", name)]
+    UnknownFormat { name: String },
+    #[fail(display = "{}", _0)]
+    InvalidTemplate(TemplateError),
+}
+
+impl From<TemplateError> for FormatError {
+    fn from(err: TemplateError) -> Self {
+        FormatError::InvalidTemplate(err)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Piece {
+    Literal(String),
+    // %m
+    Surface,
+    // %M
+    SurfaceWithSpace,
+    // %H
+    Features,
+    // %f[..] and %F<sep>[..]
+    SelectedFeatures(Vec<usize>, char),
+    // %c
+    WordCost,
+    // %s
+    Stat,
+    // %S
+    Sentence,
+    // %L
+    SentenceLength,
+    // %pS
+    PrefixSpace,
+    // %ps
+    Begin,
+    // %pe
+    End,
+    // %pl
+    Length,
+    // %pL
+    LengthWithSpace,
+    // %pn
+    NodeId,
+    // %pb
+    Best,
+    // %pc
+    TotalCost,
+    // %pC
+    ConnectionCost,
+    // %phl
+    LeftId,
+    // %phr
+    RightId,
+}
+
+// Node/bos/eos template of dicrc. see https://taku910.github.io/mecab/format.html
+#[derive(Debug, PartialEq, Clone)]
+pub struct Template {
+    pieces: Vec<Piece>,
+}
+
+impl Template {
+    pub fn parse(src: &str) -> Result<Template, TemplateError> {
+        let mut pieces = Vec::new();
+        let mut literal = String::new();
+        let mut chars = src.chars();
+        let unexpected_end = || TemplateError::UnexpectedEnd {
+            template: String::from(src),
+        };
+        let unknown = |directive: &str| TemplateError::UnknownDirective {
+            directive: String::from(directive),
+            template: String::from(src),
+        };
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => literal.push(match chars.next().ok_or_else(unexpected_end)? {
+                    'n' => '\n',
+                    't' => '\t',
+                    'r' => '\r',
+                    's' => ' ',
+                    '0' => '\0',
+                    c => c,
+                }),
+                '%' => {
+                    let piece = match chars.next().ok_or_else(unexpected_end)? {
+                        '%' => {
+                            literal.push('%');
+                            continue;
+                        }
+                        'm' => Piece::Surface,
+                        'M' => Piece::SurfaceWithSpace,
+                        'H' => Piece::Features,
+                        'c' => Piece::WordCost,
+                        's' => Piece::Stat,
+                        'S' => Piece::Sentence,
+                        'L' => Piece::SentenceLength,
+                        'f' => Piece::SelectedFeatures(Self::parse_indices(&mut chars, src)?, ','),
+                        'F' => {
+                            let sep = match chars.next().ok_or_else(unexpected_end)? {
+                                '\\' => match chars.next().ok_or_else(unexpected_end)? {
+                                    't' => '\t',
+                                    's' => ' ',
+                                    c => c,
+                                },
+                                c => c,
+                            };
+                            Piece::SelectedFeatures(Self::parse_indices(&mut chars, src)?, sep)
+                        }
+                        'p' => match chars.next().ok_or_else(unexpected_end)? {
+                            'S' => Piece::PrefixSpace,
+                            's' => Piece::Begin,
+                            'e' => Piece::End,
+                            'l' => Piece::Length,
+                            'L' => Piece::LengthWithSpace,
+                            'n' => Piece::NodeId,
+                            'b' => Piece::Best,
+                            'c' => Piece::TotalCost,
+                            'C' => Piece::ConnectionCost,
+                            'h' => match chars.next().ok_or_else(unexpected_end)? {
+                                'l' => Piece::LeftId,
+                                'r' => Piece::RightId,
+                                c => return Err(unknown(&format!("ph{}", c))),
+                            },
+                            c => return Err(unknown(&format!("p{}", c))),
+                        },
+                        c => return Err(unknown(&c.to_string())),
+                    };
+                    if !literal.is_empty() {
+                        pieces.push(Piece::Literal(literal.clone()));
+                        literal.clear();
+                    }
+                    pieces.push(piece);
+                }
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            pieces.push(Piece::Literal(literal));
+        }
+        Ok(Template { pieces })
+    }
+
+    // "[0,1,2]"
+    fn parse_indices<I: Iterator<Item = char>>(
+        chars: &mut I,
+        src: &str,
+    ) -> Result<Vec<usize>, TemplateError> {
+        let invalid = || TemplateError::InvalidFeatureList {
+            template: String::from(src),
+        };
+        if chars.next() != Some('[') {
+            return Err(invalid());
+        }
+        let mut list = String::new();
+        loop {
+            match chars.next() {
+                Some(']') => break,
+                Some(c) => list.push(c),
+                None => return Err(invalid()),
+            }
+        }
+        list.split(',')
+            .map(|idx| idx.trim().parse().map_err(|_| invalid()))
+            .collect()
+    }
+
+    // Render one node. eos and bos templates are rendered with token None.
+    pub fn render<T: AsRef<str>>(
+        &self,
+        out: &mut String,
+        input: &str,
+        tokens: &[Token<T>],
+        idx: Option<usize>,
+    ) {
+        let token = idx.map(|idx| &tokens[idx]);
+        // tokens are contiguous so that the previous end is the beginning of whitespace
+        let prev_end = match idx {
+            Some(idx) if idx > 0 => tokens[idx - 1].end,
+            _ => 0,
+        };
+        for piece in &self.pieces {
+            match (piece, token) {
+                (Piece::Literal(literal), _) => out.push_str(literal),
+                (Piece::Sentence, _) => out.push_str(input),
+                (Piece::SentenceLength, _) => write!(out, "{}", input.len()).unwrap(),
+                (_, None) => (),
+                (Piece::Surface, Some(token)) => out.push_str(&input[token.begin..token.end]),
+                (Piece::SurfaceWithSpace, Some(token)) => out.push_str(&input[prev_end..token.end]),
+                (Piece::PrefixSpace, Some(token)) => out.push_str(&input[prev_end..token.begin]),
+                (Piece::Features, Some(token)) => out.push_str(token.word.info.as_ref()),
+                (Piece::SelectedFeatures(indices, sep), Some(token)) => {
+                    let features: Vec<&str> = token.word.info.as_ref().split(',').collect();
+                    let mut first = true;
+                    for idx in indices {
+                        // MeCab omits empty and "*" fields
+                        match features.get(*idx) {
+                            Some(feature) if !feature.is_empty() && *feature != "*" => {
+                                if !first {
+                                    out.push(*sep);
+                                }
+                                out.push_str(feature);
+                                first = false;
+                            }
+                            _ => (),
+                        }
+                    }
+                }
+                (Piece::WordCost, Some(token)) => write!(out, "{}", token.word.cost).unwrap(),
+                // 0 for words of the dictionary and 1 for unknown words
+                (Piece::Stat, Some(token)) => out.push(if token.unknown { '1' } else { '0' }),
+                (Piece::Begin, Some(token)) => write!(out, "{}", token.begin).unwrap(),
+                (Piece::End, Some(token)) => write!(out, "{}", token.end).unwrap(),
+                (Piece::Length, Some(token)) => write!(out, "{}", token.end - token.begin).unwrap(),
+                (Piece::LengthWithSpace, Some(token)) => {
+                    write!(out, "{}", token.end - prev_end).unwrap()
+                }
+                (Piece::NodeId, Some(_)) => write!(out, "{}", idx.unwrap()).unwrap(),
+                (Piece::Best, Some(_)) => out.push('*'),
+                (Piece::TotalCost, Some(token)) => write!(out, "{}", token.cost).unwrap(),
+                (Piece::ConnectionCost, Some(token)) => {
+                    let prev_cost = match idx {
+                        Some(idx) if idx > 0 => tokens[idx - 1].cost,
+                        _ => 0,
+                    };
+                    write!(out, "{}", token.cost - prev_cost - token.word.cost).unwrap()
+                }
+                (Piece::LeftId, Some(token)) => write!(out, "{}", token.word.lid).unwrap(),
+                (Piece::RightId, Some(token)) => write!(out, "{}", token.word.rid).unwrap(),
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct OutputFormat {
+    pub bos: Template,
+    pub node: Template,
+    // node template of unknown words
+    pub unk: Template,
+    pub eos: Template,
+}
+
+impl Default for OutputFormat {
+    // same as mecab without -O
+    fn default() -> Self {
+        OutputFormat {
+            bos: Template { pieces: Vec::new() },
+            node: Template::parse("%m\\t%H\\n").unwrap(),
+            unk: Template::parse("%m\\t%H\\n").unwrap(),
+            eos: Template::parse("EOS\\n").unwrap(),
+        }
+    }
+}
+
+impl OutputFormat {
+    pub fn render<T: AsRef<str>>(&self, input: &str, tokens: &[Token<T>]) -> String {
+        let mut out = String::new();
+        self.bos.render(&mut out, input, tokens, None);
+        for (idx, token) in tokens.iter().enumerate() {
+            let template = if token.unknown { &self.unk } else { &self.node };
+            template.render(&mut out, input, tokens, Some(idx));
+        }
+        self.eos.render(&mut out, input, tokens, None);
+        out
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct JsonToken<'a> {
+    surface: &'a str,
+    // byte offsets in the sentence
+    begin: usize,
+    end: usize,
+    features: Vec<&'a str>,
+    lid: usize,
+    rid: usize,
+    word_cost: i64,
+    // total cost of the path up to this token
+    cost: i64,
+    unknown: bool,
+}
+
+impl<'a> JsonToken<'a> {
+    pub(crate) fn new<T: AsRef<str>>(input: &'a str, token: &Token<'a, T>) -> Self {
+        let word = token.word;
+        JsonToken {
+            surface: &input[token.begin..token.end],
+            begin: token.begin,
+            end: token.end,
+            features: word.info.as_ref().split(',').collect(),
+            lid: token.word.lid,
+            rid: token.word.rid,
+            word_cost: token.word.cost,
+            cost: token.cost,
+            unknown: token.unknown,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonSentence<'a> {
+    sentence: &'a str,
+    // null if failed to parse
+    tokens: Option<Vec<JsonToken<'a>>>,
+}
+
+// one line of JSON per sentence
+pub fn render_json<'a, T: AsRef<str>>(input: &'a str, tokens: Option<&[Token<'a, T>]>) -> String {
+    let sentence = JsonSentence {
+        sentence: input,
+        tokens: tokens.map(|tokens| {
+            tokens
+                .iter()
+                .map(|token| JsonToken::new(input, token))
+                .collect()
+        }),
+    };
+    let mut out = serde_json::to_string(&sentence).unwrap();
+    out.push('\n');
+    out
+}
+
+// Universal POS tag from IPADIC part-of-speech (features[0] and features[1])
+fn upos(features: &[&str]) -> &'static str {
+    let sub = features.get(1).cloned().unwrap_or("*");
+    match features.first().cloned().unwrap_or("*") {
+        "名詞" => match sub {
+            "固有名詞" => "PROPN",
+            "代名詞" => "PRON",
+            "数" => "NUM",
+            "形容動詞語幹" => "ADJ",
+            _ => "NOUN",
+        },
+        "動詞" => match sub {
+            "非自立" => "AUX",
+            _ => "VERB",
+        },
+        "形容詞" => "ADJ",
+        "副詞" => "ADV",
+        "連体詞" => "DET",
+        "接続詞" => "CCONJ",
+        "接頭詞" => "NOUN",
+        "助詞" => match sub {
+            "接続助詞" => "SCONJ",
+            "終助詞" => "PART",
+            "並立助詞" => "CCONJ",
+            _ => "ADP",
+        },
+        "助動詞" => "AUX",
+        "感動詞" | "フィラー" => "INTJ",
+        "記号" => match sub {
+            "句点" | "読点" | "括弧開" | "括弧閉" => "PUNCT",
+            _ => "SYM",
+        },
+        _ => "X",
+    }
+}
+
+// CoNLL-U without dependencies. LEMMA is the base form (features[6]) and XPOS is
+// features[0..4] joined by '-'.
+pub fn render_conllu<T: AsRef<str>>(sent_id: usize, input: &str, tokens: &[Token<T>]) -> String {
+    let mut out = String::new();
+    writeln!(out, "# sent_id = {}", sent_id).unwrap();
+    writeln!(out, "# text = {}", input).unwrap();
+    for (idx, token) in tokens.iter().enumerate() {
+        let surface = &input[token.begin..token.end];
+        let features: Vec<&str> = token.word.info.as_ref().split(',').collect();
+        let lemma = match features.get(6) {
+            Some(lemma) if *lemma != "*" && !lemma.is_empty() => lemma,
+            _ => surface,
+        };
+        let xpos: Vec<&str> = features
+            .iter()
+            .take(4)
+            .filter(|feature| **feature != "*" && !feature.is_empty())
+            .cloned()
+            .collect();
+        let xpos = if xpos.is_empty() {
+            String::from("_")
+        } else {
+            xpos.join("-")
+        };
+        let misc = if idx + 1 < tokens.len() && tokens[idx + 1].begin == token.end {
+            "SpaceAfter=No"
+        } else {
+            "_"
+        };
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{}\t_\t_\t_\t_\t{}",
+            idx + 1,
+            surface,
+            lemma,
+            upos(&features),
+            xpos,
+            misc
+        )
+        .unwrap();
+    }
+    out.push('\n');
+    out
+}
+
+pub struct Dicrc {
+    entries: HashMap<String, String>,
+}
+
+impl Dicrc {
+    pub fn new<R: Read>(src: &mut R) -> Result<Dicrc, FormatError> {
+        let mut buf = Vec::new();
+        io::BufReader::new(src)
+            .read_to_end(&mut buf)
+            .map_err(|_| FormatError::FailedToRead)?;
+        let mut entries = HashMap::new();
+        for line in String::from_utf8_lossy(&buf).lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+            let mut splited_line = line.splitn(2, '=');
+            if let (Some(key), Some(value)) = (splited_line.next(), splited_line.next()) {
+                entries.insert(String::from(key.trim()), String::from(value.trim()));
+            }
+        }
+        Ok(Dicrc { entries })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(|value| value.as_str())
+    }
+
+    // -O <name>: node-format-<name>, unk-format-<name>, bos-format-<name> and eos-format-<name>.
+    // unknown words are rendered by node-format-<name> without unk-format-<name>.
+    pub fn output_format(&self, name: &str) -> Result<OutputFormat, FormatError> {
+        let node = self.get(&format!("node-format-{}", name)).ok_or_else(|| {
+            FormatError::UnknownFormat {
+                name: String::from(name),
+            }
+        })?;
+        Ok(OutputFormat {
+            bos: Template::parse(self.get(&format!("bos-format-{}", name)).unwrap_or(""))?,
+            node: Template::parse(node)?,
+            unk: Template::parse(self.get(&format!("unk-format-{}", name)).unwrap_or(node))?,
+            eos: Template::parse(self.get(&format!("eos-format-{}", name)).unwrap_or(""))?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test_format {
+    use super::*;
+    use crate::parser::Word;
+
+    #[test]
+    fn test_parse_template() {
+        assert_eq!(
+            Template::parse("%m\\t%F-[0,1,2,3]\\n").unwrap().pieces,
+            vec![
+                Piece::Surface,
+                Piece::Literal(String::from("\t")),
+                Piece::SelectedFeatures(vec![0, 1, 2, 3], '-'),
+                Piece::Literal(String::from("\n")),
+            ]
+        );
+        assert_eq!(
+            Template::parse("%pS%f[7]%%").unwrap().pieces,
+            vec![
+                Piece::PrefixSpace,
+                Piece::SelectedFeatures(vec![7], ','),
+                Piece::Literal(String::from("%")),
+            ]
+        );
+        assert!(Template::parse("%z").is_err());
+        assert!(Template::parse("%f[1,").is_err());
+        assert!(Template::parse("%p").is_err());
+    }
+
+    #[test]
+    fn test_render() {
+        let tokyo = Word {
+            info: String::from("名詞,固有名詞,地域,一般,*,*,東京,トウキョウ,トーキョー"),
+            lid: 1,
+            rid: 1,
+            cost: 3003,
+        };
+        let to = Word {
+            info: String::from("名詞,接尾,地域,*,*,*,都,ト,ト"),
+            lid: 3,
+            rid: 2,
+            cost: 9428,
+        };
+        let tokens = vec![
+            Token {
+                begin: 0,
+                end: 6,
+                cost: 2693,
+                word: &tokyo,
+                unknown: false,
+            },
+            Token {
+                begin: 6,
+                end: 9,
+                cost: 10818,
+                word: &to,
+                unknown: false,
+            },
+        ];
+        let dicrc = Dicrc::new(
+            &mut "; comment\n\
+                  node-format-simple = %m\\t%F-[0,1,2,3]\\n\n\
+                  eos-format-simple  = EOS\\n\n\
+                  node-format-yomi = %pS%f[7]\n\
+                  eos-format-yomi  = \\n\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(
+            dicrc
+                .output_format("simple")
+                .unwrap()
+                .render("東京都", &tokens),
+            "東京\t名詞-固有名詞-地域-一般\n都\t名詞-接尾-地域\nEOS\n"
+        );
+        assert_eq!(
+            dicrc
+                .output_format("yomi")
+                .unwrap()
+                .render("東京都", &tokens),
+            "トウキョウト\n"
+        );
+        assert!(dicrc.output_format("chasen").is_err());
+        assert_eq!(
+            OutputFormat {
+                bos: Template::parse("BOS %S\\n").unwrap(),
+                node: Template::parse("%ps,%pe,%pl,%c,%pc,%pC,%phl,%phr\\n").unwrap(),
+                unk: Template::parse("").unwrap(),
+                eos: Template::parse("%L\\n").unwrap(),
+            }
+            .render("東京都", &tokens),
+            "BOS 東京都\n0,6,6,3003,2693,-310,1,1\n6,9,3,9428,10818,-1303,3,2\n9\n"
+        );
+        // unknown words are rendered by unk-format, or node-format without it
+        let mut tokens = tokens;
+        tokens[1].unknown = true;
+        let dicrc = Dicrc::new(
+            &mut "node-format-stat = %m\\t%s\\n\n\
+                  unk-format-stat = %m\\t%s\\tunknown\\n\n\
+                  node-format-plain = %m\\t%s\\n\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(
+            dicrc
+                .output_format("stat")
+                .unwrap()
+                .render("東京都", &tokens),
+            "東京\t0\n都\t1\tunknown\n"
+        );
+        assert_eq!(
+            dicrc
+                .output_format("plain")
+                .unwrap()
+                .render("東京都", &tokens),
+            "東京\t0\n都\t1\n"
+        );
+        // only the placeholder itself is omitted
+        let stars = Word {
+            info: String::from("記号,一般,*,*,*,*,**,**,**"),
+            lid: 5,
+            rid: 5,
+            cost: 100,
+        };
+        let tokens = vec![Token {
+            begin: 0,
+            end: 2,
+            cost: 100,
+            word: &stars,
+            unknown: false,
+        }];
+        let mut out = String::new();
+        Template::parse("%F-[0,2,6]")
+            .unwrap()
+            .render(&mut out, "**", &tokens, Some(0));
+        assert_eq!(out, "記号-**");
+    }
+
+    #[test]
+    fn test_render_json_conllu() {
+        let tokyo = Word {
+            info: String::from("名詞,固有名詞,地域,一般,*,*,東京,トウキョウ,トーキョー"),
+            lid: 1,
+            rid: 1,
+            cost: 3003,
+        };
+        let ni = Word {
+            info: String::from("助詞,格助詞,一般,*,*,*,に,ニ,ニ"),
+            lid: 6,
+            rid: 5,
+            cost: 4304,
+        };
+        let tokens = vec![
+            Token {
+                begin: 0,
+                end: 6,
+                cost: 2693,
+                word: &tokyo,
+                unknown: false,
+            },
+            Token {
+                begin: 6,
+                end: 9,
+                cost: 3200,
+                word: &ni,
+                unknown: false,
+            },
+        ];
+        let json: serde_json::Value =
+            serde_json::from_str(&render_json("東京に", Some(&tokens))).unwrap();
+        assert_eq!(json["sentence"], "東京に");
+        assert_eq!(json["tokens"][0]["surface"], "東京");
+        assert_eq!(json["tokens"][0]["features"][1], "固有名詞");
+        assert_eq!(json["tokens"][1]["begin"], 6);
+        assert_eq!(json["tokens"][1]["word_cost"], 4304);
+        assert_eq!(json["tokens"][1]["cost"], 3200);
+        assert_eq!(
+            render_json::<String>("東西", None),
+            "{\"sentence\":\"東西\",\"tokens\":null}\n"
+        );
+        assert_eq!(
+            render_conllu(3, "東京に", &tokens),
+            "# sent_id = 3\n\
+             # text = 東京に\n\
+             1\t東京\t東京\tPROPN\t名詞-固有名詞-地域-一般\t_\t_\t_\t_\tSpaceAfter=No\n\
+             2\tに\tに\tADP\t助詞-格助詞-一般\t_\t_\t_\t_\t_\n\n"
+        );
+    }
+}
@@ -0,0 +1,188 @@
+use super::header::{ImportError, InfoType};
+use super::mapped::MappedMorph;
+use super::Morph;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+// Dictionary which a handle reads again from its file
+pub trait FromFile: Sized {
+    fn from_file(path: &Path) -> Result<Self, ImportError>;
+}
+
+impl<T: Serialize + DeserializeOwned + Clone + core::fmt::Debug + InfoType> FromFile for Morph<T> {
+    fn from_file(path: &Path) -> Result<Self, ImportError> {
+        Morph::import(&mut fs::File::open(path)?)
+    }
+}
+
+impl FromFile for MappedMorph {
+    fn from_file(path: &Path) -> Result<Self, ImportError> {
+        Ok(MappedMorph::open(path)?)
+    }
+}
+
+// Shared dictionary which can be replaced while analyses are running.
+// Readers take a snapshot with load() and keep using it even if it is swapped out.
+pub struct MorphHandle<L> {
+    current: RwLock<Arc<L>>,
+}
+
+impl<L> MorphHandle<L> {
+    pub fn new(morph: L) -> Self {
+        MorphHandle {
+            current: RwLock::new(Arc::new(morph)),
+        }
+    }
+
+    pub fn load(&self) -> Arc<L> {
+        self.current.read().unwrap().clone()
+    }
+
+    // Returns the previous dictionary
+    pub fn store(&self, morph: L) -> Arc<L> {
+        let morph = Arc::new(morph);
+        std::mem::replace(&mut *self.current.write().unwrap(), morph)
+    }
+}
+
+impl<L: FromFile> MorphHandle<L> {
+    // The current dictionary is kept if reading fails.
+    // A file in the other format fails, e.g. a mapped one replaced by a bincode one.
+    pub fn reload<P: AsRef<Path>>(&self, path: P) -> Result<(), ImportError> {
+        let morph = L::from_file(path.as_ref())?;
+        self.store(morph);
+        Ok(())
+    }
+}
+
+fn modified<P: AsRef<Path>>(path: P) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+// Poll path every interval until shutdown and reload the dictionary when
+// - the modification time changed and stayed the same for one interval, so that
+//   a file being written is not imported
+// - or requested is set (e.g. by SIGHUP). requested is cleared.
+// on_reload receives the result of every reload.
+pub fn watch<L, P, F>(
+    handle: &MorphHandle<L>,
+    path: P,
+    interval: Duration,
+    shutdown: &AtomicBool,
+    requested: &AtomicBool,
+    on_reload: F,
+) where
+    L: FromFile,
+    P: AsRef<Path>,
+    F: Fn(Result<(), ImportError>),
+{
+    let path = path.as_ref();
+    let mut loaded = modified(path);
+    let mut last_seen = loaded;
+    // sleep in short steps to respond to shutdown and requests quickly
+    let step = std::cmp::min(interval, Duration::from_millis(100));
+    let mut elapsed = Duration::from_millis(0);
+    while !shutdown.load(Ordering::SeqCst) {
+        thread::sleep(step);
+        elapsed += step;
+        if requested.swap(false, Ordering::SeqCst) {
+            loaded = modified(path);
+            last_seen = loaded;
+            on_reload(handle.reload(path));
+            continue;
+        }
+        if elapsed < interval {
+            continue;
+        }
+        elapsed = Duration::from_millis(0);
+        let now = modified(path);
+        if now != loaded && now == last_seen && now.is_some() {
+            loaded = now;
+            on_reload(handle.reload(path));
+        }
+        last_seen = now;
+    }
+}
+
+#[cfg(test)]
+mod test_handle {
+    use super::*;
+    use crate::testutil;
+
+    fn morph(info: &str) -> Morph<String> {
+        let dict_src = format!("東京,1,1,3003,{}\n", info);
+        testutil::morph("2 2\n0 1 -310\n1 0 -409", &dict_src)
+    }
+
+    #[test]
+    fn test_store() {
+        let handle = MorphHandle::new(morph("old"));
+        let in_flight = handle.load();
+        let previous = handle.store(morph("new"));
+        assert!(Arc::ptr_eq(&previous, &in_flight));
+        assert_eq!(
+            in_flight.parse("東京".as_bytes()),
+            Some(vec![String::from("old")])
+        );
+        assert_eq!(
+            handle.load().parse("東京".as_bytes()),
+            Some(vec![String::from("new")])
+        );
+    }
+
+    #[test]
+    fn test_reload() {
+        let path = std::env::temp_dir().join(format!("namaco-test-reload-{}", std::process::id()));
+        morph("new")
+            .export(&mut fs::File::create(&path).unwrap())
+            .unwrap();
+        let handle = MorphHandle::new(morph("old"));
+        handle.reload(&path).unwrap();
+        assert_eq!(
+            handle.load().parse("東京".as_bytes()),
+            Some(vec![String::from("new")])
+        );
+
+        // broken file keeps the current dictionary
+        fs::write(&path, b"broken").unwrap();
+        assert!(handle.reload(&path).is_err());
+        assert_eq!(
+            handle.load().parse("東京".as_bytes()),
+            Some(vec![String::from("new")])
+        );
+
+        // reload on request
+        morph("requested")
+            .export(&mut fs::File::create(&path).unwrap())
+            .unwrap();
+        let shutdown = AtomicBool::new(false);
+        let requested = AtomicBool::new(true);
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                watch(
+                    &handle,
+                    &path,
+                    Duration::from_secs(60),
+                    &shutdown,
+                    &requested,
+                    |result| {
+                        result.unwrap();
+                        shutdown.store(true, Ordering::SeqCst);
+                    },
+                )
+            });
+        });
+        assert!(!requested.load(Ordering::SeqCst));
+        assert_eq!(
+            handle.load().parse("東京".as_bytes()),
+            Some(vec![String::from("requested")])
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,229 @@
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use serde_derive::{Deserialize, Serialize};
+use std::io;
+use std::io::{Read, Write};
+
+// Compiled dictionary file:
+//   MAGIC, VERSION and length of Header as u32 little endian, Header by bincode, content
+// Header is placed after VERSION so that later versions can change it.
+pub const MAGIC: &[u8; 8] = b"NAMACODC";
+// 1: trie with usize nodes and values allocated for each key, migrated on import
+// 2: trie with u32 nodes, values in one arena, an alphabet and tails, and unknown words
+pub const VERSION: u32 = 2;
+pub const OLDEST_VERSION: u32 = 1;
+const MAX_HEADER_LEN: u32 = 64 * 1024;
+
+// Type of Word::info, named in Header by a tag fixed in the source rather than by the compiler
+pub trait InfoType {
+    const TAG: &'static str;
+}
+
+impl InfoType for String {
+    const TAG: &'static str = "String";
+}
+
+#[derive(Fail, Debug)]
+pub enum ImportError {
+    #[fail(display = "failed to read dictionary: {}", _0)]
+    Io(io::Error),
+    #[fail(display = "not a compiled dictionary")]
+    NotDictionary,
+    #[fail(
+        display = "dictionary format version {} is not supported (expected {}). compile it again",
+        found, expected
+    )]
+    UnsupportedVersion { found: u32, expected: u32 },
+    #[fail(display = "dictionary holds {} while {} is expected", found, expected)]
+    TypeMismatch { found: String, expected: String },
+    #[fail(display = "dictionary is truncated ({} of {} bytes)", found, expected)]
+    Truncated { found: u64, expected: u64 },
+    #[fail(display = "checksum mismatch. dictionary is corrupted")]
+    ChecksumMismatch,
+    #[fail(display = "dictionary was compiled from unknown charset {}", charset)]
+    UnknownCharset { charset: String },
+    #[fail(display = "broken dictionary: {}", reason)]
+    Broken { reason: String },
+}
+
+impl From<io::Error> for ImportError {
+    fn from(err: io::Error) -> Self {
+        ImportError::Io(err)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Header {
+    // InfoType::TAG of Word::info
+    pub info_type: String,
+    // encoding of the source dictionary, decoded to UTF-8 on compile
+    pub charset: String,
+    // namaco version which compiled the dictionary
+    pub builder: String,
+    // seconds since UNIX epoch
+    pub created: u64,
+    pub content_len: u64,
+    // SHA-256 of content
+    pub digest: Vec<u8>,
+}
+
+impl Header {
+    pub fn new(info_type: &str, charset: &str, content: &[u8]) -> Self {
+        let created = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+        Header {
+            info_type: info_type.to_owned(),
+            charset: charset.to_owned(),
+            builder: format!("namaco {}", env!("CARGO_PKG_VERSION")),
+            created,
+            content_len: content.len() as u64,
+            digest: digest(content),
+        }
+    }
+}
+
+fn digest(content: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input(content);
+    let mut digest = vec![0; hasher.output_bytes()];
+    hasher.result(&mut digest);
+    digest
+}
+
+pub fn write<W: Write>(target: &mut W, header: &Header, content: &[u8]) -> Result<(), io::Error> {
+    let header = bincode::serialize(header).unwrap();
+    target.write_all(MAGIC)?;
+    target.write_all(&VERSION.to_le_bytes())?;
+    target.write_all(&(header.len() as u32).to_le_bytes())?;
+    target.write_all(&header)?;
+    target.write_all(content)
+}
+
+fn read_u32<R: Read>(src: &mut R) -> Result<u32, ImportError> {
+    let mut buf = [0u8; 4];
+    src.read_exact(&mut buf).map_err(|err| match err.kind() {
+        io::ErrorKind::UnexpectedEof => ImportError::NotDictionary,
+        _ => ImportError::Io(err),
+    })?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+// Read MAGIC, VERSION and Header. src is left at the beginning of content.
+pub fn read<R: Read>(src: &mut R) -> Result<(u32, Header), ImportError> {
+    let mut magic = [0u8; 8];
+    match src.read_exact(&mut magic) {
+        Ok(()) if &magic == MAGIC => (),
+        Ok(()) => return Err(ImportError::NotDictionary),
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+            return Err(ImportError::NotDictionary)
+        }
+        Err(err) => return Err(ImportError::Io(err)),
+    }
+    let version = read_u32(src)?;
+    if !(OLDEST_VERSION..=VERSION).contains(&version) {
+        return Err(ImportError::UnsupportedVersion {
+            found: version,
+            expected: VERSION,
+        });
+    }
+    let header_len = read_u32(src)?;
+    if header_len > MAX_HEADER_LEN {
+        return Err(ImportError::Broken {
+            reason: String::from("too long header"),
+        });
+    }
+    let mut header = vec![0; header_len as usize];
+    src.read_exact(&mut header)
+        .map_err(|_| ImportError::Broken {
+            reason: String::from("truncated header"),
+        })?;
+    let header = bincode::config()
+        .limit(u64::from(header_len))
+        .deserialize(&header)
+        .map_err(|err| ImportError::Broken {
+            reason: format!("invalid header: {}", err),
+        })?;
+    Ok((version, header))
+}
+
+// Read the rest of src as content of header
+pub fn read_content<R: Read>(src: &mut R, header: &Header) -> Result<Vec<u8>, ImportError> {
+    let mut content = Vec::new();
+    src.take(header.content_len.saturating_add(1))
+        .read_to_end(&mut content)?;
+    if (content.len() as u64) < header.content_len {
+        return Err(ImportError::Truncated {
+            found: content.len() as u64,
+            expected: header.content_len,
+        });
+    }
+    if content.len() as u64 > header.content_len || digest(&content) != header.digest {
+        return Err(ImportError::ChecksumMismatch);
+    }
+    Ok(content)
+}
+
+#[cfg(test)]
+mod test_header {
+    use super::*;
+
+    fn file(content: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let header = Header::new(String::TAG, "UTF-8", content);
+        write(&mut buf, &header, content).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_read() {
+        let buf = file(b"content");
+        let mut src = &buf[..];
+        let (_, header) = read(&mut src).unwrap();
+        assert_eq!(header.info_type, "String");
+        assert_eq!(header.charset, "UTF-8");
+        assert_eq!(read_content(&mut src, &header).unwrap(), b"content");
+    }
+
+    #[test]
+    fn test_broken() {
+        let buf = file(b"content");
+        let mut src = &buf[..buf.len() - 1];
+        let (_, header) = read(&mut src).unwrap();
+        match read_content(&mut src, &header) {
+            Err(ImportError::Truncated { found, expected }) => {
+                assert_eq!((found, expected), (6, 7))
+            }
+            other => panic!("{:?}", other),
+        }
+
+        let mut corrupted = buf.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        let mut src = &corrupted[..];
+        let (_, header) = read(&mut src).unwrap();
+        assert!(matches!(
+            read_content(&mut src, &header),
+            Err(ImportError::ChecksumMismatch)
+        ));
+
+        let mut old = buf.clone();
+        old[8] = 0;
+        assert!(matches!(
+            read(&mut &old[..]),
+            Err(ImportError::UnsupportedVersion {
+                found: 0,
+                expected: VERSION
+            })
+        ));
+
+        assert!(matches!(
+            read(&mut &b"NAMA"[..]),
+            Err(ImportError::NotDictionary)
+        ));
+        assert!(matches!(
+            read(&mut &buf[..20]),
+            Err(ImportError::Broken { .. })
+        ));
+    }
+}
@@ -1,12 +1,28 @@
+pub mod eval;
+pub mod format;
+pub mod handle;
+pub mod header;
+pub mod mapped;
 mod matrix;
 pub mod parser;
-mod trie;
+pub mod pattern;
+pub mod server;
+pub mod sort;
+pub mod stream;
+#[cfg(test)]
+mod testutil;
+pub mod trie;
+pub mod unknown;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use serde_derive::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::i64;
 use std::io;
 use std::io::{Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 use std::usize;
 #[macro_use]
 extern crate failure;
@@ -17,9 +33,39 @@ pub use self::parser::Word;
 pub struct Morph<T: Serialize> {
     trie: trie::Trie<Word<T>>,
     matrix: matrix::Matrix,
+    unknown: unknown::Unknown<T>,
+}
+
+// Morph of dictionary format version 1
+#[derive(Deserialize)]
+struct NestedMorph<T> {
+    trie: trie::NestedTrie<Word<T>>,
+    matrix: matrix::Matrix,
 }
 
-type DPTable<'a, T> = Vec<Vec<(i64, Vec<&'a Word<T>>)>>;
+#[derive(Debug, PartialEq, Clone)]
+pub struct Token<'a, T> {
+    pub begin: usize,
+    pub end: usize,
+    // total cost of the path up to this token
+    pub cost: i64,
+    pub word: &'a Word<T>,
+    // made by unknown word processing rather than found in the dictionary
+    pub unknown: bool,
+}
+
+// a word reachable from the beginning of the sentence
+#[derive(Debug, PartialEq, Clone)]
+pub struct LatticeNode<'a, T> {
+    pub begin: usize,
+    pub end: usize,
+    // total cost of the best path up to this node
+    pub cost: i64,
+    // index of previous node on the best path. None for the first word of the sentence.
+    pub prev: Option<usize>,
+    pub word: &'a Word<T>,
+    pub unknown: bool,
+}
 
 use core::fmt::Debug;
 impl<T: Serialize + DeserializeOwned + Clone + Debug> Morph<T> {
@@ -34,102 +80,538 @@ impl<T: Serialize + DeserializeOwned + Clone + Debug> Morph<T> {
     {
         let trie = parser::build_trie(dict_src, classifier)?;
         let matrix = matrix::Matrix::new(matrix_src).unwrap();
-        Ok(Morph { trie, matrix })
+        Ok(Morph {
+            trie,
+            matrix,
+            unknown: unknown::Unknown::default(),
+        })
     }
 
     #[allow(dead_code)]
-    pub fn export<W: Write>(&self, target: &mut W) -> Result<(), io::Error> {
+    pub fn export<W: Write>(&self, target: &mut W) -> Result<(), io::Error>
+    where
+        T: header::InfoType,
+    {
+        self.export_with(target, "UTF-8")
+    }
+
+    // charset names the encoding of the source dictionary, which was decoded to UTF-8
+    pub fn export_with<W: Write>(&self, target: &mut W, charset: &str) -> Result<(), io::Error>
+    where
+        T: header::InfoType,
+    {
+        let content = bincode::serialize(&self).unwrap();
+        let header = header::Header::new(T::TAG, charset, &content);
         let mut stream = io::BufWriter::new(target);
-        stream.write_all(&bincode::serialize(&self).unwrap())?;
-        Ok(())
+        header::write(&mut stream, &header, &content)?;
+        stream.flush()
     }
 
     #[allow(dead_code)]
-    pub fn import<R: Read>(target: &mut R) -> Result<Morph<T>, io::Error> {
+    pub fn import<R: Read>(target: &mut R) -> Result<Morph<T>, header::ImportError>
+    where
+        T: header::InfoType,
+    {
         let mut stream = io::BufReader::new(target);
-        let mut buf = Vec::new();
-        stream.read_to_end(&mut buf)?;
-        let trie = bincode::deserialize(&buf);
-        Ok(trie.unwrap())
+        let (version, header) = header::read(&mut stream)?;
+        let expected = T::TAG;
+        // version 1 named the type by std::any::type_name, which may differ between compilers
+        let legacy = version == 1 && header.info_type == std::any::type_name::<T>();
+        if header.info_type != expected && !legacy {
+            return Err(header::ImportError::TypeMismatch {
+                found: header.info_type,
+                expected: expected.to_owned(),
+            });
+        }
+        if encoding_rs::Encoding::for_label(header.charset.as_bytes()).is_none() {
+            return Err(header::ImportError::UnknownCharset {
+                charset: header.charset,
+            });
+        }
+        let content = header::read_content(&mut stream, &header)?;
+        let broken = |reason: String| header::ImportError::Broken { reason };
+        if version == header::VERSION {
+            let morph: Morph<T> =
+                bincode::deserialize(&content).map_err(|err| broken(err.to_string()))?;
+            if !morph.unknown_in_matrix() {
+                return Err(broken(String::from("connection id out of matrix")));
+            }
+            return Ok(morph);
+        }
+        let nested: NestedMorph<T> =
+            bincode::deserialize(&content).map_err(|err| broken(err.to_string()))?;
+        let trie = nested
+            .trie
+            .migrate()
+            .ok_or_else(|| broken(String::from("trie exceeds u32 indices")))?;
+        // content of old versions was not checked on export
+        trie.verify().map_err(|err| broken(err.to_string()))?;
+        Ok(Morph {
+            trie,
+            matrix: nested.matrix,
+            unknown: unknown::Unknown::default(),
+        })
+    }
+
+    // unknown words made where the dictionary does not cover the input
+    pub fn with_unknown(mut self, unknown: unknown::Unknown<T>) -> Result<Self, io::Error> {
+        self.unknown = unknown;
+        if !self.unknown_in_matrix() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unk.def: connection id out of matrix",
+            ));
+        }
+        Ok(self)
+    }
+
+    fn unknown_in_matrix(&self) -> bool {
+        let (_, _, words) = self.unknown.raw_parts();
+        words
+            .iter()
+            .all(|word| self.matrix.contains(word.lid, word.rid))
     }
 
     pub fn parse(&self, input: &[u8]) -> Option<Vec<T>> {
-        if input.is_empty() {
-            return None;
-        }
-        // dp[p] = (cost, lid, word)
-        // p    : position of end of word
-        // cost : total cost
-        // word : None or Word<T>
-        let mut dp: DPTable<T> = Vec::new();
-        dp.resize_with(input.len(), Vec::new);
-        // initialize dp
-        for end in 1..input.len() + 1 {
-            if let Ok(words) = self.trie.find(&input[..end]) {
-                for word in words {
-                    dp[end - 1].push((self.matrix.at(0, word.rid) as i64 + word.cost, vec![word]));
-                }
-            }
+        self.tokenize(input)
+            .map(|tokens| tokens.iter().map(|token| token.word.info.clone()).collect())
+    }
+}
+
+// Analyses were methods of Morph before Lexicon was added, and are kept for its callers
+impl<T: Serialize> Morph<T> {
+    pub fn tokenize(&self, input: &[u8]) -> Option<Vec<Token<'_, T>>> {
+        Lexicon::tokenize(self, input)
+    }
+
+    pub fn wakati(&self, input: &[u8]) -> Option<Vec<(usize, usize)>> {
+        Lexicon::wakati(self, input)
+    }
+
+    pub fn lattice(&self, input: &[u8]) -> Vec<LatticeNode<'_, T>> {
+        Lexicon::lattice(self, input)
+    }
+
+    pub fn nbest(&self, input: &[u8], n: usize) -> Vec<(i64, Vec<Token<'_, T>>)> {
+        Lexicon::nbest(self, input, n)
+    }
+}
+
+fn write_words<W: Write, T: AsRef<str>>(
+    stream: &mut W,
+    key: &[u8],
+    words: &[Word<T>],
+) -> Result<(), io::Error> {
+    let surface = String::from_utf8_lossy(key);
+    let surface = if surface.contains(',') || surface.contains('"') {
+        format!("\"{}\"", surface.replace('"', "\"\""))
+    } else {
+        surface.into_owned()
+    };
+    for word in words {
+        writeln!(
+            stream,
+            "{},{},{},{},{}",
+            surface,
+            word.lid,
+            word.rid,
+            word.cost,
+            word.info.as_ref()
+        )?;
+    }
+    Ok(())
+}
+
+// Dictionary which analyses run on
+pub trait Lexicon {
+    type Info;
+
+    // trie from surfaces to their words
+    fn trie(&self) -> trie::TrieView<'_, Word<Self::Info>>;
+
+    // cost of connecting a word with lid to the following word with rid. id 0 is BOS/EOS.
+    fn connection(&self, lid: usize, rid: usize) -> i32;
+
+    // matrix.def of connection costs. pairs never given are omitted.
+    fn dump_matrix<W: Write>(&self, target: &mut W) -> Result<(), io::Error>
+    where
+        Self: Sized;
+
+    // words whose surface is a prefix of way with the length of the surface, shortest first
+    fn search<'a>(&'a self, way: &[u8], f: &mut dyn FnMut(usize, &'a [Word<Self::Info>])) {
+        for (len, words) in self.trie().common_prefix_search(way) {
+            f(len, words);
         }
-        // fill dp
-        for end in 2..input.len() + 1 {
-            for begin in 1..end {
-                if let Ok(words) = self.trie.find(&input[begin..end]) {
-                    for word in words {
-                        let mut best: Option<(i64, Vec<&Word<T>>)> = None;
-                        for prev in &dp[begin - 1] {
-                            let join_cost = self.matrix.at(prev.1.last().unwrap().lid, word.rid);
-                            let total_cost = prev.0 + word.cost + join_cost as i64;
-                            best = match best {
-                                Some(inner) => {
-                                    if total_cost < inner.0 {
-                                        let mut path = prev.1.clone();
-                                        path.push(word);
-                                        Some((total_cost, path))
-                                    } else {
-                                        Some(inner)
-                                    }
-                                }
-                                None => {
-                                    let mut path = prev.1.clone();
-                                    path.push(word);
-                                    Some((total_cost, path))
-                                }
-                            }
-                        }
-                        if let Some(inner) = best {
-                            dp[end - 1].push(inner);
-                        }
+    }
+
+    // words made where the dictionary does not cover the input. none by default.
+    fn unknown(&self) -> unknown::UnknownView<'_, Self::Info> {
+        unknown::UnknownView::empty()
+    }
+
+    // invariants of the trie of the dictionary
+    fn verify(&self) -> Result<(), trie::VerifyError> {
+        self.trie().verify()
+    }
+
+    fn stats(&self) -> trie::Stats {
+        self.trie().stats()
+    }
+
+    // Words as MeCab CSV in ascending order of surface.
+    // Quotes around fields of the source are not restored except for surfaces.
+    fn dump_dict<W: Write>(&self, target: &mut W) -> Result<(), io::Error>
+    where
+        Self: Sized,
+        Self::Info: AsRef<str>,
+    {
+        let mut stream = io::BufWriter::new(target);
+        for (key, words) in self.trie().iter() {
+            write_words(&mut stream, &key, words)?;
+        }
+        stream.flush()
+    }
+
+    // Words whose surfaces match pattern in the format of dump_dict
+    fn lookup<W: Write>(&self, pattern: &pattern::Pattern, target: &mut W) -> Result<(), io::Error>
+    where
+        Self: Sized,
+        Self::Info: AsRef<str>,
+    {
+        let mut stream = io::BufWriter::new(target);
+        for (key, _, words) in self.trie().search(pattern) {
+            write_words(&mut stream, &key, words)?;
+        }
+        stream.flush()
+    }
+
+    fn tokenize(&self, input: &[u8]) -> Option<Vec<Token<'_, Self::Info>>> {
+        let (lattice, last) = viterbi(self, input)?;
+        let mut tokens = Vec::new();
+        let mut here = Some(last);
+        while let Some(idx) = here {
+            let node = &lattice[idx];
+            tokens.push(Token {
+                begin: node.begin,
+                end: node.end,
+                cost: node.cost,
+                word: node.word,
+                unknown: node.unknown,
+            });
+            here = node.prev;
+        }
+        tokens.reverse();
+        Some(tokens)
+    }
+
+    // Segmentation only. Neither Token nor info is built.
+    fn wakati(&self, input: &[u8]) -> Option<Vec<(usize, usize)>> {
+        let (lattice, last) = viterbi(self, input)?;
+        let mut spans = Vec::new();
+        let mut here = Some(last);
+        while let Some(idx) = here {
+            spans.push((lattice[idx].begin, lattice[idx].end));
+            here = lattice[idx].prev;
+        }
+        spans.reverse();
+        Some(spans)
+    }
+
+    // All nodes reachable from BOS
+    fn lattice(&self, input: &[u8]) -> Vec<LatticeNode<'_, Self::Info>> {
+        build_lattice(self, input).0
+    }
+
+    // All nodes reachable from BOS and the last node of the best path, if any reaches EOS.
+    // The best path is followed by prev from it.
+    fn lattice_with_best(&self, input: &[u8]) -> (Vec<LatticeNode<'_, Self::Info>>, Option<usize>) {
+        let (lattice, ends) = build_lattice(self, input);
+        let last = best_last(self, &lattice, &ends[input.len()]);
+        (lattice, last)
+    }
+
+    // At most n paths in ascending order of total cost (including EOS).
+    // A* search from EOS with the cost of the best path to each node as heuristic.
+    fn nbest(&self, input: &[u8], n: usize) -> Vec<(i64, Vec<Token<'_, Self::Info>>)> {
+        if input.is_empty() || n == 0 {
+            return Vec::new();
+        }
+        let (lattice, ends) = build_lattice(self, input);
+        let head = self.unknown().space(input);
+        // (node, cost from node to EOS excluding cost of node itself, next state)
+        // node None stands for BOS.
+        let mut states: Vec<(Option<usize>, i64, Option<usize>)> = Vec::new();
+        let mut queue = BinaryHeap::new();
+        for idx in &ends[input.len()] {
+            let to_eos = self.connection(lattice[*idx].word.lid, 0) as i64;
+            queue.push(Reverse((lattice[*idx].cost + to_eos, states.len())));
+            states.push((Some(*idx), to_eos, None));
+        }
+        let mut paths = Vec::new();
+        while let Some(Reverse((total_cost, state_idx))) = queue.pop() {
+            let (node, to_eos, _) = states[state_idx];
+            let node = match node {
+                Some(node) => &lattice[node],
+                None => {
+                    let mut tokens = Vec::new();
+                    let mut cost = 0i64;
+                    let mut prev_lid = 0;
+                    let mut here = states[state_idx].2;
+                    while let Some(state) = here {
+                        let node = &lattice[states[state].0.unwrap()];
+                        cost += self.connection(prev_lid, node.word.rid) as i64 + node.word.cost;
+                        prev_lid = node.word.lid;
+                        tokens.push(Token {
+                            begin: node.begin,
+                            end: node.end,
+                            cost,
+                            word: node.word,
+                            unknown: node.unknown,
+                        });
+                        here = states[state].2;
                     }
+                    paths.push((total_cost, tokens));
+                    if paths.len() >= n {
+                        break;
+                    }
+                    continue;
                 }
+            };
+            let to_eos = to_eos + node.word.cost;
+            if node.begin == head {
+                let to_eos = to_eos + self.connection(0, node.word.rid) as i64;
+                queue.push(Reverse((to_eos, states.len())));
+                states.push((None, to_eos, Some(state_idx)));
+            }
+            for prev in &ends[node.begin] {
+                let to_eos =
+                    to_eos + self.connection(lattice[*prev].word.lid, node.word.rid) as i64;
+                queue.push(Reverse((lattice[*prev].cost + to_eos, states.len())));
+                states.push((Some(*prev), to_eos, Some(state_idx)));
             }
         }
+        paths
+    }
 
-        // select best path
-        let mut best: Option<(i64, &Vec<&Word<T>>)> = None;
-        for (cost, path) in &dp[input.len() - 1] {
-            let cost = *cost + self.matrix.at(path.last().unwrap().lid, 0) as i64;
-            best = match best {
-                Some((best_cost, best_path)) => {
-                    if cost < best_cost {
-                        Some((cost, path))
-                    } else {
-                        Some((best_cost, best_path))
-                    }
+    // Apply f to every input on threads sharing this dictionary.
+    // Results are in the same order as inputs.
+    fn map_batch<S, R, F>(&self, inputs: &[S], threads: usize, f: F) -> Vec<R>
+    where
+        S: Sync,
+        R: Send,
+        F: Fn(&Self, &S) -> R + Sync,
+        Self: Sync + Sized,
+    {
+        let threads = std::cmp::max(1, std::cmp::min(threads, inputs.len()));
+        if threads == 1 {
+            return inputs.iter().map(|input| f(self, input)).collect();
+        }
+        // small chunks so that a thread given long sentences does not hold up the rest
+        let chunk_size = std::cmp::max(1, inputs.len() / (threads * 4));
+        let chunks: Vec<&[S]> = inputs.chunks(chunk_size).collect();
+        let next = AtomicUsize::new(0);
+        let mut done: Vec<(usize, Vec<R>)> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut done = Vec::new();
+                        loop {
+                            let idx = next.fetch_add(1, Ordering::Relaxed);
+                            if idx >= chunks.len() {
+                                break done;
+                            }
+                            done.push((
+                                idx,
+                                chunks[idx].iter().map(|input| f(self, input)).collect(),
+                            ));
+                        }
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().unwrap())
+                .collect()
+        });
+        done.sort_by_key(|(idx, _)| *idx);
+        done.into_iter().flat_map(|(_, results)| results).collect()
+    }
+}
+
+// Forward pass. Returns nodes and indices of nodes ending at each position.
+fn build_lattice<'a, L: Lexicon + ?Sized>(
+    lexicon: &'a L,
+    input: &[u8],
+) -> (Vec<LatticeNode<'a, L::Info>>, Vec<Vec<usize>>) {
+    let mut lattice: Vec<LatticeNode<'a, L::Info>> = Vec::new();
+    // ends[p]: indices of nodes which end at p
+    let mut ends: Vec<Vec<usize>> = Vec::new();
+    ends.resize_with(input.len() + 1, Vec::new);
+    // spaces are skipped before each word as MeCab does, so nodes ending before them
+    // are also put where they end. nodes before trailing spaces reach EOS.
+    let head = lexicon.unknown().space(input);
+    for begin in head..input.len() {
+        if begin > head && ends[begin].is_empty() {
+            continue;
+        }
+        let space = lexicon.unknown().space(&input[begin..]);
+        if space > 0 {
+            let prevs = ends[begin].clone();
+            ends[begin + space].extend(prevs);
+            continue;
+        }
+        let mut add = |len: usize, words: &'a [Word<L::Info>], unknown: bool| {
+            for word in words {
+                // the first word connects to BOS
+                let mut best = if begin == head {
+                    Some((lexicon.connection(0, word.rid) as i64, None))
+                } else {
+                    None
+                };
+                for prev in &ends[begin] {
+                    let join_cost = lexicon.connection(lattice[*prev].word.lid, word.rid);
+                    let total_cost = lattice[*prev].cost + join_cost as i64;
+                    best = match best {
+                        Some((best_cost, _)) if total_cost >= best_cost => best,
+                        _ => Some((total_cost, Some(*prev))),
+                    };
+                }
+                if let Some((cost, prev)) = best {
+                    ends[begin + len].push(lattice.len());
+                    lattice.push(LatticeNode {
+                        begin,
+                        end: begin + len,
+                        cost: cost + word.cost,
+                        prev,
+                        word,
+                        unknown,
+                    });
                 }
-                None => Some((cost, path)),
             }
-        }
+        };
+        let mut found = false;
+        lexicon.search(&input[begin..], &mut |len, words| {
+            found = true;
+            add(len, words, false)
+        });
+        lexicon
+            .unknown()
+            .search(&input[begin..], found, &mut |len, words| {
+                add(len, words, true)
+            });
+    }
+    (lattice, ends)
+}
+
+// Returns all reachable nodes and the index of the last node of the best path.
+fn viterbi<'a, L: Lexicon + ?Sized>(
+    lexicon: &'a L,
+    input: &[u8],
+) -> Option<(Vec<LatticeNode<'a, L::Info>>, usize)> {
+    if input.is_empty() {
+        return None;
+    }
+    let (lattice, ends) = build_lattice(lexicon, input);
+    let last = best_last(lexicon, &lattice, &ends[input.len()])?;
+    Some((lattice, last))
+}
+
+// The node of lasts which makes the best path with EOS
+fn best_last<L: Lexicon + ?Sized>(
+    lexicon: &L,
+    lattice: &[LatticeNode<'_, L::Info>],
+    lasts: &[usize],
+) -> Option<usize> {
+    let mut best: Option<(i64, usize)> = None;
+    for idx in lasts {
+        let cost = lattice[*idx].cost + lexicon.connection(lattice[*idx].word.lid, 0) as i64;
+        best = match best {
+            Some((best_cost, _)) if cost >= best_cost => best,
+            _ => Some((cost, *idx)),
+        };
+    }
+    best.map(|(_, last)| last)
+}
+
+impl<T: Serialize> Lexicon for Morph<T> {
+    type Info = T;
+
+    fn trie(&self) -> trie::TrieView<'_, Word<T>> {
+        self.trie.view()
+    }
 
-        best.map(|x| x.1.iter().map(|word| word.info.clone()).collect())
+    fn connection(&self, lid: usize, rid: usize) -> i32 {
+        self.matrix.at(lid, rid)
+    }
+
+    fn unknown(&self) -> unknown::UnknownView<'_, T> {
+        self.unknown.view()
+    }
+
+    fn dump_matrix<W: Write>(&self, target: &mut W) -> Result<(), io::Error> {
+        let mut stream = io::BufWriter::new(target);
+        self.matrix.dump(&mut stream)?;
+        stream.flush()
+    }
+
+    // the trie itself has more invariants than its view, e.g. of blank cells
+    fn verify(&self) -> Result<(), trie::VerifyError> {
+        self.trie.verify()
+    }
+
+    fn stats(&self) -> trie::Stats {
+        self.trie.stats()
+    }
+}
+
+impl<T: Serialize + DeserializeOwned + Clone + Debug + Send + Sync> Morph<T> {
+    pub fn map_batch<S, R, F>(&self, inputs: &[S], threads: usize, f: F) -> Vec<R>
+    where
+        S: Sync,
+        R: Send,
+        F: Fn(&Self, &S) -> R + Sync,
+    {
+        Lexicon::map_batch(self, inputs, threads, f)
+    }
+
+    pub fn parse_batch<S: AsRef<[u8]> + Sync>(
+        &self,
+        inputs: &[S],
+        threads: usize,
+    ) -> Vec<Option<Vec<T>>> {
+        self.map_batch(inputs, threads, |morph, input| morph.parse(input.as_ref()))
+    }
+
+    // from_text with options of building the trie
+    pub fn from_text_with<R: Read, F>(
+        matrix_src: &mut R,
+        dict_src: &mut R,
+        classifier: F,
+        options: parser::BuildOptions,
+    ) -> Result<Self, io::Error>
+    where
+        F: Fn(&[&str]) -> (Vec<u8>, Word<T>),
+    {
+        let trie = parser::build_trie_with(dict_src, classifier, options)?;
+        let matrix = matrix::Matrix::new(matrix_src).unwrap();
+        Ok(Morph {
+            trie,
+            matrix,
+            unknown: unknown::Unknown::default(),
+        })
     }
 }
 
 #[cfg(test)]
 mod test_morph {
+    use super::header::InfoType;
     use super::*;
     use std::io::Cursor;
 
+    impl InfoType for u32 {
+        const TAG: &'static str = "u32";
+    }
+
     #[test]
     fn test_import_export() {
         let dict_src = "蟹,0,10,100,カニ\n\
@@ -145,54 +627,209 @@ mod test_morph {
                           2 0 21
                           2 1 -54
                           2 2 512";
-        let morph = Morph::from_text(
-            &mut Cursor::new(matrix_src.as_bytes()),
-            &mut Cursor::new(dict_src.as_bytes()),
-            |arr| {
-                (
-                    arr[0].as_bytes().to_vec(),
-                    Word {
-                        info: String::from(arr[4].trim()),
-                        lid: arr[1].parse().unwrap(),
-                        rid: arr[2].parse().unwrap(),
-                        cost: arr[3].parse().unwrap(),
-                    },
-                )
-            },
-        )
-        .unwrap();
+        let morph = testutil::morph(matrix_src, dict_src);
         let mut bytes = Vec::new();
         morph.export(&mut bytes).unwrap();
-        let restored = Morph::import(&mut Cursor::new(bytes)).unwrap();
-        assert_eq!(
-            restored.trie.find("蟹".as_bytes()),
-            Ok(&[Word {
-                lid: 0,
-                rid: 10,
-                cost: 100,
-                info: String::from("カニ"),
-            }][..])
-        );
-        assert_eq!(
-            restored.trie.find("土".as_bytes()),
-            Ok(&[Word {
-                lid: 1,
-                rid: 20,
-                cost: 200,
-                info: String::from("ツチ"),
-            }][..])
-        );
-        assert_eq!(
-            restored.trie.find("味".as_bytes()),
-            Ok(&[Word {
-                lid: 2,
-                rid: 30,
-                cost: 300,
-                info: String::from("アジ"),
-            }][..])
+        let restored = Morph::import(&mut Cursor::new(&bytes)).unwrap();
+        assert_eq!(
+            restored.trie.iter().collect::<Vec<_>>(),
+            morph.trie.iter().collect::<Vec<_>>()
+        );
+        match Morph::<u32>::import(&mut Cursor::new(&bytes)) {
+            Err(header::ImportError::TypeMismatch { found, .. }) => {
+                assert_eq!(found, "String")
+            }
+            _ => panic!("imported as another type"),
+        }
+        assert_eq!(
+            restored.trie.get("蟹".as_bytes()),
+            Some(
+                &[Word {
+                    lid: 0,
+                    rid: 10,
+                    cost: 100,
+                    info: String::from("カニ"),
+                }][..]
+            )
+        );
+        assert_eq!(
+            restored.trie.get("土".as_bytes()),
+            Some(
+                &[Word {
+                    lid: 1,
+                    rid: 20,
+                    cost: 200,
+                    info: String::from("ツチ"),
+                }][..]
+            )
+        );
+        assert_eq!(
+            restored.trie.get("味".as_bytes()),
+            Some(
+                &[Word {
+                    lid: 2,
+                    rid: 30,
+                    cost: 300,
+                    info: String::from("アジ"),
+                }][..]
+            )
         );
         assert_eq!(restored.matrix.at(0, 1), 121);
         assert_eq!(restored.matrix.at(2, 1), -54);
+
+        let mut bytes = Vec::new();
+        morph.export_with(&mut bytes, "EUC-JP").unwrap();
+        let (_, header) = header::read(&mut &bytes[..]).unwrap();
+        assert_eq!(header.charset, "EUC-JP");
+        assert!(Morph::<String>::import(&mut Cursor::new(&bytes)).is_ok());
+        let mut bytes = Vec::new();
+        morph.export_with(&mut bytes, "EBCDIC").unwrap();
+        match Morph::<String>::import(&mut Cursor::new(&bytes)) {
+            Err(header::ImportError::UnknownCharset { charset }) => assert_eq!(charset, "EBCDIC"),
+            _ => panic!("imported from an unknown charset"),
+        }
+    }
+
+    #[test]
+    fn test_import_nested() {
+        let dict_src = "蟹,0,0,100,カニ\n\
+                        蟹,0,0,200,ガニ\n\
+                        土,0,0,200,ツチ";
+        let mut morph = testutil::morph("1 1\n0 0 0", dict_src);
+        // dictionary of version 1, whose keys are in nodes to the end
+        morph.trie.expand_tails();
+        let widen = |n: u32| {
+            if n == u32::MAX {
+                usize::MAX
+            } else {
+                n as usize
+            }
+        };
+        let (tree, storage, _, _) = morph.trie.raw_parts();
+        let trie = trie::NestedTrie {
+            capacities: Vec::new(),
+            cache: Vec::new(),
+            tree: tree
+                .iter()
+                .map(|node| trie::WideNode {
+                    base: widen(node.base),
+                    check: widen(node.check),
+                    id: widen(node.id),
+                })
+                .collect(),
+            storage: storage.iter().map(|words| words.to_vec()).collect(),
+        };
+        // bincode serializes NestedMorph as a tuple of its fields
+        let content = bincode::serialize(&(trie, &morph.matrix)).unwrap();
+        let mut bytes = Vec::new();
+        let header = header::Header::new(std::any::type_name::<String>(), "UTF-8", &content);
+        header::write(&mut bytes, &header, &content).unwrap();
+        bytes[8..12].copy_from_slice(&1u32.to_le_bytes());
+
+        let migrated = Morph::<String>::import(&mut Cursor::new(&bytes)).unwrap();
+        assert!(migrated.verify().is_ok());
+        assert!(migrated.trie.iter().eq(morph.trie.iter()));
+        assert_eq!(
+            migrated.parse("蟹土".as_bytes()),
+            morph.parse("蟹土".as_bytes())
+        );
+
+        // an index beyond u32 can not be migrated
+        let trie = trie::NestedTrie::<Word<String>> {
+            capacities: Vec::new(),
+            cache: Vec::new(),
+            tree: vec![trie::WideNode {
+                base: 1 << 40,
+                check: usize::MAX,
+                id: 0,
+            }],
+            storage: Vec::new(),
+        };
+        let content = bincode::serialize(&(trie, &morph.matrix)).unwrap();
+        let mut bytes = Vec::new();
+        let header = header::Header::new(String::TAG, "UTF-8", &content);
+        header::write(&mut bytes, &header, &content).unwrap();
+        bytes[8..12].copy_from_slice(&1u32.to_le_bytes());
+        assert!(matches!(
+            Morph::<String>::import(&mut Cursor::new(&bytes)),
+            Err(header::ImportError::Broken { .. })
+        ));
+    }
+
+    #[test]
+    fn test_unknown_out_of_matrix() {
+        let morph = testutil::morph("2 1\n0 0 0\n1 0 0", "東,0,0,100,ヒガシ");
+        let unknown = || {
+            unknown::Unknown::from_text(
+                &mut "DEFAULT 0 1 0\n".as_bytes(),
+                &mut "DEFAULT,1,1,3274,記号\n".as_bytes(),
+                testutil::classify,
+            )
+            .unwrap()
+        };
+        let err = morph.with_unknown(unknown()).err().unwrap();
+        assert_eq!(err.to_string(), "unk.def: connection id out of matrix");
+
+        // imported as is, it would panic on the matrix while tokenizing
+        let mut morph = testutil::morph("2 1\n0 0 0\n1 0 0", "東,0,0,100,ヒガシ");
+        morph.unknown = unknown();
+        let mut bytes = Vec::new();
+        morph.export(&mut bytes).unwrap();
+        match Morph::<String>::import(&mut Cursor::new(&bytes)) {
+            Err(header::ImportError::Broken { reason }) => {
+                assert_eq!(reason, "connection id out of matrix")
+            }
+            _ => panic!("imported unknown words out of the matrix"),
+        }
+    }
+
+    #[test]
+    fn test_dump() {
+        let dict_src = "都,3,2,9428,名詞,接尾\n\
+                        東京,1,1,3003,名詞,固有名詞\n\
+                        \",\",5,5,100,記号,読点\n\
+                        \"1,\"\"2\"\"\",6,6,200,名詞,数\n\
+                        東,8,7,6245,名詞,一般\n\
+                        東京,1,1,4000,名詞,一般\n";
+        let matrix_src = "9 8\n0 7 -283\n0 1 -310\n8 1 -368\n";
+        let morph = testutil::morph(matrix_src, dict_src);
+        let mut dict = Vec::new();
+        morph.dump_dict(&mut dict).unwrap();
+        let mut matrix = Vec::new();
+        morph.dump_matrix(&mut matrix).unwrap();
+        assert_eq!(
+            String::from_utf8(dict.clone()).unwrap(),
+            "\",\",5,5,100,記号,読点\n\
+             \"1,\"\"2\"\"\",6,6,200,名詞,数\n\
+             東,8,7,6245,名詞,一般\n\
+             東京,1,1,3003,名詞,固有名詞\n\
+             東京,1,1,4000,名詞,一般\n\
+             都,3,2,9428,名詞,接尾\n"
+        );
+        assert_eq!(
+            String::from_utf8(matrix.clone()).unwrap(),
+            "9 8\n0 1 -310\n0 7 -283\n8 1 -368\n"
+        );
+
+        let mut found = Vec::new();
+        morph
+            .lookup(&pattern::Pattern::glob("東*").unwrap(), &mut found)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(found).unwrap(),
+            "東,8,7,6245,名詞,一般\n\
+             東京,1,1,3003,名詞,固有名詞\n\
+             東京,1,1,4000,名詞,一般\n"
+        );
+
+        // compiling the dump gives the same dictionary
+        let restored = Morph::from_text(
+            &mut Cursor::new(matrix),
+            &mut Cursor::new(dict),
+            testutil::classify,
+        )
+        .unwrap();
+        assert!(restored.trie.iter().eq(morph.trie.iter()));
     }
 
     #[test]
@@ -223,22 +860,7 @@ mod test_morph {
             5 6 -12165
             6 6 -3547
             7 0 -409";
-        let morph = Morph::from_text(
-            &mut Cursor::new(matrix_src.as_bytes()),
-            &mut Cursor::new(dict_src.as_bytes()),
-            |arr| {
-                (
-                    arr[0].as_bytes().to_vec(),
-                    Word {
-                        info: String::from(arr[4].trim()),
-                        lid: arr[1].parse().unwrap(),
-                        rid: arr[2].parse().unwrap(),
-                        cost: arr[3].parse().unwrap(),
-                    },
-                )
-            },
-        )
-        .unwrap();
+        let morph = testutil::morph(matrix_src, dict_src);
         assert_eq!(
             morph.parse("東京都に住む".as_bytes()),
             Some(vec![
@@ -248,5 +870,117 @@ mod test_morph {
                 String::from("住む・動詞・スム"),
             ])
         );
+        assert_eq!(
+            morph.wakati("東京都に住む".as_bytes()),
+            Some(vec![(0, 6), (6, 9), (9, 12), (12, 18)])
+        );
+        assert_eq!(morph.wakati("東西".as_bytes()), None);
+
+        let best = morph.tokenize("東京都に住む".as_bytes()).unwrap();
+        let paths = morph.nbest("東京都に住む".as_bytes(), 100);
+        // 東|京|都|に|住む, 東京|都|に|住む and 東|京都|に|住む with 2 kinds of 都 and に
+        assert_eq!(paths.len(), 10);
+        assert_eq!(paths[0].1, best);
+        assert_eq!(
+            paths[0].0,
+            best.last().unwrap().cost + morph.matrix.at(best.last().unwrap().word.lid, 0) as i64
+        );
+        for i in 1..paths.len() {
+            assert!(paths[i - 1].0 <= paths[i].0);
+            assert!(paths[..i].iter().all(|path| path.1 != paths[i].1));
+        }
+        assert_eq!(morph.nbest("東京都に住む".as_bytes(), 3).len(), 3);
+        assert!(morph.nbest("東西".as_bytes(), 3).is_empty());
+
+        let lattice = morph.lattice("東京都に住む".as_bytes());
+        assert_eq!(lattice.len(), 9);
+        assert!(best.iter().all(|token| lattice
+            .iter()
+            .any(|node| node.begin == token.begin && node.word == token.word)));
+
+        // 西 is not in the dictionary
+        let unknown = unknown::Unknown::from_text(
+            &mut "DEFAULT 0 1 0\nSPACE 0 1 0\nKANJI 0 0 2\n\
+                  0x0020 SPACE\n0x3000 SPACE\n0x4E00..0x9FA5 KANJI\n"
+                .as_bytes(),
+            &mut "DEFAULT,5,4,3274,記号\nSPACE,5,4,3274,空白\nKANJI,2,1,9361,名詞\n".as_bytes(),
+            testutil::classify,
+        )
+        .unwrap();
+        let morph = morph.with_unknown(unknown).unwrap();
+        let tokens = morph.tokenize("東西に住む".as_bytes()).unwrap();
+        assert_eq!(
+            tokens
+                .iter()
+                .map(|token| (token.begin, token.end, token.unknown))
+                .collect::<Vec<_>>(),
+            vec![(0, 3, false), (3, 6, true), (6, 9, false), (9, 15, false)]
+        );
+        assert_eq!(tokens[1].word.info, "名詞");
+        assert_eq!(morph.nbest("東西に住む".as_bytes(), 1)[0].1, tokens);
+        // words of the dictionary are preferred where KANJI is not invoked
+        assert_eq!(
+            morph.wakati("東京都に住む".as_bytes()),
+            Some(vec![(0, 6), (6, 9), (9, 12), (12, 18)])
+        );
+
+        // spaces are skipped before words as MeCab does, and %pS prints them
+        let input = "東京 　に住む";
+        let tokens = morph.tokenize(input.as_bytes()).unwrap();
+        assert_eq!(
+            tokens
+                .iter()
+                .map(|token| (token.begin, token.end))
+                .collect::<Vec<_>>(),
+            vec![(0, 6), (10, 13), (13, 19)]
+        );
+        assert_eq!(morph.nbest(input.as_bytes(), 1)[0].1, tokens);
+        let output = format::OutputFormat {
+            node: format::Template::parse("[%pS]%m(%pL)\\n").unwrap(),
+            ..format::OutputFormat::default()
+        };
+        assert_eq!(
+            output.render(input, &tokens),
+            "[]東京(6)\n[ 　]に(7)\n[]住む(6)\nEOS\n"
+        );
+        // before the first word and before EOS
+        assert_eq!(morph.wakati(" 東京 ".as_bytes()), Some(vec![(1, 7)]));
+        assert_eq!(morph.nbest(" 東京 ".as_bytes(), 1)[0].1.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_batch() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Morph<String>>();
+        assert_send_sync::<Word<String>>();
+
+        let dict_src = "\
+            東,8,7,6245,東\n\
+            京,1,1,10791,京\n\
+            京都,2,1,2135,京都\n\
+            東京,1,1,3003,東京\n\
+            都,3,2,9428,都\n";
+        let matrix_src = "\
+            9 8
+            0 7 -283
+            0 1 -310
+            8 1 -368
+            1 2 -9617
+            1 3 -1303";
+        let morph: Morph<String> = testutil::morph(matrix_src, dict_src);
+        let sentences = ["東京都", "京都", "東", "東西", "都京", ""];
+        let inputs: Vec<&str> = (0..1000).map(|i| sentences[i % sentences.len()]).collect();
+        let sequential: Vec<Option<Vec<String>>> = inputs
+            .iter()
+            .map(|input| morph.parse(input.as_bytes()))
+            .collect();
+        assert!(sequential.iter().any(Option::is_some));
+        assert_eq!(morph.parse_batch(&inputs, 4), sequential);
+        assert_eq!(morph.parse_batch(&inputs, 1), sequential);
+        assert_eq!(
+            morph.parse_batch(&inputs[..3], 16),
+            sequential[..3].to_vec()
+        );
+        assert_eq!(morph.parse_batch(&[] as &[&str], 4), vec![]);
     }
 }
@@ -1,6 +1,525 @@
 // copyright (c) 2019 Nakano Masaki <namachan10777@gmail.com>
 
 use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use namaco::Lexicon;
+
+// info holds feature columns (after surface, lid, rid and cost) joined by comma.
+fn ipadic_word(arr: &[&str]) -> (Vec<u8>, namaco::parser::Word<String>) {
+    (
+        arr[0].as_bytes().to_vec(),
+        namaco::parser::Word {
+            info: arr[4..].join(",").trim_end().to_owned(),
+            lid: arr[1].parse().unwrap(),
+            rid: arr[2].parse().unwrap(),
+            cost: arr[3].parse().unwrap(),
+        },
+    )
+}
+
+// validator of counts such as --threads, where 0 would do nothing forever
+fn positive(value: String) -> Result<(), String> {
+    match value.parse::<usize>() {
+        Ok(count) if count > 0 => Ok(()),
+        _ => Err(String::from("must be a positive integer")),
+    }
+}
+
+fn output_format_args<'a, 'b>() -> Vec<clap::Arg<'a, 'b>> {
+    vec![
+        clap::Arg::with_name("OUTPUT_FORMAT")
+            .short("O")
+            .long("output-format")
+            .value_name("NAME")
+            .help("wakati, json, conllu or NAME of node-format-NAME in dicrc"),
+        clap::Arg::with_name("DICRC")
+            .long("dicrc")
+            .value_name("DICRC")
+            .help("pass dicrc of the dictionary"),
+        clap::Arg::with_name("NODE_FORMAT")
+            .long("node-format")
+            .value_name("TEMPLATE")
+            .help("user-defined node format"),
+        clap::Arg::with_name("UNK_FORMAT")
+            .long("unk-format")
+            .value_name("TEMPLATE")
+            .help("user-defined node format of unknown words. the node format without it"),
+        clap::Arg::with_name("BOS_FORMAT")
+            .long("bos-format")
+            .value_name("TEMPLATE")
+            .help("user-defined beginning-of-sentence format"),
+        clap::Arg::with_name("EOS_FORMAT")
+            .long("eos-format")
+            .value_name("TEMPLATE")
+            .help("user-defined end-of-sentence format"),
+    ]
+}
+
+fn output_format(matches: &clap::ArgMatches) -> namaco::format::OutputFormat {
+    let mut format = match matches.value_of("OUTPUT_FORMAT") {
+        Some(name) => {
+            let dicrc = match matches.value_of("DICRC") {
+                Some(dicrc) => dicrc,
+                None => clap::Error::with_description(
+                    "--output-format requires --dicrc except for wakati, json and conllu",
+                    clap::ErrorKind::MissingRequiredArgument,
+                )
+                .exit(),
+            };
+            let dicrc = namaco::format::Dicrc::new(&mut fs::File::open(dicrc).unwrap()).unwrap();
+            dicrc.output_format(name).unwrap()
+        }
+        None => namaco::format::OutputFormat::default(),
+    };
+    if let Some(template) = matches.value_of("NODE_FORMAT") {
+        format.node = namaco::format::Template::parse(template).unwrap();
+        format.unk = format.node.clone();
+    }
+    if let Some(template) = matches.value_of("UNK_FORMAT") {
+        format.unk = namaco::format::Template::parse(template).unwrap();
+    }
+    if let Some(template) = matches.value_of("BOS_FORMAT") {
+        format.bos = namaco::format::Template::parse(template).unwrap();
+    }
+    if let Some(template) = matches.value_of("EOS_FORMAT") {
+        format.eos = namaco::format::Template::parse(template).unwrap();
+    }
+    format
+}
+
+enum Output {
+    Wakati,
+    Json,
+    Conllu,
+    Template(namaco::format::OutputFormat),
+}
+
+fn output(matches: &clap::ArgMatches) -> Output {
+    match matches.value_of("OUTPUT_FORMAT") {
+        Some("wakati") => Output::Wakati,
+        Some("json") => Output::Json,
+        Some("conllu") => Output::Conllu,
+        _ => Output::Template(output_format(matches)),
+    }
+}
+
+// None if failed to parse. sent_id is 1-origin.
+fn render<L>(morph: &L, output: &Output, sent_id: usize, input: &str) -> Option<String>
+where
+    L: Lexicon,
+    L::Info: AsRef<str>,
+{
+    // a blank line is an empty sentence, not a failure
+    if input.is_empty() {
+        let tokens: &[namaco::Token<L::Info>] = &[];
+        return Some(match output {
+            Output::Wakati => String::from("\n"),
+            Output::Json => namaco::format::render_json(input, Some(tokens)),
+            // a sentence of CoNLL-U needs at least one token
+            Output::Conllu => String::new(),
+            Output::Template(format) => format.render(input, tokens),
+        });
+    }
+    match output {
+        Output::Wakati => morph.wakati(input.as_bytes()).map(|spans| {
+            let words: Vec<&str> = spans
+                .iter()
+                .map(|(begin, end)| &input[*begin..*end])
+                .collect();
+            words.join(" ") + "\n"
+        }),
+        Output::Json => morph
+            .tokenize(input.as_bytes())
+            .map(|tokens| namaco::format::render_json(input, Some(&tokens))),
+        Output::Conllu => morph
+            .tokenize(input.as_bytes())
+            .map(|tokens| namaco::format::render_conllu(sent_id, input, &tokens)),
+        Output::Template(format) => morph
+            .tokenize(input.as_bytes())
+            .map(|tokens| format.render(input, &tokens)),
+    }
+}
+
+// printed in place of a sentence which failed to parse
+fn render_failure(output: &Output, input: &str) -> String {
+    match output {
+        Output::Json => namaco::format::render_json::<String>(input, None),
+        // a sentence of CoNLL-U needs at least one token
+        Output::Conllu => {
+            eprintln!("failed to parse: {}", input);
+            String::new()
+        }
+        _ => String::from("failed to parse\n"),
+    }
+}
+
+// a line given to parse subcommand
+struct Sentence {
+    line: usize,
+    sent_id: usize,
+    input: String,
+    // decoding error or too long line
+    error: Option<&'static str>,
+}
+
+#[derive(PartialEq, Clone, Copy)]
+enum ErrorPolicy {
+    // print failure in place of the sentence
+    Mark,
+    Skip,
+    Abort,
+}
+
+fn to_morphemes<T: AsRef<str>>(
+    input: &str,
+    tokens: &[namaco::Token<T>],
+) -> Vec<namaco::eval::Morpheme> {
+    tokens
+        .iter()
+        .map(|token| {
+            namaco::eval::Morpheme::new(&input[token.begin..token.end], token.word.info.as_ref())
+        })
+        .collect()
+}
+
+fn import(path: &str) -> namaco::Morph<String> {
+    let imported = fs::File::open(path)
+        .map_err(namaco::header::ImportError::from)
+        .and_then(|mut file| namaco::Morph::import(&mut file));
+    match imported {
+        Ok(morph) => morph,
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn open_mapped(path: &str) -> namaco::mapped::MappedMorph {
+    match namaco::mapped::MappedMorph::open(path) {
+        Ok(morph) => morph,
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+// source file of compile decoded from charset. UTF-8 is read as is.
+fn source(path: &str, charset: &'static encoding_rs::Encoding) -> Box<dyn Read> {
+    let file = fs::File::open(path).unwrap();
+    if charset == encoding_rs::UTF_8 {
+        return Box::new(file);
+    }
+    let mut bytes = Vec::new();
+    io::BufReader::new(file).read_to_end(&mut bytes).unwrap();
+    match charset.decode_without_bom_handling_and_without_replacement(&bytes) {
+        Some(text) => Box::new(io::Cursor::new(text.into_owned().into_bytes())),
+        None => {
+            eprintln!("{}: not encoded in {}", path, charset.name());
+            std::process::exit(1);
+        }
+    }
+}
+
+// whether path was compiled with --mmap. a file which cannot be read is reported on import.
+fn is_mapped(path: &str) -> bool {
+    namaco::mapped::is_mapped(path).unwrap_or(false)
+}
+
+fn stats<L: Lexicon>(morph: &L, compiled: &str) {
+    // statistics of a broken trie are meaningless
+    if let Err(err) = morph.verify() {
+        eprintln!("{}: {}", compiled, err);
+        std::process::exit(1);
+    }
+    print!("{}", morph.stats());
+}
+
+fn dump<L>(morph: &L, matches: &clap::ArgMatches)
+where
+    L: Lexicon,
+    L::Info: AsRef<str>,
+{
+    morph
+        .dump_dict(&mut fs::File::create(matches.value_of("DICT").unwrap()).unwrap())
+        .unwrap();
+    morph
+        .dump_matrix(&mut fs::File::create(matches.value_of("MATRIX").unwrap()).unwrap())
+        .unwrap();
+}
+
+fn lookup<L>(morph: &L, pattern: &namaco::pattern::Pattern)
+where
+    L: Lexicon,
+    L::Info: AsRef<str>,
+{
+    if let Err(err) = morph.lookup(pattern, &mut io::stdout()) {
+        // output cut off by a pipe is not an error
+        if err.kind() != io::ErrorKind::BrokenPipe {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn repl<L>(morph: &L, matches: &clap::ArgMatches)
+where
+    L: Lexicon,
+    L::Info: AsRef<str>,
+{
+    let output = output(matches);
+    let mut buf = String::new();
+    let mut sent_id = 0;
+    loop {
+        buf.clear();
+        if std::io::stdin().read_line(&mut buf).ok() == Some(0) {
+            break;
+        }
+        sent_id += 1;
+        let input = buf.trim();
+        match render(morph, &output, sent_id, input) {
+            Some(rendered) => print!("{}", rendered),
+            None => print!("{}", render_failure(&output, input)),
+        }
+    }
+}
+
+fn parse<L>(morph: &L, matches: &clap::ArgMatches)
+where
+    L: Lexicon + Sync,
+    L::Info: AsRef<str>,
+{
+    let output = output(matches);
+    let encoding =
+        match encoding_rs::Encoding::for_label(matches.value_of("ENCODING").unwrap().as_bytes()) {
+            Some(encoding) => encoding,
+            None => {
+                clap::Error::with_description("unknown encoding", clap::ErrorKind::InvalidValue)
+                    .exit()
+            }
+        };
+    let policy = match matches.value_of("ON_ERROR").unwrap() {
+        "skip" => ErrorPolicy::Skip,
+        "abort" => ErrorPolicy::Abort,
+        _ => ErrorPolicy::Mark,
+    };
+    let max_length: usize = matches.value_of("MAX_LENGTH").unwrap().parse().unwrap();
+    let mut writer: Box<dyn Write> = match matches.value_of("OUTPUT") {
+        Some(path) => Box::new(io::BufWriter::new(fs::File::create(path).unwrap())),
+        None => Box::new(io::BufWriter::new(io::stdout())),
+    };
+    let inputs: Vec<&str> = match matches.values_of("INPUT") {
+        Some(inputs) => inputs.collect(),
+        None => vec!["-"],
+    };
+    let threads: usize = matches.value_of("THREADS").unwrap().parse().unwrap();
+    // lines analyzed at once. memory is bounded by BATCH_PER_THREAD * threads * max_length.
+    const BATCH_PER_THREAD: usize = 256;
+    let mut sent_id = 0;
+    let mut buf = Vec::new();
+    for path in inputs {
+        let src: Box<dyn Read> = if path == "-" {
+            Box::new(io::stdin())
+        } else {
+            Box::new(fs::File::open(path).unwrap())
+        };
+        let mut reader = namaco::stream::LineReader::new(src, max_length);
+        let mut line_cnt = 0;
+        let mut eof = false;
+        while !eof {
+            let mut batch = Vec::new();
+            while batch.len() < BATCH_PER_THREAD * threads {
+                let line = match reader.read_line(&mut buf).unwrap() {
+                    Some(line) => line,
+                    None => {
+                        eof = true;
+                        break;
+                    }
+                };
+                line_cnt += 1;
+                sent_id += 1;
+                let (input, error) = match line {
+                    namaco::stream::Line::TooLong => (String::new(), Some("line too long")),
+                    namaco::stream::Line::Complete => {
+                        match encoding.decode_without_bom_handling_and_without_replacement(&buf) {
+                            Some(input) => (input.trim().to_owned(), None),
+                            None => (
+                                encoding
+                                    .decode_without_bom_handling(&buf)
+                                    .0
+                                    .trim()
+                                    .to_owned(),
+                                Some("invalid byte sequence"),
+                            ),
+                        }
+                    }
+                };
+                batch.push(Sentence {
+                    line: line_cnt,
+                    sent_id,
+                    input,
+                    error,
+                });
+            }
+            let rendered = morph.map_batch(&batch, threads, |morph, sentence| {
+                if sentence.error.is_some() {
+                    None
+                } else {
+                    render(morph, &output, sentence.sent_id, &sentence.input)
+                }
+            });
+            for (sentence, rendered) in batch.iter().zip(rendered) {
+                let error = match (sentence.error, rendered) {
+                    (None, Some(rendered)) => {
+                        writer.write_all(rendered.as_bytes()).unwrap();
+                        continue;
+                    }
+                    (Some(error), _) => error,
+                    (None, None) => "failed to parse",
+                };
+                match policy {
+                    ErrorPolicy::Mark => writer
+                        .write_all(render_failure(&output, &sentence.input).as_bytes())
+                        .unwrap(),
+                    ErrorPolicy::Skip => eprintln!("{}:{}: {}", path, sentence.line, error),
+                    ErrorPolicy::Abort => {
+                        writer.flush().unwrap();
+                        eprintln!("{}:{}: {}", path, sentence.line, error);
+                        std::process::exit(1);
+                    }
+                }
+            }
+        }
+    }
+    writer.flush().unwrap();
+}
+
+// the dictionary is reloaded in the same format, either mapped or not
+fn serve<L>(morph: L, dict: &str, matches: &clap::ArgMatches)
+where
+    L: Lexicon + namaco::handle::FromFile + Send + Sync,
+    L::Info: AsRef<str>,
+{
+    let morph = namaco::handle::MorphHandle::new(morph);
+    let config = namaco::server::Config {
+        addr: format!(
+            "{}:{}",
+            matches.value_of("HOST").unwrap(),
+            matches.value_of("PORT").unwrap()
+        ),
+        max_body: matches.value_of("MAX_BODY").unwrap().parse().unwrap(),
+        threads: matches.value_of("THREADS").unwrap().parse().unwrap(),
+    };
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let handler_shutdown = shutdown.clone();
+    ctrlc::set_handler(move || handler_shutdown.store(true, Ordering::SeqCst)).unwrap();
+    let reload_requested = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, reload_requested.clone()).unwrap();
+    // without --watch, only SIGHUP triggers a reload
+    let interval = if matches.is_present("WATCH") {
+        matches
+            .value_of("WATCH_INTERVAL")
+            .unwrap()
+            .parse::<f64>()
+            .ok()
+            .filter(|seconds| *seconds > 0.0)
+            .and_then(|seconds| std::time::Duration::try_from_secs_f64(seconds).ok())
+            .unwrap_or_else(|| {
+                clap::Error::with_description(
+                    "--watch-interval must be a positive number of seconds",
+                    clap::ErrorKind::InvalidValue,
+                )
+                .exit()
+            })
+    } else {
+        std::time::Duration::from_secs(u64::MAX)
+    };
+    eprintln!("listening on {}", config.addr);
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            namaco::handle::watch(
+                &morph,
+                dict,
+                interval,
+                &shutdown,
+                &reload_requested,
+                |result| match result {
+                    Ok(()) => eprintln!("reloaded {}", dict),
+                    Err(err) => eprintln!("failed to reload {}: {}", dict, err),
+                },
+            )
+        });
+        let result = namaco::server::serve(&morph, &config, &shutdown);
+        // stop the watcher even if the server failed to start
+        shutdown.store(true, Ordering::SeqCst);
+        result.unwrap();
+    });
+}
+
+fn eval<L>(morph: &L, matches: &clap::ArgMatches)
+where
+    L: Lexicon,
+    L::Info: AsRef<str>,
+{
+    let corpus = namaco::eval::read_corpus(
+        &mut fs::File::open(matches.value_of("CORPUS").unwrap()).unwrap(),
+    )
+    .unwrap();
+    let depth: usize = matches.value_of("DEPTH").unwrap().parse().unwrap();
+    let summary_only = matches.is_present("SUMMARY_ONLY");
+    let mut evaluation = namaco::eval::Evaluation::new(depth);
+    for (sentence_idx, gold) in corpus.iter().enumerate() {
+        let input: String = gold.iter().map(|morph| morph.surface.as_str()).collect();
+        let system = morph
+            .tokenize(input.as_bytes())
+            .map(|tokens| to_morphemes(&input, &tokens));
+        let diff = evaluation.add(gold, system.as_deref());
+        let mistaken = diff
+            .iter()
+            .any(|d| !matches!(d, namaco::eval::Diff::Same(_)));
+        if summary_only || !mistaken {
+            continue;
+        }
+        println!("# sentence {}: {}", sentence_idx + 1, input);
+        if system.is_none() {
+            println!("# failed to parse");
+        }
+        for d in diff {
+            let (mark, morph) = match d {
+                namaco::eval::Diff::Same(morph) => (' ', morph),
+                namaco::eval::Diff::Gold(morph) => ('-', morph),
+                namaco::eval::Diff::System(morph) => ('+', morph),
+            };
+            println!("{} {}\t{}", mark, morph.surface, morph.features.join(","));
+        }
+    }
+    println!(
+        "sentences: {} (failed: {})",
+        evaluation.sentences, evaluation.failed_sentences
+    );
+    println!(
+        "segmentation: precision {:.4} ({}/{}) recall {:.4} ({}/{}) F1 {:.4}",
+        evaluation.precision(),
+        evaluation.correct_segments,
+        evaluation.system_tokens,
+        evaluation.recall(),
+        evaluation.correct_segments,
+        evaluation.gold_tokens,
+        evaluation.f1(),
+    );
+    println!(
+        "pos (depth {}): accuracy {:.4} ({}/{})",
+        depth,
+        evaluation.pos_accuracy(),
+        evaluation.correct_pos,
+        evaluation.correct_segments,
+    );
+}
 
 fn main() {
     let matches = clap::App::new("namaco")
@@ -33,51 +552,353 @@ fn main() {
                         .value_name("OUTPUT")
                         .required(true)
                         .help("specify output file name"),
+                )
+                .arg(
+                    clap::Arg::with_name("CHAR_DEF")
+                        .long("char-def")
+                        .value_name("CHAR_DEF")
+                        .requires("UNK_DEF")
+                        .help("pass char.def for unknown words"),
+                )
+                .arg(
+                    clap::Arg::with_name("UNK_DEF")
+                        .long("unk-def")
+                        .value_name("UNK_DEF")
+                        .requires("CHAR_DEF")
+                        .help("pass unk.def for unknown words"),
+                )
+                .arg(
+                    clap::Arg::with_name("CHARSET")
+                        .long("charset")
+                        .value_name("CHARSET")
+                        .default_value("utf-8")
+                        .help(
+                            "encoding of the source files such as euc-jp. recorded in the output",
+                        ),
+                )
+                .arg(
+                    clap::Arg::with_name("MMAP")
+                        .long("mmap")
+                        .help("write the format used in place by mmap"),
+                )
+                .arg(
+                    clap::Arg::with_name("THREADS")
+                        .short("j")
+                        .long("threads")
+                        .value_name("THREADS")
+                        .validator(positive)
+                        .default_value("1")
+                        .help(
+                            "number of threads building the trie. the output does not depend on it",
+                        ),
+                )
+                .arg(
+                    clap::Arg::with_name("ALPHABET")
+                        .long("alphabet")
+                        .help("remap bytes of surfaces by frequency to make the trie smaller"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("stats")
+                .about("verify the trie of a compiled dictionary and print its statistics")
+                .arg(
+                    clap::Arg::with_name("COMPILED")
+                        .required(true)
+                        .help("pass compiled dictionary"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("dump")
+                .about("decompile a compiled dictionary into csv and matrix")
+                .arg(
+                    clap::Arg::with_name("COMPILED")
+                        .required(true)
+                        .help("pass compiled dictionary"),
+                )
+                .arg(
+                    clap::Arg::with_name("DICT")
+                        .short("d")
+                        .long("dict")
+                        .value_name("DICT")
+                        .required(true)
+                        .help("specify output csv file name"),
+                )
+                .arg(
+                    clap::Arg::with_name("MATRIX")
+                        .short("m")
+                        .long("matrix")
+                        .value_name("MATRIX")
+                        .required(true)
+                        .help("specify output matrix file name"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("lookup")
+                .about("print words of a compiled dictionary whose surfaces match a pattern as csv")
+                .arg(
+                    clap::Arg::with_name("COMPILED")
+                        .required(true)
+                        .help("pass compiled dictionary"),
+                )
+                .arg(
+                    clap::Arg::with_name("PATTERN")
+                        .short("p")
+                        .long("pattern")
+                        .value_name("PATTERN")
+                        .required(true)
+                        .help("? matches any character and * any characters of a surface"),
+                )
+                .arg(
+                    clap::Arg::with_name("REGEX")
+                        .long("regex")
+                        .help("read the pattern as a regular expression matching whole surfaces"),
                 ),
         )
         .subcommand(
             clap::SubCommand::with_name("repl")
                 .arg(clap::Arg::with_name("DICT").required(true))
+                .args(&output_format_args())
                 .help("pass compiled dictionary"),
         )
+        .subcommand(
+            clap::SubCommand::with_name("parse")
+                .about("analyze files line by line")
+                .arg(
+                    clap::Arg::with_name("DICT")
+                        .required(true)
+                        .help("pass compiled dictionary"),
+                )
+                .arg(
+                    clap::Arg::with_name("INPUT")
+                        .multiple(true)
+                        .help("pass input files (stdin if omitted or -)"),
+                )
+                .arg(
+                    clap::Arg::with_name("OUTPUT")
+                        .short("o")
+                        .long("output")
+                        .value_name("OUTPUT")
+                        .help("specify output file name (stdout if omitted)"),
+                )
+                .arg(
+                    clap::Arg::with_name("ENCODING")
+                        .long("encoding")
+                        .value_name("ENCODING")
+                        .default_value("utf-8")
+                        .help("encoding of input such as euc-jp or shift_jis. output is utf-8"),
+                )
+                .arg(
+                    clap::Arg::with_name("ON_ERROR")
+                        .long("on-error")
+                        .value_name("POLICY")
+                        .possible_values(&["mark", "skip", "abort"])
+                        .default_value("mark")
+                        .help("what to do with lines which cannot be decoded or parsed"),
+                )
+                .arg(
+                    clap::Arg::with_name("MAX_LENGTH")
+                        .long("max-length")
+                        .value_name("BYTES")
+                        .default_value("1048576")
+                        .help("longer lines are treated as errors"),
+                )
+                .arg(
+                    clap::Arg::with_name("THREADS")
+                        .short("j")
+                        .long("threads")
+                        .value_name("THREADS")
+                        .validator(positive)
+                        .default_value("1")
+                        .help("number of threads sharing the dictionary"),
+                )
+                .args(&output_format_args()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("serve")
+                .about("serve analyses as JSON over HTTP")
+                .arg(
+                    clap::Arg::with_name("DICT")
+                        .required(true)
+                        .help("pass compiled dictionary"),
+                )
+                .arg(
+                    clap::Arg::with_name("HOST")
+                        .long("host")
+                        .value_name("HOST")
+                        .default_value("127.0.0.1"),
+                )
+                .arg(
+                    clap::Arg::with_name("PORT")
+                        .short("p")
+                        .long("port")
+                        .value_name("PORT")
+                        .default_value("8080"),
+                )
+                .arg(
+                    clap::Arg::with_name("MAX_BODY")
+                        .long("max-body")
+                        .value_name("BYTES")
+                        .default_value("1048576")
+                        .help("larger requests are rejected"),
+                )
+                .arg(
+                    clap::Arg::with_name("THREADS")
+                        .short("j")
+                        .long("threads")
+                        .value_name("THREADS")
+                        .validator(positive)
+                        .default_value("4")
+                        .help("number of threads handling requests"),
+                )
+                .arg(
+                    clap::Arg::with_name("WATCH")
+                        .long("watch")
+                        .help("reload DICT when it is replaced (SIGHUP always reloads)"),
+                )
+                .arg(
+                    clap::Arg::with_name("WATCH_INTERVAL")
+                        .long("watch-interval")
+                        .value_name("SECONDS")
+                        .default_value("2")
+                        .help("interval to check modification of DICT"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("eval")
+                .about("evaluate analyses against a gold corpus")
+                .arg(
+                    clap::Arg::with_name("DICT")
+                        .required(true)
+                        .help("pass compiled dictionary"),
+                )
+                .arg(
+                    clap::Arg::with_name("CORPUS")
+                        .required(true)
+                        .help("pass gold corpus in mecab output format"),
+                )
+                .arg(
+                    clap::Arg::with_name("DEPTH")
+                        .long("depth")
+                        .value_name("DEPTH")
+                        .default_value("4")
+                        .help("number of features compared for pos accuracy (0 for all)"),
+                )
+                .arg(
+                    clap::Arg::with_name("SUMMARY_ONLY")
+                        .long("summary-only")
+                        .help("do not print per-sentence diff of mistakes"),
+                ),
+        )
         .get_matches();
     if let Some(matches) = matches.subcommand_matches("compile") {
-        let mut dict_file = fs::File::open(matches.value_of("DICT").unwrap()).unwrap();
-        let mut matrix_file = fs::File::open(matches.value_of("MATRIX").unwrap()).unwrap();
+        let dict = matches.value_of("DICT").unwrap();
+        let charset =
+            match encoding_rs::Encoding::for_label(matches.value_of("CHARSET").unwrap().as_bytes())
+            {
+                Some(charset) => charset,
+                None => {
+                    clap::Error::with_description("unknown charset", clap::ErrorKind::InvalidValue)
+                        .exit()
+                }
+            };
+        let mut dict_file = source(dict, charset);
+        let mut matrix_file = source(matches.value_of("MATRIX").unwrap(), charset);
         let mut output_file = fs::File::create(matches.value_of("OUTPUT").unwrap()).unwrap();
-        let morph = namaco::Morph::from_text(&mut matrix_file, &mut dict_file, |arr| {
-            (
-                arr[0].as_bytes().to_vec(),
-                namaco::parser::Word {
-                    info: arr.join(","),
-                    lid: arr[1].parse().unwrap(),
-                    rid: arr[2].parse().unwrap(),
-                    cost: arr[3].parse().unwrap(),
-                },
-            )
-        })
-        .unwrap();
-        morph.export(&mut output_file).unwrap();
-    } else if let Some(matches) = matches.subcommand_matches("repl") {
-        let morph: namaco::Morph<String> =
-            namaco::Morph::import(&mut fs::File::open(matches.value_of("DICT").unwrap()).unwrap())
-                .unwrap();
-        let mut buf = String::new();
-        loop {
-            buf.clear();
-            if std::io::stdin().read_line(&mut buf).ok() == Some(0) {
-                break;
+        let options = namaco::parser::BuildOptions::default()
+            .threads(matches.value_of("THREADS").unwrap().parse().unwrap())
+            .alphabet(matches.is_present("ALPHABET"));
+        // e.g. an empty surface
+        let morph = match namaco::Morph::from_text_with(
+            &mut matrix_file,
+            &mut dict_file,
+            ipadic_word,
+            options,
+        ) {
+            Ok(morph) => morph,
+            Err(err) => {
+                eprintln!("{}: {}", dict, err);
+                std::process::exit(1);
             }
-            match morph.parse(buf.trim().as_bytes()) {
-                Some(arr) => {
-                    for x in arr {
-                        println!("{:?}", x);
+        };
+        let morph = match (matches.value_of("CHAR_DEF"), matches.value_of("UNK_DEF")) {
+            (Some(char_def), Some(unk_def)) => {
+                let unknown = namaco::unknown::Unknown::from_text(
+                    &mut source(char_def, charset),
+                    &mut source(unk_def, charset),
+                    ipadic_word,
+                );
+                match unknown.and_then(|unknown| morph.with_unknown(unknown)) {
+                    Ok(morph) => morph,
+                    Err(err) => {
+                        eprintln!("{}", err);
+                        std::process::exit(1);
                     }
                 }
-                None => {
-                    println!("failed to parse");
-                }
             }
+            _ => morph,
+        };
+        if matches.is_present("MMAP") {
+            namaco::mapped::export(&morph, &mut output_file).unwrap();
+        } else {
+            morph.export_with(&mut output_file, charset.name()).unwrap();
+        }
+    } else if let Some(matches) = matches.subcommand_matches("stats") {
+        let compiled = matches.value_of("COMPILED").unwrap();
+        if is_mapped(compiled) {
+            stats(&open_mapped(compiled), compiled);
+        } else {
+            stats(&import(compiled), compiled);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("dump") {
+        let compiled = matches.value_of("COMPILED").unwrap();
+        if is_mapped(compiled) {
+            dump(&open_mapped(compiled), matches);
+        } else {
+            dump(&import(compiled), matches);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("lookup") {
+        let compiled = matches.value_of("COMPILED").unwrap();
+        let src = matches.value_of("PATTERN").unwrap();
+        let pattern = if matches.is_present("REGEX") {
+            namaco::pattern::Pattern::regex(src)
+        } else {
+            namaco::pattern::Pattern::glob(src)
+        }
+        .unwrap_or_else(|err| {
+            clap::Error::with_description(&err.to_string(), clap::ErrorKind::InvalidValue).exit()
+        });
+        if is_mapped(compiled) {
+            lookup(&open_mapped(compiled), &pattern);
+        } else {
+            lookup(&import(compiled), &pattern);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("repl") {
+        let dict = matches.value_of("DICT").unwrap();
+        if is_mapped(dict) {
+            repl(&open_mapped(dict), matches);
+        } else {
+            repl(&import(dict), matches);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("parse") {
+        let dict = matches.value_of("DICT").unwrap();
+        if is_mapped(dict) {
+            parse(&open_mapped(dict), matches);
+        } else {
+            parse(&import(dict), matches);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("serve") {
+        let dict = matches.value_of("DICT").unwrap();
+        if is_mapped(dict) {
+            serve(open_mapped(dict), dict, matches);
+        } else {
+            serve(import(dict), dict, matches);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("eval") {
+        let dict = matches.value_of("DICT").unwrap();
+        if is_mapped(dict) {
+            eval(&open_mapped(dict), matches);
+        } else {
+            eval(&import(dict), matches);
         }
     }
 }
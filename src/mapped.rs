@@ -0,0 +1,718 @@
+use super::matrix;
+use super::trie;
+use super::trie::{Node, TrieView};
+use super::unknown;
+use super::unknown::{Category, UnknownView};
+use super::{Lexicon, Morph, Word};
+use serde::Serialize;
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::mem::size_of;
+use std::path::Path;
+
+// Dictionary layout which is used in place after mmap.
+//
+// header: MAGIC, VERSION as u64 little endian, and u64 of
+//   BYTE_ORDER, nodes, ranges, words, lsize, rsize, bytes of infos, codes, tails, bytes of tail,
+//   chars, categories
+// sections, each of them is aligned to 8 bytes:
+//   nodes  [Node]              transition table of the trie, u32 base, check and id
+//   ranges [u32]               words of id are words[ranges[id]..ranges[id + 1]]
+//   words  [Word<MappedStr>]    words of the trie followed by unknown words
+//   matrix [i32]               lsize * rsize connection costs
+//   infos  [u8]                UTF-8 strings referred by words
+//   codes  [u8]                labels of bytes in the trie, 256 or none for the identity
+//   tails  [u32]               tail of the key of id is tail[tails[id]..tails[id + 1]],
+//                              none for keys after the last key with a tail
+//   tail   [u8]
+//   chars  [u32]               categories of characters for unknown words, 0x10000 or none
+//   categories [Category]      words of a category are unknown words[begin..end]
+// Numbers after VERSION are in native byte order, so a file is bound to the architecture
+// which built it. Infos are always strings, so no type is recorded unlike compiled dictionaries.
+pub const MAGIC: &[u8; 8] = b"NAMACOMM";
+// 1: trie with u32 nodes, ranges and tails, and unknown words
+pub const VERSION: u64 = 1;
+const BYTE_ORDER: u64 = 0x0102_0304_0506_0708;
+const HEADER_LEN: usize = 8 + 8 * 13;
+const ALIGN: usize = 8;
+
+// Info of a word in a mapped dictionary.
+// offset is relative to the address of MappedStr itself, so it is valid only in the mapping.
+// Neither Clone nor a constructor is provided for that reason.
+#[repr(C)]
+pub struct MappedStr {
+    offset: usize,
+    len: usize,
+}
+
+impl MappedStr {
+    pub fn as_str(&self) -> &str {
+        // range and encoding are checked in MappedMorph::open
+        unsafe {
+            let ptr = (self as *const MappedStr as *const u8).add(self.offset);
+            std::str::from_utf8_unchecked(std::slice::from_raw_parts(ptr, self.len))
+        }
+    }
+}
+
+impl AsRef<str> for MappedStr {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl fmt::Debug for MappedStr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl PartialEq for MappedStr {
+    fn eq(&self, other: &MappedStr) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Section {
+    // in bytes from the beginning of the file
+    offset: usize,
+    // number of elements
+    len: usize,
+}
+
+impl Section {
+    fn end(&self, size: usize) -> usize {
+        self.offset + self.len * size
+    }
+}
+
+struct Layout {
+    nodes: usize,
+    ranges: usize,
+    words: usize,
+    lsize: usize,
+    rsize: usize,
+    infos: usize,
+    codes: usize,
+    tails: usize,
+    tail: usize,
+    chars: usize,
+    categories: usize,
+}
+
+impl Layout {
+    // nodes, ranges, words, matrix, infos, codes, tails, tail, chars and categories, and the
+    // length of the file. None if the numbers overflow.
+    fn sections(&self) -> Option<([Section; 10], usize)> {
+        let sizes = [
+            (self.nodes, size_of::<Node>()),
+            (self.ranges, size_of::<u32>()),
+            (self.words, size_of::<Word<MappedStr>>()),
+            (self.lsize.checked_mul(self.rsize)?, size_of::<i32>()),
+            (self.infos, 1),
+            (self.codes, 1),
+            (self.tails, size_of::<u32>()),
+            (self.tail, 1),
+            (self.chars, size_of::<u32>()),
+            (self.categories, size_of::<Category>()),
+        ];
+        let mut sections = [Section::default(); 10];
+        let mut offset = HEADER_LEN;
+        for (section, (len, size)) in sections.iter_mut().zip(sizes.iter()) {
+            *section = Section { offset, len: *len };
+            offset = align(offset.checked_add(len.checked_mul(*size)?)?)?;
+        }
+        Some((sections, offset))
+    }
+}
+
+fn align(offset: usize) -> Option<usize> {
+    Some(offset.checked_add(ALIGN - 1)? / ALIGN * ALIGN)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Word<MappedStr> and Node are read as they are
+fn check_platform() -> Result<(), io::Error> {
+    if size_of::<usize>() == 8 && size_of::<Node>() == 12 && size_of::<Word<MappedStr>>() == 40 {
+        Ok(())
+    } else {
+        Err(io::Error::other(
+            "mapped dictionaries are supported only on 64-bit platforms",
+        ))
+    }
+}
+
+fn write_usize<W: Write>(stream: &mut W, n: usize) -> Result<(), io::Error> {
+    stream.write_all(&(n as u64).to_ne_bytes())
+}
+
+// ranges and tails, which fit in u32 as indices of the trie do
+fn write_u32<W: Write>(stream: &mut W, n: usize) -> Result<(), io::Error> {
+    match u32::try_from(n) {
+        Ok(n) => stream.write_all(&n.to_ne_bytes()),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "trie is too large for mapped dictionaries",
+        )),
+    }
+}
+
+fn pad<W: Write>(stream: &mut W, section: &Section, size: usize) -> Result<(), io::Error> {
+    let end = section.end(size);
+    stream.write_all(&[0; ALIGN][..align(end).unwrap() - end])
+}
+
+// Write morph in the mapped layout. Info is stored as UTF-8 string.
+pub fn export<T, W>(morph: &Morph<T>, target: &mut W) -> Result<(), io::Error>
+where
+    T: Serialize + AsRef<str>,
+    W: Write,
+{
+    check_platform()?;
+    let (tree, storage, tails, codes) = morph.trie.raw_parts();
+    let (matrix, rsize) = morph.matrix.raw_parts();
+    let (chars, categories, unknown_words) = morph.unknown.raw_parts();
+    let words = || {
+        storage
+            .iter()
+            .flat_map(|words| words.iter())
+            .chain(unknown_words)
+    };
+    let layout = Layout {
+        nodes: tree.len(),
+        ranges: storage.len() + 1,
+        words: words().count(),
+        lsize: matrix.len().checked_div(rsize).unwrap_or(0),
+        rsize,
+        infos: words().map(|word| word.info.as_ref().len()).sum(),
+        codes: codes.len(),
+        tails: if tails.is_empty() { 0 } else { tails.len() + 1 },
+        tail: tails.iter().map(|tail| tail.len()).sum(),
+        chars: chars.len(),
+        categories: categories.len(),
+    };
+    let (sections, _) = layout.sections().unwrap();
+    let mut stream = io::BufWriter::new(target);
+    stream.write_all(MAGIC)?;
+    stream.write_all(&VERSION.to_le_bytes())?;
+    stream.write_all(&BYTE_ORDER.to_ne_bytes())?;
+    for n in &[
+        layout.nodes,
+        layout.ranges,
+        layout.words,
+        layout.lsize,
+        layout.rsize,
+        layout.infos,
+        layout.codes,
+        layout.tails,
+        layout.tail,
+        layout.chars,
+        layout.categories,
+    ] {
+        write_usize(&mut stream, *n)?;
+    }
+
+    for node in tree {
+        stream.write_all(&node.base.to_ne_bytes())?;
+        stream.write_all(&node.check.to_ne_bytes())?;
+        stream.write_all(&node.id.to_ne_bytes())?;
+    }
+    pad(&mut stream, &sections[0], size_of::<Node>())?;
+
+    let mut range = 0;
+    write_u32(&mut stream, range)?;
+    for words in &storage {
+        range += words.len();
+        write_u32(&mut stream, range)?;
+    }
+    pad(&mut stream, &sections[1], size_of::<u32>())?;
+
+    let mut info_offset = sections[4].offset;
+    for (idx, word) in words().enumerate() {
+        let word_offset = sections[2].offset + idx * size_of::<Word<MappedStr>>();
+        write_usize(&mut stream, info_offset - word_offset)?;
+        write_usize(&mut stream, word.info.as_ref().len())?;
+        stream.write_all(&word.cost.to_ne_bytes())?;
+        write_usize(&mut stream, word.lid)?;
+        write_usize(&mut stream, word.rid)?;
+        info_offset += word.info.as_ref().len();
+    }
+    pad(&mut stream, &sections[2], size_of::<Word<MappedStr>>())?;
+
+    for cost in matrix {
+        stream.write_all(&cost.to_ne_bytes())?;
+    }
+    pad(&mut stream, &sections[3], size_of::<i32>())?;
+
+    for word in words() {
+        stream.write_all(word.info.as_ref().as_bytes())?;
+    }
+    pad(&mut stream, &sections[4], 1)?;
+
+    stream.write_all(codes)?;
+    pad(&mut stream, &sections[5], 1)?;
+
+    if !tails.is_empty() {
+        let mut offset = 0;
+        write_u32(&mut stream, offset)?;
+        for tail in &tails {
+            offset += tail.len();
+            write_u32(&mut stream, offset)?;
+        }
+    }
+    pad(&mut stream, &sections[6], size_of::<u32>())?;
+
+    for tail in &tails {
+        stream.write_all(tail)?;
+    }
+    pad(&mut stream, &sections[7], 1)?;
+
+    for ch in chars {
+        stream.write_all(&ch.to_ne_bytes())?;
+    }
+    pad(&mut stream, &sections[8], size_of::<u32>())?;
+
+    for category in categories {
+        for n in &[
+            category.invoke,
+            category.group,
+            category.length,
+            category.begin,
+            category.end,
+        ] {
+            stream.write_all(&n.to_ne_bytes())?;
+        }
+    }
+    pad(&mut stream, &sections[9], size_of::<Category>())?;
+    stream.flush()
+}
+
+// Whether path starts with MAGIC
+pub fn is_mapped<P: AsRef<Path>>(path: P) -> Result<bool, io::Error> {
+    let mut magic = [0u8; 8];
+    match fs::File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == MAGIC),
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+// Dictionary used in place of the mapped file. Pages are loaded on demand and shared
+// between processes mapping the same file.
+pub struct MappedMorph {
+    map: memmap2::Mmap,
+    nodes: Section,
+    ranges: Section,
+    words: Section,
+    matrix: Section,
+    codes: Section,
+    tails: Section,
+    tail: Section,
+    chars: Section,
+    categories: Section,
+    rsize: usize,
+    // words of the trie, which unknown words follow
+    known: usize,
+}
+
+impl MappedMorph {
+    // The file must not be modified while it is mapped. Replace it by renaming instead.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<MappedMorph, io::Error> {
+        check_platform()?;
+        let file = fs::File::open(path)?;
+        if file.metadata()?.len() < HEADER_LEN as u64 {
+            return Err(invalid("not a mapped dictionary"));
+        }
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        MappedMorph::validate(map)
+    }
+
+    // Check everything which is read without bounds checking
+    fn validate(map: memmap2::Mmap) -> Result<MappedMorph, io::Error> {
+        if &map[..8] != MAGIC {
+            return Err(invalid("not a mapped dictionary"));
+        }
+        let version = u64::from_le_bytes(map[8..16].try_into().unwrap());
+        if version != VERSION {
+            return Err(invalid(&format!(
+                "mapped dictionary format version {} is not supported (expected {}). \
+                 compile it again",
+                version, VERSION
+            )));
+        }
+        let field = |idx: usize| {
+            let bytes = &map[16 + idx * 8..24 + idx * 8];
+            u64::from_ne_bytes(bytes.try_into().unwrap())
+        };
+        if field(0) != BYTE_ORDER {
+            return Err(invalid("dictionary built on another architecture"));
+        }
+        let layout = Layout {
+            nodes: field(1) as usize,
+            ranges: field(2) as usize,
+            words: field(3) as usize,
+            lsize: field(4) as usize,
+            rsize: field(5) as usize,
+            infos: field(6) as usize,
+            codes: field(7) as usize,
+            tails: field(8) as usize,
+            tail: field(9) as usize,
+            chars: field(10) as usize,
+            categories: field(11) as usize,
+        };
+        let (sections, len) = match layout.sections() {
+            Some(sections) => sections,
+            None => return Err(invalid("broken header")),
+        };
+        if len != map.len() {
+            return Err(invalid("unexpected file length"));
+        }
+        if layout.nodes == 0 {
+            return Err(invalid("empty trie"));
+        }
+        let mut morph = MappedMorph {
+            map,
+            nodes: sections[0],
+            ranges: sections[1],
+            words: sections[2],
+            matrix: sections[3],
+            codes: sections[5],
+            tails: sections[6],
+            tail: sections[7],
+            chars: sections[8],
+            categories: sections[9],
+            rsize: layout.rsize,
+            known: 0,
+        };
+        let ranges: &[u32] = morph.slice(morph.ranges);
+        if !trie::in_range(morph.slice(morph.nodes), ranges.len().saturating_sub(1)) {
+            return Err(invalid("node out of range"));
+        }
+        if ranges.windows(2).any(|pair| pair[0] > pair[1])
+            || ranges
+                .last()
+                .is_some_and(|last| *last as usize > layout.words)
+        {
+            return Err(invalid("broken ranges"));
+        }
+        let known = ranges.last().map_or(0, |last| *last as usize);
+        if !unknown::in_range(
+            morph.slice(morph.chars),
+            morph.slice(morph.categories),
+            layout.words - known,
+        ) {
+            return Err(invalid("broken unknown words"));
+        }
+        let tails: &[u32] = morph.slice(morph.tails);
+        if tails.len() > ranges.len()
+            || tails.len() == 1
+            || tails.windows(2).any(|pair| pair[0] > pair[1])
+            || tails
+                .last()
+                .is_some_and(|last| *last as usize > layout.tail)
+        {
+            return Err(invalid("broken tails"));
+        }
+        // none or a permutation of bytes as export writes
+        let codes: &[u8] = morph.slice(morph.codes);
+        let mut seen = [false; 256];
+        for code in codes {
+            seen[*code as usize] = true;
+        }
+        if !(codes.is_empty() || codes.len() == 256 && seen.iter().all(|seen| *seen)) {
+            return Err(invalid("broken codes"));
+        }
+        let infos = sections[4];
+        let words: &[Word<MappedStr>] = morph.slice(morph.words);
+        for (idx, word) in words.iter().enumerate() {
+            if word.lid >= layout.lsize || word.rid >= layout.rsize {
+                return Err(invalid("connection id out of matrix"));
+            }
+            let begin = (morph.words.offset + idx * size_of::<Word<MappedStr>>())
+                .checked_add(word.info.offset)
+                .filter(|begin| *begin >= infos.offset);
+            let end = begin.and_then(|begin| begin.checked_add(word.info.len));
+            match (begin, end) {
+                (Some(begin), Some(end)) if end <= infos.end(1) => {
+                    if std::str::from_utf8(&morph.map[begin..end]).is_err() {
+                        return Err(invalid("info is not UTF-8"));
+                    }
+                }
+                _ => return Err(invalid("info out of range")),
+            }
+        }
+        morph.known = known;
+        Ok(morph)
+    }
+
+    fn slice<E>(&self, section: Section) -> &[E] {
+        // sections are aligned and in the mapping
+        unsafe {
+            std::slice::from_raw_parts(
+                self.map.as_ptr().add(section.offset) as *const E,
+                section.len,
+            )
+        }
+    }
+
+    pub fn parse(&self, input: &[u8]) -> Option<Vec<&str>> {
+        self.tokenize(input).map(|tokens| {
+            tokens
+                .iter()
+                .map(|token| token.word.info.as_str())
+                .collect()
+        })
+    }
+}
+
+impl Lexicon for MappedMorph {
+    type Info = MappedStr;
+
+    fn trie(&self) -> TrieView<'_, Word<MappedStr>> {
+        TrieView::new(
+            self.slice(self.nodes),
+            self.slice(self.words),
+            self.slice(self.ranges),
+            self.slice(self.tail),
+            self.slice(self.tails),
+            self.slice(self.codes),
+        )
+    }
+
+    fn connection(&self, lid: usize, rid: usize) -> i32 {
+        self.slice::<i32>(self.matrix)[lid * self.rsize + rid]
+    }
+
+    fn unknown(&self) -> UnknownView<'_, MappedStr> {
+        let words: &[Word<MappedStr>] = self.slice(self.words);
+        UnknownView::new(
+            self.slice(self.chars),
+            self.slice(self.categories),
+            &words[self.known..],
+        )
+    }
+
+    fn dump_matrix<W: Write>(&self, target: &mut W) -> Result<(), io::Error> {
+        let mut stream = io::BufWriter::new(target);
+        matrix::dump(&mut stream, self.slice(self.matrix), self.rsize)?;
+        stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod test_mapped {
+    use super::*;
+    use crate::parser::BuildOptions;
+    use crate::testutil;
+
+    fn morph() -> Morph<String> {
+        morph_with(BuildOptions::default())
+    }
+
+    fn morph_with(options: BuildOptions) -> Morph<String> {
+        let dict_src = "東,8,7,6245,名詞,一般\n\
+                        京,1,1,10791,名詞,一般\n\
+                        京都,2,1,2135,名詞,固有名詞\n\
+                        東京,1,1,3003,名詞,固有名詞\n\
+                        東京,1,1,4000,名詞,一般\n\
+                        都,3,2,9428,名詞,接尾\n";
+        let matrix_src = "9 8\n0 7 -283\n0 1 -310\n8 1 -368\n1 2 -9617\n1 3 -1303\n3 0 -409";
+        testutil::morph_with(matrix_src, dict_src, options)
+    }
+
+    fn unknown() -> crate::unknown::Unknown<String> {
+        crate::unknown::Unknown::from_text(
+            &mut "DEFAULT 0 1 0\nKANJI 0 0 2\n0x4E00..0x9FA5 KANJI\n".as_bytes(),
+            &mut "DEFAULT,8,7,3274,記号\nKANJI,8,7,9361,名詞,一般\n".as_bytes(),
+            testutil::classify,
+        )
+        .unwrap()
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("namaco-test-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_open() {
+        check_open(morph());
+        // labels of bytes are stored with the trie
+        check_open(morph_with(BuildOptions {
+            threads: 2,
+            alphabet: true,
+        }));
+        // unknown words follow words of the trie
+        let morph = morph().with_unknown(unknown()).unwrap();
+        assert_eq!(
+            spans(morph.tokenize("西東京".as_bytes())),
+            Some(vec![(0, 3, true), (3, 9, false)])
+        );
+        check_open(morph);
+    }
+
+    fn spans<T>(tokens: Option<Vec<crate::Token<T>>>) -> Option<Vec<(usize, usize, bool)>> {
+        tokens.map(|tokens| {
+            tokens
+                .iter()
+                .map(|token| (token.begin, token.end, token.unknown))
+                .collect()
+        })
+    }
+
+    fn check_open(morph: Morph<String>) {
+        let path = temp_path("mapped");
+        export(&morph, &mut fs::File::create(&path).unwrap()).unwrap();
+        assert!(is_mapped(&path).unwrap());
+        let mapped = MappedMorph::open(&path).unwrap();
+
+        let input = "東京都".as_bytes();
+        assert_eq!(
+            mapped.parse(input),
+            Some(vec!["名詞,固有名詞", "名詞,接尾"])
+        );
+        let expected = morph.nbest(input, 10);
+        let paths = mapped.nbest(input, 10);
+        assert_eq!(paths.len(), expected.len());
+        for ((cost, tokens), (expected_cost, expected_tokens)) in paths.iter().zip(&expected) {
+            assert_eq!(cost, expected_cost);
+            for (token, expected) in tokens.iter().zip(expected_tokens) {
+                assert_eq!((token.begin, token.end), (expected.begin, expected.end));
+                assert_eq!(token.word.info.as_str(), expected.word.info);
+                assert_eq!(
+                    (token.word.cost, token.word.lid, token.word.rid),
+                    (expected.word.cost, expected.word.lid, expected.word.rid)
+                );
+            }
+        }
+        assert_eq!(mapped.lattice(input).len(), morph.lattice(input).len());
+        let input = "西東京".as_bytes();
+        assert_eq!(spans(mapped.tokenize(input)), spans(morph.tokenize(input)));
+
+        // commands other than analyses read the trie through Lexicon too
+        assert_eq!(mapped.verify(), Ok(()));
+        assert_eq!(
+            mapped.stats().to_string().lines().next(),
+            morph.stats().to_string().lines().next()
+        );
+        let (mut dumped, mut expected) = (Vec::new(), Vec::new());
+        mapped.dump_dict(&mut dumped).unwrap();
+        morph.dump_dict(&mut expected).unwrap();
+        assert_eq!(dumped, expected);
+        let pattern = crate::pattern::Pattern::glob("東*").unwrap();
+        let (mut found, mut expected) = (Vec::new(), Vec::new());
+        mapped.lookup(&pattern, &mut found).unwrap();
+        morph.lookup(&pattern, &mut expected).unwrap();
+        assert_eq!(found, expected);
+        let (mut dumped, mut expected) = (Vec::new(), Vec::new());
+        mapped.dump_matrix(&mut dumped).unwrap();
+        morph.dump_matrix(&mut expected).unwrap();
+        assert_eq!(dumped, expected);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_broken() {
+        let mut buf = Vec::new();
+        export(&morph(), &mut buf).unwrap();
+        let path = temp_path("mapped-broken");
+
+        fs::write(&path, &buf[..buf.len() - 8]).unwrap();
+        let err = MappedMorph::open(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // info of the first word points outside of infos
+        let mut broken = buf.clone();
+        let nodes = u64::from_ne_bytes(buf[24..32].try_into().unwrap()) as usize;
+        let ranges = u64::from_ne_bytes(buf[32..40].try_into().unwrap()) as usize;
+        let ranges_offset = align(HEADER_LEN + nodes * size_of::<Node>()).unwrap();
+        let first_word = align(ranges_offset + ranges * size_of::<u32>()).unwrap();
+        broken[first_word..first_word + 8].copy_from_slice(&u64::MAX.to_ne_bytes());
+        fs::write(&path, &broken).unwrap();
+        let err = MappedMorph::open(&path).err().unwrap();
+        assert_eq!(err.to_string(), "info out of range");
+
+        // base of root points outside of nodes
+        let mut broken = buf.clone();
+        broken[HEADER_LEN..HEADER_LEN + 4].copy_from_slice(&(u32::MAX - 1).to_ne_bytes());
+        fs::write(&path, &broken).unwrap();
+        let err = MappedMorph::open(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "node out of range");
+
+        let mut buf = Vec::new();
+        export(
+            &morph_with(BuildOptions {
+                threads: 1,
+                alphabet: true,
+            }),
+            &mut buf,
+        )
+        .unwrap();
+        let field =
+            |idx: usize| u64::from_ne_bytes(buf[24 + idx * 8..32 + idx * 8].try_into().unwrap());
+        let layout = Layout {
+            nodes: field(0) as usize,
+            ranges: field(1) as usize,
+            words: field(2) as usize,
+            lsize: field(3) as usize,
+            rsize: field(4) as usize,
+            infos: field(5) as usize,
+            codes: field(6) as usize,
+            tails: field(7) as usize,
+            tail: field(8) as usize,
+            chars: field(9) as usize,
+            categories: field(10) as usize,
+        };
+        let (sections, _) = layout.sections().unwrap();
+
+        // codes are not a permutation now
+        let mut broken = buf.clone();
+        let last = sections[5].end(1) - 1;
+        broken[last] = broken[last - 1];
+        fs::write(&path, &broken).unwrap();
+        let err = MappedMorph::open(&path).err().unwrap();
+        assert_eq!(err.to_string(), "broken codes");
+
+        // the tail of the last key is out of tail
+        let mut broken = buf.clone();
+        let last = sections[6].end(size_of::<u32>()) - size_of::<u32>();
+        broken[last..last + 4].copy_from_slice(&(layout.tail as u32 + 1).to_ne_bytes());
+        fs::write(&path, &broken).unwrap();
+        let err = MappedMorph::open(&path).err().unwrap();
+        assert_eq!(err.to_string(), "broken tails");
+
+        // the last category refers to words after unknown words
+        let mut buf = Vec::new();
+        export(&morph().with_unknown(unknown()).unwrap(), &mut buf).unwrap();
+        let categories = u64::from_ne_bytes(buf[104..112].try_into().unwrap()) as usize;
+        let bytes = categories * size_of::<Category>();
+        let last = buf.len() - (align(bytes).unwrap() - bytes) - 4;
+        let mut broken = buf.clone();
+        broken[last..last + 4].copy_from_slice(&u32::MAX.to_ne_bytes());
+        fs::write(&path, &broken).unwrap();
+        let err = MappedMorph::open(&path).err().unwrap();
+        assert_eq!(err.to_string(), "broken unknown words");
+
+        // a file of another format version
+        let mut old = buf.clone();
+        old[8..16].copy_from_slice(&0u64.to_le_bytes());
+        fs::write(&path, &old).unwrap();
+        let err = MappedMorph::open(&path).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "mapped dictionary format version 0 is not supported (expected 1). compile it again"
+        );
+
+        fs::write(&path, b"NAMACO").unwrap();
+        assert!(!is_mapped(&path).unwrap());
+        assert!(MappedMorph::open(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -19,7 +19,7 @@ pub struct Matrix {
 }
 
 use std::io;
-use std::io::{BufRead, Read};
+use std::io::{BufRead, Read, Write};
 
 impl Matrix {
     pub fn new<R: Read>(file: &mut R) -> Result<Matrix, MatrixLoadError> {
@@ -81,6 +81,32 @@ impl Matrix {
     pub fn at(&self, lid: usize, rid: usize) -> i32 {
         self.internal[lid * self.rsize + rid]
     }
+
+    pub(crate) fn contains(&self, lid: usize, rid: usize) -> bool {
+        rid < self.rsize && lid < self.internal.len() / self.rsize
+    }
+
+    // matrix.def. pairs never given to new are omitted.
+    pub fn dump<W: Write>(&self, target: &mut W) -> Result<(), io::Error> {
+        dump(target, &self.internal, self.rsize)
+    }
+
+    // costs in row-major order and the number of columns
+    pub(crate) fn raw_parts(&self) -> (&[i32], usize) {
+        (&self.internal, self.rsize)
+    }
+}
+
+// matrix.def of costs in row-major order with rsize columns, e.g. of a mapped dictionary
+pub(crate) fn dump<W: Write>(target: &mut W, costs: &[i32], rsize: usize) -> Result<(), io::Error> {
+    let lsize = costs.len().checked_div(rsize).unwrap_or(0);
+    writeln!(target, "{} {}", lsize, rsize)?;
+    for (idx, cost) in costs.iter().enumerate() {
+        if *cost != i32::MAX {
+            writeln!(target, "{} {} {}", idx / rsize, idx % rsize, cost)?;
+        }
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -102,4 +128,16 @@ mod test_matrix {
         assert_eq!(matrix.at(0, 1), 121);
         assert_eq!(matrix.at(2, 1), -54);
     }
+
+    #[test]
+    fn test_dump() {
+        let src = "2 3\n0 0 100\n1 2 -41\n0 1 121\n";
+        let matrix = Matrix::new(&mut io::Cursor::new(src)).unwrap();
+        let mut dumped = Vec::new();
+        matrix.dump(&mut dumped).unwrap();
+        assert_eq!(
+            String::from_utf8(dumped).unwrap(),
+            "2 3\n0 0 100\n0 1 121\n1 2 -41\n"
+        );
+    }
 }
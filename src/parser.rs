@@ -1,5 +1,6 @@
 use serde::Serialize;
 use serde_derive::{Deserialize, Serialize};
+use std::borrow::Cow;
 
 pub struct DictCfg {
     pub surface: usize,
@@ -8,7 +9,9 @@ pub struct DictCfg {
     pub rid: usize,
 }
 
+// repr(C) to be read from mapped dictionaries in place
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[repr(C)]
 pub struct Word<T> {
     pub info: T,
     pub cost: i64,
@@ -16,7 +19,9 @@ pub struct Word<T> {
     pub rid: usize,
 }
 
-fn split_by_comma(line: &str) -> Vec<&str> {
+// Fields of a line of MeCab CSV. A field in double quotes may contain commas, and a double
+// quote in it is doubled. A single character in double quotes such as """ is taken as is.
+pub(crate) fn split_by_comma(line: &str) -> Vec<Cow<'_, str>> {
     let mut buf = Vec::new();
     const COMMA: u8 = 0x2c;
     const DQUOTE: u8 = 0x22;
@@ -26,32 +31,63 @@ fn split_by_comma(line: &str) -> Vec<&str> {
     let mut begin = 0usize;
 
     while i < bytes.len() {
-        if bytes[i] == DQUOTE
-            && i + 3 < bytes.len()
-            && bytes[i + 2] == DQUOTE
-            && bytes[i + 3] == COMMA
-        {
-            unsafe {
-                buf.push(line.get_unchecked(i + 1..i + 2));
-            }
-            i += 4;
-            begin = i;
-        } else if bytes[i] == COMMA {
-            unsafe {
-                buf.push(line.get_unchecked(begin..i));
+        if i == begin && bytes[i] == DQUOTE {
+            if let Some((field, close)) = quoted(line, i) {
+                buf.push(field);
+                if bytes.get(close + 1) != Some(&COMMA) {
+                    // only a line break follows
+                    return buf;
+                }
+                i = close + 2;
+                begin = i;
+                continue;
             }
+        }
+        if bytes[i] == COMMA {
+            buf.push(Cow::Borrowed(&line[begin..i]));
             i += 1;
             begin = i;
         } else {
             i += 1;
         }
     }
-    unsafe {
-        buf.push(line.get_unchecked(begin..));
-    }
+    buf.push(Cow::Borrowed(&line[begin..]));
 
     buf
 }
+
+// whether a double quote at i closes a field, which is followed by a comma or the end of line
+fn closes(bytes: &[u8], i: usize) -> bool {
+    matches!(
+        bytes.get(i + 1),
+        None | Some(b',') | Some(b'\r') | Some(b'\n')
+    )
+}
+
+// The field in double quotes from begin and the index of its closing quote.
+// None if the quotes do not close, in which case they are a part of the field.
+fn quoted(line: &str, begin: usize) -> Option<(Cow<'_, str>, usize)> {
+    let bytes = line.as_bytes();
+    if bytes.get(begin + 2) == Some(&b'"')
+        && bytes[begin + 1].is_ascii()
+        && closes(bytes, begin + 2)
+    {
+        return Some((Cow::Borrowed(&line[begin + 1..begin + 2]), begin + 2));
+    }
+    let mut field = String::new();
+    let mut chars = line[begin + 1..].char_indices().peekable();
+    while let Some((offset, ch)) = chars.next() {
+        if ch == '"' {
+            if let Some((_, '"')) = chars.peek() {
+                chars.next();
+            } else if closes(bytes, begin + 1 + offset) {
+                return Some((Cow::Owned(field), begin + 1 + offset));
+            }
+        }
+        field.push(ch);
+    }
+    None
+}
 #[cfg(test)]
 mod test_split_by_comma {
     use super::*;
@@ -60,78 +96,173 @@ mod test_split_by_comma {
         assert_eq!(split_by_comma("a,b,c"), vec!["a", "b", "c"]);
         assert_eq!(split_by_comma(",a,b,,c,"), vec!["", "a", "b", "", "c", ""]);
         assert_eq!(split_by_comma("\"\"\",\",\",a"), vec!["\"", ",", "a"]);
+        assert_eq!(
+            split_by_comma("\"1,\"\"2\"\"\",\"\"\"\",a"),
+            vec!["1,\"2\"", "\"", "a"]
+        );
+        assert_eq!(split_by_comma("a,\"b,c\"\n"), vec!["a", "b,c"]);
+        // quotes which do not close are not quoting
+        assert_eq!(split_by_comma("\"a,b"), vec!["\"a", "b"]);
+        assert_eq!(split_by_comma("a,\",\""), vec!["a", ","]);
     }
 }
 
+use super::sort::{ExternalSorter, Sorted};
 use super::trie;
-use core::fmt::Debug;
+use serde::de::DeserializeOwned;
 use std::io;
 use std::io::{BufRead, Read};
 
-pub fn build_trie<R: Read, F, T: Serialize + Clone + Debug>(
+// number of words sorted in memory at once while building a trie
+const SORT_CHUNK_LEN: usize = 1 << 16;
+
+// Words are sorted by an external sort and streamed into trie::Builder,
+// so that the whole dictionary is not held in memory besides the trie.
+pub fn build_trie<R: Read, F, T: Serialize + DeserializeOwned>(
     readable: R,
     classifier: F,
 ) -> Result<trie::Trie<Word<T>>, io::Error>
+where
+    F: Fn(&[&str]) -> (Vec<u8>, Word<T>),
+{
+    // homographs share a surface
+    let mut builder = trie::Builder::new().duplicates(trie::Duplicates::Append);
+    let (words, _) = sort_words(readable, classifier)?;
+    for pair in words {
+        let (key, word) = pair?;
+        builder.push(&key, word).map_err(to_io_error)?;
+    }
+    Ok(builder.build())
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BuildOptions {
+    // threads building partitions of the trie. the trie does not depend on it.
+    pub(crate) threads: usize,
+    // remap bytes of surfaces by frequency, which makes the trie denser
+    pub(crate) alphabet: bool,
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        BuildOptions {
+            threads: 1,
+            alphabet: false,
+        }
+    }
+}
+
+impl BuildOptions {
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    pub fn alphabet(mut self, alphabet: bool) -> Self {
+        self.alphabet = alphabet;
+        self
+    }
+}
+
+// build_trie with options
+pub fn build_trie_with<R: Read, F, T: Serialize + DeserializeOwned + Send>(
+    readable: R,
+    classifier: F,
+    options: BuildOptions,
+) -> Result<trie::Trie<Word<T>>, io::Error>
+where
+    F: Fn(&[&str]) -> (Vec<u8>, Word<T>),
+{
+    let (words, counts) = sort_words(readable, classifier)?;
+    let mut builder = trie::Builder::new().duplicates(trie::Duplicates::Append);
+    if options.alphabet {
+        builder = builder.alphabet(trie::Alphabet::by_frequency(&counts));
+    }
+    let mut failure = None;
+    let words = words.map_while(|pair| match pair {
+        Ok(pair) => Some(pair),
+        Err(err) => {
+            failure = Some(err);
+            None
+        }
+    });
+    let built = builder.build_parallel(words, options.threads);
+    // a read error cuts the words short, which is the cause of any build error
+    if let Some(err) = failure {
+        return Err(err);
+    }
+    built.map_err(to_io_error)
+}
+
+// sorted words and the number of each byte in surfaces
+fn sort_words<R: Read, F, T: Serialize + DeserializeOwned>(
+    readable: R,
+    classifier: F,
+) -> Result<(Sorted<Word<T>>, [usize; 256]), io::Error>
 where
     F: Fn(&[&str]) -> (Vec<u8>, Word<T>),
 {
     let mut reader = io::BufReader::new(readable);
     let mut buf = String::new();
-    let mut dict = Vec::new();
+    let mut sorter = ExternalSorter::new(SORT_CHUNK_LEN);
+    let mut counts = [0; 256];
     while reader.read_line(&mut buf)? > 0 {
-        let arr: Vec<&str> = split_by_comma(&buf);
-        dict.push(classifier(&arr));
+        let fields = split_by_comma(&buf);
+        let arr: Vec<&str> = fields.iter().map(|field| field.as_ref()).collect();
+        let (key, word) = classifier(&arr);
+        for octet in &key {
+            counts[*octet as usize] += 1;
+        }
+        sorter.push(key, word)?;
         buf.clear();
     }
-    Ok(trie::Trie::static_construction(
-        &mut dict.iter().map(|x| (&x.0[..], x.1.clone())).collect(),
-    ))
+    Ok((sorter.finish()?, counts))
+}
+
+fn to_io_error(err: trie::BuildError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
 }
 
 #[cfg(test)]
 mod test_parser {
     use super::*;
+    use crate::testutil;
     #[test]
     fn test_parser() {
         let csv = "蟹,0,10,100,カニ\n土,1,20,200,ツチ\n味,2,30,300,アジ";
-        let result: trie::Trie<Word<String>> = build_trie(csv.as_bytes(), |arr| {
-            (
-                arr[0].as_bytes().to_vec(),
-                Word {
-                    info: String::from(arr[4].trim()),
-                    lid: arr[1].parse().unwrap(),
-                    rid: arr[2].parse().unwrap(),
-                    cost: arr[3].parse().unwrap(),
-                },
-            )
-        })
-        .unwrap();
+        let result = build_trie(csv.as_bytes(), testutil::classify).unwrap();
         assert_eq!(
-            result.find("蟹".as_bytes()),
-            Ok(&[Word {
-                lid: 0,
-                rid: 10,
-                cost: 100,
-                info: String::from("カニ"),
-            }][..])
+            result.get("蟹".as_bytes()),
+            Some(
+                &[Word {
+                    lid: 0,
+                    rid: 10,
+                    cost: 100,
+                    info: String::from("カニ"),
+                }][..]
+            )
         );
         assert_eq!(
-            result.find("土".as_bytes()),
-            Ok(&[Word {
-                lid: 1,
-                rid: 20,
-                cost: 200,
-                info: String::from("ツチ"),
-            }][..])
+            result.get("土".as_bytes()),
+            Some(
+                &[Word {
+                    lid: 1,
+                    rid: 20,
+                    cost: 200,
+                    info: String::from("ツチ"),
+                }][..]
+            )
         );
         assert_eq!(
-            result.find("味".as_bytes()),
-            Ok(&[Word {
-                lid: 2,
-                rid: 30,
-                cost: 300,
-                info: String::from("アジ"),
-            }][..])
+            result.get("味".as_bytes()),
+            Some(
+                &[Word {
+                    lid: 2,
+                    rid: 30,
+                    cost: 300,
+                    info: String::from("アジ"),
+                }][..]
+            )
         );
     }
 }
@@ -0,0 +1,565 @@
+use super::trie::Automaton;
+
+// Limit of states, which repetition counts multiply
+const MAX_STATES: usize = 1 << 16;
+
+#[derive(Fail, Debug, PartialEq)]
+pub enum PatternError {
+    #[fail(display = "unexpected {:?} at {} of \"{}\"", ch, pos, pattern)]
+    Unexpected {
+        ch: char,
+        pos: usize,
+        pattern: String,
+    },
+    #[fail(display = "unexpected end of \"{}\"", pattern)]
+    UnexpectedEnd { pattern: String },
+    #[fail(display = "\"{}\" has too many states", pattern)]
+    TooLarge { pattern: String },
+}
+
+// Characters one step of a pattern consumes
+#[derive(Debug, Clone, PartialEq)]
+enum Class {
+    Any,
+    // inclusive ranges, or characters out of them if negated
+    Ranges(bool, Vec<(char, char)>),
+}
+
+impl Class {
+    fn literal(ch: char) -> Self {
+        Class::Ranges(false, vec![(ch, ch)])
+    }
+
+    fn contains(&self, ch: char) -> bool {
+        match self {
+            Class::Any => true,
+            Class::Ranges(negated, ranges) => {
+                ranges.iter().any(|&(lo, hi)| lo <= ch && ch <= hi) != *negated
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Ast {
+    Empty,
+    Class(Class),
+    Concat(Vec<Ast>),
+    Alternate(Vec<Ast>),
+    // at least min times and at most max times, or unboundedly if None
+    Repeat(Box<Ast>, usize, Option<usize>),
+}
+
+#[derive(Debug, Clone)]
+enum State {
+    // a character in class, then the state
+    Step(Class, usize),
+    // either of states without a character
+    Split(usize, usize),
+    Match,
+}
+
+// Pattern over whole surfaces, simulated as a nondeterministic automaton over characters.
+// Broken bytes of a surface match nothing.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    states: Vec<State>,
+    start: usize,
+}
+
+impl Pattern {
+    // ? is any character and * is any characters. \ makes the next character literal.
+    pub fn glob(src: &str) -> Result<Self, PatternError> {
+        let mut parser = Parser::new(src);
+        let mut items = Vec::new();
+        while let Some(ch) = parser.next() {
+            items.push(match ch {
+                '?' => Ast::Class(Class::Any),
+                '*' => Ast::Repeat(Box::new(Ast::Class(Class::Any)), 0, None),
+                '\\' => Ast::Class(Class::literal(parser.expect()?)),
+                ch => Ast::Class(Class::literal(ch)),
+            });
+        }
+        Pattern::compile(src, &Ast::Concat(items))
+    }
+
+    // Regular expression of literals, ., [..], [^..], (..), |, *, +, ?, {m}, {m,} and {m,n}.
+    // \ makes the next character literal. It matches whole surfaces, so ^ at the beginning and
+    // $ at the end are allowed and do nothing.
+    pub fn regex(src: &str) -> Result<Self, PatternError> {
+        let mut parser = Parser::new(src);
+        parser.eat('^');
+        let ast = parser.alternate()?;
+        parser.eat('$');
+        if let Some(ch) = parser.peek() {
+            return Err(parser.unexpected(ch));
+        }
+        Pattern::compile(src, &ast)
+    }
+
+    fn compile(src: &str, ast: &Ast) -> Result<Self, PatternError> {
+        let mut pattern = Pattern {
+            states: vec![State::Match],
+            start: 0,
+        };
+        pattern.start = pattern
+            .build(ast, 0)
+            .ok_or_else(|| PatternError::TooLarge {
+                pattern: src.to_owned(),
+            })?;
+        Ok(pattern)
+    }
+
+    fn add(&mut self, state: State) -> Option<usize> {
+        if self.states.len() >= MAX_STATES {
+            return None;
+        }
+        self.states.push(state);
+        Some(self.states.len() - 1)
+    }
+
+    // states of ast followed by next, built backwards. returns the entry of them.
+    fn build(&mut self, ast: &Ast, next: usize) -> Option<usize> {
+        match ast {
+            Ast::Empty => Some(next),
+            Ast::Class(class) => self.add(State::Step(class.clone(), next)),
+            Ast::Concat(items) => items
+                .iter()
+                .rev()
+                .try_fold(next, |next, item| self.build(item, next)),
+            Ast::Alternate(items) => {
+                let (last, rest) = items.split_last().unwrap();
+                let mut entry = self.build(last, next)?;
+                for item in rest.iter().rev() {
+                    let item = self.build(item, next)?;
+                    entry = self.add(State::Split(item, entry))?;
+                }
+                Some(entry)
+            }
+            Ast::Repeat(ast, min, max) => {
+                let mut entry = match max {
+                    None => {
+                        // the body returns to the split, which is patched after building it
+                        let split = self.add(State::Split(next, next))?;
+                        let body = self.build(ast, split)?;
+                        self.states[split] = State::Split(body, next);
+                        split
+                    }
+                    Some(max) => {
+                        let mut entry = next;
+                        for _ in *min..*max {
+                            let body = self.build(ast, entry)?;
+                            entry = self.add(State::Split(body, next))?;
+                        }
+                        entry
+                    }
+                };
+                for _ in 0..*min {
+                    entry = self.build(ast, entry)?;
+                }
+                Some(entry)
+            }
+        }
+    }
+
+    // states reachable from states without a character, sorted
+    fn closure<I: Iterator<Item = usize>>(&self, states: I) -> Vec<usize> {
+        let mut seen = vec![false; self.states.len()];
+        let mut stack: Vec<usize> = states.collect();
+        let mut closure = Vec::new();
+        while let Some(idx) = stack.pop() {
+            if seen[idx] {
+                continue;
+            }
+            seen[idx] = true;
+            match self.states[idx] {
+                State::Split(first, second) => {
+                    stack.push(second);
+                    stack.push(first);
+                }
+                _ => closure.push(idx),
+            }
+        }
+        closure.sort();
+        closure
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        let state = text
+            .chars()
+            .fold(self.start(), |state, ch| self.step(&state, Some(ch)));
+        Automaton::is_match(self, &state)
+    }
+}
+
+impl Automaton for Pattern {
+    // states of the automaton simulated at once
+    type State = Vec<usize>;
+
+    fn start(&self) -> Vec<usize> {
+        self.closure(std::iter::once(self.start))
+    }
+
+    fn step(&self, states: &Vec<usize>, ch: Option<char>) -> Vec<usize> {
+        let ch = match ch {
+            Some(ch) => ch,
+            None => return Vec::new(),
+        };
+        self.closure(states.iter().filter_map(|&idx| match &self.states[idx] {
+            State::Step(class, next) if class.contains(ch) => Some(*next),
+            _ => None,
+        }))
+    }
+
+    fn is_match(&self, states: &Vec<usize>) -> bool {
+        states
+            .iter()
+            .any(|&idx| matches!(self.states[idx], State::Match))
+    }
+
+    fn can_match(&self, states: &Vec<usize>) -> bool {
+        !states.is_empty()
+    }
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(src: &'a str) -> Self {
+        Parser {
+            src,
+            chars: src.chars().collect(),
+            pos: 0,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let ch = self.peek();
+        if ch.is_some() {
+            self.pos += 1;
+        }
+        ch
+    }
+
+    fn eat(&mut self, ch: char) -> bool {
+        if self.peek() == Some(ch) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self) -> Result<char, PatternError> {
+        self.next().ok_or_else(|| PatternError::UnexpectedEnd {
+            pattern: self.src.to_owned(),
+        })
+    }
+
+    // error at the character just peeked
+    fn unexpected(&self, ch: char) -> PatternError {
+        PatternError::Unexpected {
+            ch,
+            pos: self.pos,
+            pattern: self.src.to_owned(),
+        }
+    }
+
+    fn alternate(&mut self) -> Result<Ast, PatternError> {
+        let mut items = vec![self.concat()?];
+        while self.eat('|') {
+            items.push(self.concat()?);
+        }
+        Ok(if items.len() == 1 {
+            items.pop().unwrap()
+        } else {
+            Ast::Alternate(items)
+        })
+    }
+
+    fn concat(&mut self) -> Result<Ast, PatternError> {
+        let mut items = Vec::new();
+        while let Some(ch) = self.peek() {
+            if ch == '|' || ch == ')' || (ch == '$' && self.pos + 1 == self.chars.len()) {
+                break;
+            }
+            items.push(self.repeat()?);
+        }
+        Ok(match items.len() {
+            0 => Ast::Empty,
+            1 => items.pop().unwrap(),
+            _ => Ast::Concat(items),
+        })
+    }
+
+    fn repeat(&mut self) -> Result<Ast, PatternError> {
+        let mut ast = self.atom()?;
+        loop {
+            let ch = match self.peek() {
+                Some(ch @ '*') | Some(ch @ '+') | Some(ch @ '?') | Some(ch @ '{') => ch,
+                _ => return Ok(ast),
+            };
+            self.pos += 1;
+            let (min, max) = match ch {
+                '*' => (0, None),
+                '+' => (1, None),
+                '?' => (0, Some(1)),
+                _ => self.counts()?,
+            };
+            ast = Ast::Repeat(Box::new(ast), min, max);
+        }
+    }
+
+    // {m}, {m,} or {m,n} after {
+    fn counts(&mut self) -> Result<(usize, Option<usize>), PatternError> {
+        let min = self.number()?;
+        let max = if self.eat(',') {
+            if self.peek() == Some('}') {
+                None
+            } else {
+                Some(self.number()?)
+            }
+        } else {
+            Some(min)
+        };
+        match self.expect()? {
+            '}' => (),
+            ch => {
+                self.pos -= 1;
+                return Err(self.unexpected(ch));
+            }
+        }
+        if max.is_some_and(|max| max < min) {
+            self.pos -= 1;
+            return Err(self.unexpected('}'));
+        }
+        Ok((min, max))
+    }
+
+    fn number(&mut self) -> Result<usize, PatternError> {
+        let begin = self.pos;
+        while self.peek().is_some_and(|ch| ch.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let digits: String = self.chars[begin..self.pos].iter().collect();
+        match digits.parse() {
+            Ok(n) if n < MAX_STATES => Ok(n),
+            Ok(_) => Err(PatternError::TooLarge {
+                pattern: self.src.to_owned(),
+            }),
+            Err(_) => match self.peek() {
+                Some(ch) => Err(self.unexpected(ch)),
+                None => Err(PatternError::UnexpectedEnd {
+                    pattern: self.src.to_owned(),
+                }),
+            },
+        }
+    }
+
+    fn atom(&mut self) -> Result<Ast, PatternError> {
+        let ch = self.expect()?;
+        Ok(match ch {
+            '(' => {
+                let ast = self.alternate()?;
+                if !self.eat(')') {
+                    return Err(match self.peek() {
+                        Some(ch) => self.unexpected(ch),
+                        None => PatternError::UnexpectedEnd {
+                            pattern: self.src.to_owned(),
+                        },
+                    });
+                }
+                ast
+            }
+            '.' => Ast::Class(Class::Any),
+            '[' => Ast::Class(self.class()?),
+            '\\' => Ast::Class(Class::literal(self.expect()?)),
+            '*' | '+' | '?' | '{' | ')' | '|' => {
+                self.pos -= 1;
+                return Err(self.unexpected(ch));
+            }
+            ch => Ast::Class(Class::literal(ch)),
+        })
+    }
+
+    // [..] or [^..] after [, where - between characters is a range
+    fn class(&mut self) -> Result<Class, PatternError> {
+        let negated = self.eat('^');
+        let mut ranges = Vec::new();
+        loop {
+            let lo = match self.expect()? {
+                ']' if !ranges.is_empty() => return Ok(Class::Ranges(negated, ranges)),
+                '\\' => self.expect()?,
+                ch => ch,
+            };
+            let hi = if self.peek() == Some('-') && self.chars.get(self.pos + 1) != Some(&']') {
+                self.pos += 1;
+                let hi = match self.expect()? {
+                    '\\' => self.expect()?,
+                    ch => ch,
+                };
+                if hi < lo {
+                    self.pos -= 1;
+                    return Err(self.unexpected(hi));
+                }
+                hi
+            } else {
+                lo
+            };
+            ranges.push((lo, hi));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_pattern {
+    use super::*;
+    use crate::trie::Trie;
+
+    #[test]
+    fn test_glob() {
+        let pattern = Pattern::glob("?化").unwrap();
+        assert!(pattern.is_match("変化"));
+        assert!(!pattern.is_match("化"));
+        assert!(!pattern.is_match("自動化"));
+        let pattern = Pattern::glob("*化").unwrap();
+        assert!(pattern.is_match("化"));
+        assert!(pattern.is_match("自動化"));
+        assert!(!pattern.is_match("化学"));
+        let pattern = Pattern::glob("\\*?").unwrap();
+        assert!(pattern.is_match("*a"));
+        assert!(!pattern.is_match("ba"));
+        assert_eq!(
+            Pattern::glob("a\\").unwrap_err(),
+            PatternError::UnexpectedEnd {
+                pattern: "a\\".to_owned()
+            }
+        );
+    }
+
+    #[test]
+    fn test_regex() {
+        let cases = [
+            ("東(京|北)", vec!["東京", "東北"], vec!["東", "東京都"]),
+            (
+                "^[あ-ん]+$",
+                vec!["ひらがな"],
+                vec!["", "カタカナ", "ひら仮名"],
+            ),
+            ("[^a-z]?b", vec!["b", "Bb"], vec!["ab", "bb"]),
+            ("a.{2}", vec!["abc", "aあい"], vec!["ab", "abcd"]),
+            ("a{2,}", vec!["aa", "aaaa"], vec!["a"]),
+            ("(ab){1,2}c*", vec!["ab", "ababcc"], vec!["abababc", "c"]),
+            ("a|", vec!["a", ""], vec!["aa"]),
+            ("[-a\\]]", vec!["-", "]"], vec!["\\", "b"]),
+            ("\\.\\*", vec![".*"], vec!["a*"]),
+        ];
+        for (src, matches, others) in cases.iter() {
+            let pattern = Pattern::regex(src).unwrap();
+            for text in matches {
+                assert!(pattern.is_match(text), "{} {}", src, text);
+            }
+            for text in others {
+                assert!(!pattern.is_match(text), "{} {}", src, text);
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid() {
+        let unexpected = |src: &str, ch: char, pos: usize| PatternError::Unexpected {
+            ch,
+            pos,
+            pattern: src.to_owned(),
+        };
+        assert_eq!(Pattern::regex("a)").unwrap_err(), unexpected("a)", ')', 1));
+        assert_eq!(Pattern::regex("*a").unwrap_err(), unexpected("*a", '*', 0));
+        assert_eq!(
+            Pattern::regex("a{2,1}").unwrap_err(),
+            unexpected("a{2,1}", '}', 5)
+        );
+        assert_eq!(
+            Pattern::regex("[b-a]").unwrap_err(),
+            unexpected("[b-a]", 'a', 3)
+        );
+        assert_eq!(
+            Pattern::regex("(a").unwrap_err(),
+            PatternError::UnexpectedEnd {
+                pattern: "(a".to_owned()
+            }
+        );
+        assert_eq!(
+            Pattern::regex("[]").unwrap_err(),
+            PatternError::UnexpectedEnd {
+                pattern: "[]".to_owned()
+            }
+        );
+        assert_eq!(
+            Pattern::regex("(a{100}){1000}").unwrap_err(),
+            PatternError::TooLarge {
+                pattern: "(a{100}){1000}".to_owned()
+            }
+        );
+    }
+
+    #[test]
+    fn test_search() {
+        let keys = [
+            "変化",
+            "化",
+            "化学",
+            "自動化",
+            "東京",
+            "東京都",
+            "京都",
+            "abc",
+            "ab",
+        ];
+        let trie: Trie<usize> =
+            Trie::try_from_iter(keys.iter().enumerate().map(|(i, key)| (key.as_bytes(), i)))
+                .unwrap();
+        let mut sorted = keys.to_vec();
+        sorted.sort();
+        for (src, regex) in [
+            ("?化", false),
+            ("*化", false),
+            ("*", false),
+            ("東京*", false),
+            ("京都|東京", true),
+            ("a?b+c?", true),
+            (".*[化都]", true),
+        ]
+        .iter()
+        {
+            let pattern = if *regex {
+                Pattern::regex(src)
+            } else {
+                Pattern::glob(src)
+            }
+            .unwrap();
+            let expected: Vec<&str> = sorted
+                .iter()
+                .cloned()
+                .filter(|key| pattern.is_match(key))
+                .collect();
+            let found: Vec<String> = trie
+                .search(&pattern)
+                .map(|(key, _, _)| String::from_utf8(key).unwrap())
+                .collect();
+            assert_eq!(found, expected, "{}", src);
+        }
+        let values: Vec<usize> = trie
+            .search(Pattern::glob("?化").unwrap())
+            .flat_map(|(_, _, values)| values.to_vec())
+            .collect();
+        assert_eq!(values, vec![0]);
+    }
+}
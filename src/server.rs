@@ -0,0 +1,385 @@
+use super::format::JsonToken;
+use super::handle::MorphHandle;
+use super::Lexicon;
+use serde_derive::{Deserialize, Serialize};
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Fail, Debug)]
+pub enum ServerError {
+    #[fail(display = "failed to listen on {}", addr)]
+    FailedToListen { addr: String },
+}
+
+pub struct Config {
+    // host:port
+    pub addr: String,
+    // requests with larger body are rejected with 413
+    pub max_body: usize,
+    pub threads: usize,
+}
+
+const MAX_NBEST: usize = 100;
+
+#[derive(Deserialize)]
+struct Request {
+    sentence: String,
+    // for /nbest
+    #[serde(default = "default_n")]
+    n: usize,
+}
+
+fn default_n() -> usize {
+    1
+}
+
+#[derive(Serialize)]
+struct TokenizeResponse<'a> {
+    sentence: &'a str,
+    // null if failed to parse
+    tokens: Option<Vec<JsonToken<'a>>>,
+}
+
+#[derive(Serialize)]
+struct Path<'a> {
+    // total cost including EOS
+    cost: i64,
+    tokens: Vec<JsonToken<'a>>,
+}
+
+#[derive(Serialize)]
+struct NbestResponse<'a> {
+    sentence: &'a str,
+    paths: Vec<Path<'a>>,
+}
+
+#[derive(Serialize)]
+struct LatticeNode<'a> {
+    id: usize,
+    #[serde(flatten)]
+    token: JsonToken<'a>,
+    // previous node on the best path to this node
+    prev: Option<usize>,
+    // on the best path of the sentence
+    best: bool,
+}
+
+#[derive(Serialize)]
+struct LatticeResponse<'a> {
+    sentence: &'a str,
+    nodes: Vec<LatticeNode<'a>>,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse<'a> {
+    error: &'a str,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub body: String,
+}
+
+impl Response {
+    fn ok<S: serde::Serialize>(body: &S) -> Self {
+        Response {
+            status: 200,
+            body: serde_json::to_string(body).unwrap(),
+        }
+    }
+
+    fn error(status: u16, error: &str) -> Self {
+        Response {
+            status,
+            body: serde_json::to_string(&ErrorResponse { error }).unwrap(),
+        }
+    }
+}
+
+// Route a request. Independent of sockets.
+pub fn handle<L>(morph: &L, method: &str, path: &str, body: &[u8]) -> Response
+where
+    L: Lexicon,
+    L::Info: AsRef<str>,
+{
+    match (method, path) {
+        ("GET", "/health") => Response::ok(&serde_json::json!({ "status": "ok" })),
+        ("POST", "/tokenize") | ("POST", "/nbest") | ("POST", "/lattice") => {
+            let request: Request = match serde_json::from_slice(body) {
+                Ok(request) => request,
+                Err(_) => return Response::error(400, "invalid request body"),
+            };
+            let input = request.sentence.trim();
+            match path {
+                "/tokenize" => {
+                    let tokens = morph.tokenize(input.as_bytes());
+                    Response::ok(&TokenizeResponse {
+                        sentence: input,
+                        tokens: tokens.map(|tokens| {
+                            tokens
+                                .iter()
+                                .map(|token| JsonToken::new(input, token))
+                                .collect()
+                        }),
+                    })
+                }
+                "/nbest" => {
+                    let n = std::cmp::min(request.n, MAX_NBEST);
+                    let paths = morph.nbest(input.as_bytes(), n);
+                    Response::ok(&NbestResponse {
+                        sentence: input,
+                        paths: paths
+                            .iter()
+                            .map(|(cost, tokens)| Path {
+                                cost: *cost,
+                                tokens: tokens
+                                    .iter()
+                                    .map(|token| JsonToken::new(input, token))
+                                    .collect(),
+                            })
+                            .collect(),
+                    })
+                }
+                _ => {
+                    let (lattice, last) = morph.lattice_with_best(input.as_bytes());
+                    let mut best = vec![false; lattice.len()];
+                    let mut here = last;
+                    while let Some(idx) = here {
+                        best[idx] = true;
+                        here = lattice[idx].prev;
+                    }
+                    let nodes = lattice
+                        .iter()
+                        .enumerate()
+                        .map(|(id, node)| {
+                            let token = super::Token {
+                                begin: node.begin,
+                                end: node.end,
+                                cost: node.cost,
+                                word: node.word,
+                                unknown: node.unknown,
+                            };
+                            LatticeNode {
+                                id,
+                                best: best[id],
+                                token: JsonToken::new(input, &token),
+                                prev: node.prev,
+                            }
+                        })
+                        .collect();
+                    Response::ok(&LatticeResponse {
+                        sentence: input,
+                        nodes,
+                    })
+                }
+            }
+        }
+        (_, "/health") | (_, "/tokenize") | (_, "/nbest") | (_, "/lattice") => {
+            Response::error(405, "method not allowed")
+        }
+        _ => Response::error(404, "not found"),
+    }
+}
+
+fn respond<L>(morph: &MorphHandle<L>, config: &Config, mut request: tiny_http::Request)
+where
+    L: Lexicon,
+    L::Info: AsRef<str>,
+{
+    let too_large = Response::error(413, "request body too large");
+    let response = match request.body_length() {
+        Some(len) if len > config.max_body => too_large,
+        _ => {
+            let mut body = Vec::new();
+            let limit = config.max_body as u64 + 1;
+            match request.as_reader().take(limit).read_to_end(&mut body) {
+                Ok(_) if body.len() > config.max_body => too_large,
+                Ok(_) => {
+                    // drop query string
+                    let path = request.url().split('?').next().unwrap_or("").to_owned();
+                    // snapshot so that a reload does not affect this request
+                    handle(&*morph.load(), request.method().as_str(), &path, &body)
+                }
+                Err(_) => Response::error(400, "failed to read request body"),
+            }
+        }
+    };
+    let header =
+        tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+    let _ = request.respond(
+        tiny_http::Response::from_string(response.body)
+            .with_status_code(response.status)
+            .with_header(header),
+    );
+}
+
+// Serve until shutdown becomes true. Requests in flight are completed before returning.
+// The dictionary in morph can be replaced while serving.
+pub fn serve<L>(
+    morph: &MorphHandle<L>,
+    config: &Config,
+    shutdown: &AtomicBool,
+) -> Result<(), ServerError>
+where
+    L: Lexicon + Send + Sync,
+    L::Info: AsRef<str>,
+{
+    let server =
+        tiny_http::Server::http(&config.addr).map_err(|_| ServerError::FailedToListen {
+            addr: config.addr.clone(),
+        })?;
+    let server = &server;
+    thread::scope(|scope| {
+        for _ in 0..std::cmp::max(1, config.threads) {
+            scope.spawn(move || {
+                while !shutdown.load(Ordering::SeqCst) {
+                    match server.recv_timeout(Duration::from_millis(100)) {
+                        Ok(Some(request)) => respond(morph, config, request),
+                        Ok(None) => (),
+                        Err(_) => break,
+                    }
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_server {
+    use super::*;
+    use crate::testutil;
+    use crate::Morph;
+    use std::io::Write;
+    use std::net::TcpStream;
+
+    fn morph() -> Morph<String> {
+        let dict_src = "東,8,7,6245,名詞,一般\n\
+                        京,1,1,10791,名詞,一般\n\
+                        京都,2,1,2135,名詞,固有名詞\n\
+                        東京,1,1,3003,名詞,固有名詞\n\
+                        都,3,2,9428,名詞,接尾\n";
+        let matrix_src = "9 8\n0 7 -283\n0 1 -310\n8 1 -368\n1 2 -9617\n1 3 -1303\n3 0 -409";
+        testutil::morph(matrix_src, dict_src)
+    }
+
+    #[test]
+    fn test_handle() {
+        let morph = morph();
+        let response = handle(
+            &morph,
+            "POST",
+            "/tokenize",
+            r#"{"sentence": "東京都"}"#.as_bytes(),
+        );
+        assert_eq!(response.status, 200);
+        let json: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(json["tokens"][0]["surface"], "東京");
+        assert_eq!(json["tokens"][1]["surface"], "都");
+
+        let response = handle(
+            &morph,
+            "POST",
+            "/nbest",
+            r#"{"sentence": "東京都", "n": 2}"#.as_bytes(),
+        );
+        let json: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(json["paths"].as_array().unwrap().len(), 2);
+        assert_eq!(json["paths"][0]["tokens"][0]["surface"], "東京");
+
+        let response = handle(
+            &morph,
+            "POST",
+            "/lattice",
+            r#"{"sentence": "東京都"}"#.as_bytes(),
+        );
+        let json: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+        let nodes = json["nodes"].as_array().unwrap();
+        assert_eq!(nodes.len(), 5);
+        assert_eq!(nodes.iter().filter(|node| node["best"] == true).count(), 2);
+
+        // words of a run of unknown characters are the same word at the same beginning
+        let unknown = crate::unknown::Unknown::from_text(
+            &mut "DEFAULT 0 1 0\nKATAKANA 1 1 2\n0x30A1..0x30FE KATAKANA\n".as_bytes(),
+            &mut "DEFAULT,5,5,3274,記号,一般\nKATAKANA,1,1,7209,名詞,一般\n".as_bytes(),
+            testutil::classify,
+        )
+        .unwrap();
+        let morph = morph.with_unknown(unknown).unwrap();
+        let response = handle(
+            &morph,
+            "POST",
+            "/lattice",
+            r#"{"sentence": "トキョ"}"#.as_bytes(),
+        );
+        let json: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+        let nodes = json["nodes"].as_array().unwrap();
+        assert!(nodes.iter().filter(|node| node["begin"] == 0).count() >= 3);
+        let best: Vec<(u64, u64)> = nodes
+            .iter()
+            .filter(|node| node["best"] == true)
+            .map(|node| {
+                (
+                    node["begin"].as_u64().unwrap(),
+                    node["end"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(best, vec![(0, 9)]);
+        assert_eq!(morph.wakati("トキョ".as_bytes()), Some(vec![(0, 9)]));
+
+        assert_eq!(handle(&morph, "POST", "/tokenize", b"{").status, 400);
+        assert_eq!(handle(&morph, "GET", "/tokenize", b"").status, 405);
+        assert_eq!(handle(&morph, "POST", "/unknown", b"").status, 404);
+        assert_eq!(handle(&morph, "GET", "/health", b"").status, 200);
+    }
+
+    #[test]
+    fn test_serve() {
+        let morph = MorphHandle::new(morph());
+        // bind an unused port first to learn its number
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config = Config {
+            addr: format!("127.0.0.1:{}", port),
+            max_body: 64,
+            threads: 2,
+        };
+        let shutdown = AtomicBool::new(false);
+        thread::scope(|scope| {
+            let server = scope.spawn(|| serve(&morph, &config, &shutdown));
+            let request = |body: &str| {
+                let mut stream = loop {
+                    if let Ok(stream) = TcpStream::connect(("127.0.0.1", port)) {
+                        break stream;
+                    }
+                    thread::sleep(Duration::from_millis(10));
+                };
+                write!(
+                    stream,
+                    "POST /tokenize HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+                     Content-Length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).unwrap();
+                response
+            };
+            let response = request(r#"{"sentence": "東京都"}"#);
+            assert!(response.starts_with("HTTP/1.1 200"));
+            assert!(response.contains(r#""surface":"東京""#));
+            let response = request(&format!(r#"{{"sentence": "{}"}}"#, "東".repeat(30)));
+            assert!(response.starts_with("HTTP/1.1 413"));
+            shutdown.store(true, Ordering::SeqCst);
+            assert!(server.join().unwrap().is_ok());
+        });
+    }
+}
@@ -0,0 +1,255 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::hash_map::RandomState;
+use std::collections::BinaryHeap;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+// External merge sort of (key, value) pairs by key.
+// At most chunk_len pairs are held in memory. The others are spilled to sorted runs
+// in temporary files, which are merged by finish.
+// Values of the same key keep the order of push.
+pub struct ExternalSorter<T> {
+    chunk_len: usize,
+    chunk: Vec<(Vec<u8>, T)>,
+    runs: Vec<Run>,
+    // made at the first spill
+    dir: Option<Arc<RunDir>>,
+}
+
+// Directory of runs only the user can enter. It is made anew under a random name,
+// so a file or link someone else put in the temporary directory is never opened.
+// Removed when the last run is.
+struct RunDir {
+    path: PathBuf,
+}
+
+// attempts at a name not taken yet
+const RUN_DIR_ATTEMPTS: usize = 16;
+
+impl RunDir {
+    fn create() -> Result<Self, io::Error> {
+        let mut builder = fs::DirBuilder::new();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+        let mut taken = None;
+        for _ in 0..RUN_DIR_ATTEMPTS {
+            // keys of RandomState are random for each process and differ on each call
+            let random = RandomState::new().build_hasher().finish();
+            let path = std::env::temp_dir().join(format!(
+                "namaco-sort-{}-{:016x}",
+                std::process::id(),
+                random
+            ));
+            // fails on an existing path, even a link
+            match builder.create(&path) {
+                Ok(()) => return Ok(RunDir { path }),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => taken = Some(err),
+                Err(err) => return Err(err),
+            }
+        }
+        Err(taken.unwrap())
+    }
+}
+
+impl Drop for RunDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir(&self.path);
+    }
+}
+
+// sorted pairs in a file of RunDir, removed on drop
+struct Run {
+    dir: Arc<RunDir>,
+    idx: usize,
+    len: usize,
+}
+
+impl Run {
+    fn path(&self) -> PathBuf {
+        self.dir.path.join(self.idx.to_string())
+    }
+}
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(self.path());
+    }
+}
+
+fn to_io_error(err: bincode::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+impl<T: Serialize + DeserializeOwned> ExternalSorter<T> {
+    pub fn new(chunk_len: usize) -> Self {
+        ExternalSorter {
+            chunk_len: std::cmp::max(1, chunk_len),
+            chunk: Vec::new(),
+            runs: Vec::new(),
+            dir: None,
+        }
+    }
+
+    pub fn push(&mut self, key: Vec<u8>, value: T) -> Result<(), io::Error> {
+        self.chunk.push((key, value));
+        if self.chunk.len() >= self.chunk_len {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn spill(&mut self) -> Result<(), io::Error> {
+        // stable
+        self.chunk.sort_by(|a, b| a.0.cmp(&b.0));
+        let dir = match &self.dir {
+            Some(dir) => dir.clone(),
+            None => {
+                let dir = Arc::new(RunDir::create()?);
+                self.dir = Some(dir.clone());
+                dir
+            }
+        };
+        let run = Run {
+            dir,
+            idx: self.runs.len(),
+            len: self.chunk.len(),
+        };
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(run.path())?;
+        let mut stream = io::BufWriter::new(file);
+        for pair in self.chunk.drain(..) {
+            bincode::serialize_into(&mut stream, &pair).map_err(to_io_error)?;
+        }
+        stream.flush()?;
+        self.runs.push(run);
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<Sorted<T>, io::Error> {
+        self.chunk.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut sources = Vec::new();
+        for run in self.runs.drain(..) {
+            let reader = io::BufReader::new(fs::File::open(run.path())?);
+            sources.push(Source::Run(run, reader));
+        }
+        // the last chunk is the latest input
+        sources.push(Source::Memory(std::mem::take(&mut self.chunk).into_iter()));
+        let mut sorted = Sorted {
+            sources,
+            heads: Vec::new(),
+            heap: BinaryHeap::new(),
+        };
+        for idx in 0..sorted.sources.len() {
+            sorted.heads.push(None);
+            sorted.advance(idx)?;
+        }
+        Ok(sorted)
+    }
+}
+
+enum Source<T> {
+    Run(Run, io::BufReader<fs::File>),
+    Memory(std::vec::IntoIter<(Vec<u8>, T)>),
+}
+
+// Pairs in ascending order of key
+pub struct Sorted<T> {
+    sources: Vec<Source<T>>,
+    // the next value of each source
+    heads: Vec<Option<T>>,
+    // the next key of each source. ties are broken by the order of sources.
+    heap: BinaryHeap<Reverse<(Vec<u8>, usize)>>,
+}
+
+impl<T: DeserializeOwned> Sorted<T> {
+    fn advance(&mut self, idx: usize) -> Result<(), io::Error> {
+        let next = match &mut self.sources[idx] {
+            Source::Run(run, reader) => {
+                if run.len == 0 {
+                    None
+                } else {
+                    run.len -= 1;
+                    Some(bincode::deserialize_from(reader).map_err(to_io_error)?)
+                }
+            }
+            Source::Memory(iter) => iter.next(),
+        };
+        if let Some((key, value)) = next {
+            self.heads[idx] = Some(value);
+            self.heap.push(Reverse((key, idx)));
+        }
+        Ok(())
+    }
+}
+
+impl<T: DeserializeOwned> Iterator for Sorted<T> {
+    type Item = Result<(Vec<u8>, T), io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((key, idx)) = self.heap.pop()?;
+        let value = self.heads[idx].take().unwrap();
+        Some(self.advance(idx).map(|()| (key, value)))
+    }
+}
+
+#[cfg(test)]
+mod test_sort {
+    use super::*;
+    use crate::testutil::xorshift;
+
+    #[test]
+    fn test_external_sort() {
+        let mut rand = xorshift(0x9e37_79b9_7f4a_7c15);
+        let pairs: Vec<(Vec<u8>, u32)> = (0..1000)
+            .map(|i| {
+                let len = rand(3) as usize;
+                ((0..len).map(|_| rand(4) as u8).collect(), i)
+            })
+            .collect();
+        let mut expected = pairs.clone();
+        expected.sort_by(|a, b| a.0.cmp(&b.0));
+
+        for chunk_len in &[1, 7, 1000, 5000] {
+            let mut sorter = ExternalSorter::new(*chunk_len);
+            for (key, value) in pairs.clone() {
+                sorter.push(key, value).unwrap();
+            }
+            let sorted: Vec<_> = sorter.finish().unwrap().map(Result::unwrap).collect();
+            assert_eq!(sorted, expected);
+        }
+    }
+
+    #[test]
+    fn test_runs_removed() {
+        let mut sorter = ExternalSorter::new(2);
+        for i in 0..5u32 {
+            sorter.push(vec![5 - i as u8], i).unwrap();
+        }
+        let paths: Vec<PathBuf> = sorter.runs.iter().map(Run::path).collect();
+        assert_eq!(paths.len(), 2);
+        assert!(paths.iter().all(|path| path.exists()));
+        let dir = sorter.dir.as_ref().unwrap().path.clone();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&dir).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+        let mut sorted = sorter.finish().unwrap();
+        assert_eq!(sorted.next().unwrap().unwrap(), (vec![1], 4));
+        drop(sorted);
+        assert!(paths.iter().all(|path| !path.exists()));
+        assert!(!dir.exists());
+    }
+}
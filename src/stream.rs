@@ -0,0 +1,92 @@
+use std::io;
+use std::io::{BufRead, Read};
+
+#[derive(Debug, PartialEq)]
+pub enum Line {
+    Complete,
+    // longer than max_length. buffer is left empty.
+    TooLong,
+}
+
+// Line reader which never holds more than max_length bytes of a line.
+pub struct LineReader<R: Read> {
+    reader: io::BufReader<R>,
+    max_length: usize,
+}
+
+impl<R: Read> LineReader<R> {
+    pub fn new(src: R, max_length: usize) -> Self {
+        LineReader {
+            reader: io::BufReader::new(src),
+            max_length,
+        }
+    }
+
+    // Read one line without "\n" and "\r\n" into buf. Ok(None) at EOF.
+    pub fn read_line(&mut self, buf: &mut Vec<u8>) -> Result<Option<Line>, io::Error> {
+        buf.clear();
+        let mut read_any = false;
+        let mut too_long = false;
+        loop {
+            let available = self.reader.fill_buf()?;
+            if available.is_empty() {
+                break;
+            }
+            read_any = true;
+            let (chunk, consumed, terminated) = match available.iter().position(|b| *b == b'\n') {
+                Some(idx) => (&available[..idx], idx + 1, true),
+                None => (available, available.len(), false),
+            };
+            if !too_long {
+                if buf.len() + chunk.len() > self.max_length + 1 {
+                    too_long = true;
+                    buf.clear();
+                } else {
+                    buf.extend_from_slice(chunk);
+                }
+            }
+            self.reader.consume(consumed);
+            if terminated {
+                break;
+            }
+        }
+        if !read_any {
+            return Ok(None);
+        }
+        if buf.last() == Some(&b'\r') {
+            buf.pop();
+        }
+        if too_long || buf.len() > self.max_length {
+            buf.clear();
+            Ok(Some(Line::TooLong))
+        } else {
+            Ok(Some(Line::Complete))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_line_reader {
+    use super::*;
+
+    #[test]
+    fn test_read_line() {
+        // chain splits the line of 0123456789 into two reads
+        let src = "東京\r\n\n01234"
+            .as_bytes()
+            .chain("56789\n012345\nabc".as_bytes());
+        let mut reader = LineReader::new(src, 6);
+        let mut buf = Vec::new();
+        assert_eq!(reader.read_line(&mut buf).unwrap(), Some(Line::Complete));
+        assert_eq!(buf, "東京".as_bytes());
+        assert_eq!(reader.read_line(&mut buf).unwrap(), Some(Line::Complete));
+        assert_eq!(buf, b"");
+        assert_eq!(reader.read_line(&mut buf).unwrap(), Some(Line::TooLong));
+        assert_eq!(buf, b"");
+        assert_eq!(reader.read_line(&mut buf).unwrap(), Some(Line::Complete));
+        assert_eq!(buf, b"012345");
+        assert_eq!(reader.read_line(&mut buf).unwrap(), Some(Line::Complete));
+        assert_eq!(buf, b"abc");
+        assert_eq!(reader.read_line(&mut buf).unwrap(), None);
+    }
+}
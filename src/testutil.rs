@@ -0,0 +1,42 @@
+// Helpers shared by tests of several modules
+use super::parser::{BuildOptions, Word};
+use super::Morph;
+use std::io::Cursor;
+
+// xorshift, which is enough for tests and needs no crate. rand(n) is below n.
+pub fn xorshift(seed: u64) -> impl FnMut(u64) -> u64 {
+    let mut state = seed;
+    move |n: u64| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state % n
+    }
+}
+
+// surface, lid, rid, cost and features of a line of MeCab CSV. info is the features as is.
+pub fn classify(arr: &[&str]) -> (Vec<u8>, Word<String>) {
+    (
+        arr[0].as_bytes().to_vec(),
+        Word {
+            info: arr[4..].join(",").trim().to_owned(),
+            lid: arr[1].parse().unwrap(),
+            rid: arr[2].parse().unwrap(),
+            cost: arr[3].parse().unwrap(),
+        },
+    )
+}
+
+pub fn morph(matrix_src: &str, dict_src: &str) -> Morph<String> {
+    morph_with(matrix_src, dict_src, BuildOptions::default())
+}
+
+pub fn morph_with(matrix_src: &str, dict_src: &str, options: BuildOptions) -> Morph<String> {
+    Morph::from_text_with(
+        &mut Cursor::new(matrix_src.as_bytes()),
+        &mut Cursor::new(dict_src.as_bytes()),
+        classify,
+        options,
+    )
+    .unwrap()
+}
@@ -1,13 +1,87 @@
 // copyright (c) 2019 Nakano Masaki <namachan10777@gmail>
-use serde::Serialize;
 use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{mpsc, Mutex};
+use std::thread;
 use std::usize;
 
+// repr(C) to be read from mapped dictionaries in place.
+// Fields are u32 to halve the size of tree. u32::MAX is read as usize::MAX by the accessors.
 #[derive(Clone, PartialEq, Debug, Copy, Serialize, Deserialize)]
-struct Node {
-    base: usize,
-    check: usize,
-    id: usize,
+#[repr(C)]
+pub(crate) struct Node {
+    pub(crate) base: u32,
+    pub(crate) check: u32,
+    pub(crate) id: u32,
+}
+
+// The tree can not be larger than this because of u32 indices.
+// So are ids, values and tail, which insert and Builder check with Sizes before they grow.
+const MAX_CELLS: usize = u32::MAX as usize;
+
+// Bounds of the lengths of a trie, checked before it grows so that narrow never fails
+#[derive(Clone, Copy, Debug, Default)]
+struct Sizes {
+    cells: usize,
+    ids: usize,
+    values: usize,
+    tail: usize,
+}
+
+impl Sizes {
+    fn of<T>(trie: &Trie<T>) -> Self {
+        Sizes {
+            cells: trie.tree.len(),
+            ids: trie.arena.len(),
+            values: trie.arena.count(),
+            tail: trie.tail.len(),
+        }
+    }
+
+    // After placing rows of nodes, appending tail bytes and a value of a new id.
+    // Blank cells of a doubled tree hold its length, so the tree doubles at most once
+    // beyond the larger of its length and the rows.
+    fn grown(self, rows: usize, tail: usize) -> Self {
+        Sizes {
+            cells: 2 * std::cmp::max(self.cells, ROW_LEN * rows),
+            ids: self.ids + 1,
+            values: self.values + 1,
+            tail: self.tail + tail,
+        }
+    }
+
+    fn plus(self, other: Sizes) -> Self {
+        Sizes {
+            cells: self.cells + other.cells,
+            ids: self.ids + other.ids,
+            values: self.values + other.values,
+            tail: self.tail + other.tail,
+        }
+    }
+
+    fn fits(&self) -> bool {
+        self.cells <= MAX_CELLS
+            && self.ids < MAX_CELLS
+            && self.values < MAX_CELLS
+            && self.tail < MAX_CELLS
+    }
+}
+
+fn narrow(n: usize) -> u32 {
+    if n == usize::MAX {
+        u32::MAX
+    } else {
+        assert!(n < MAX_CELLS, "index of trie exceeds u32");
+        n as u32
+    }
+}
+
+fn widen(n: u32) -> usize {
+    if n == u32::MAX {
+        usize::MAX
+    } else {
+        n as usize
+    }
 }
 impl Default for Node {
     fn default() -> Node {
@@ -31,7 +105,35 @@ impl Node {
     }
 
     fn node(check: usize, base: usize, id: usize) -> Self {
-        Node { check, base, id }
+        Node {
+            check: narrow(check),
+            base: narrow(base),
+            id: narrow(id),
+        }
+    }
+
+    pub(crate) fn base(&self) -> usize {
+        widen(self.base)
+    }
+
+    pub(crate) fn check(&self) -> usize {
+        widen(self.check)
+    }
+
+    pub(crate) fn id(&self) -> usize {
+        widen(self.id)
+    }
+
+    fn set_base(&mut self, base: usize) {
+        self.base = narrow(base);
+    }
+
+    fn set_check(&mut self, check: usize) {
+        self.check = narrow(check);
+    }
+
+    fn set_id(&mut self, id: usize) {
+        self.id = narrow(id);
     }
 
     fn blank() -> Self {
@@ -61,18 +163,18 @@ const NO_CHILD: usize = usize::MAX;
 
 impl Into<DecodedNode> for Node {
     fn into(self) -> DecodedNode {
-        if self.check == NO_PARENT {
-            if self.base == NO_CHILD {
+        if self.check() == NO_PARENT {
+            if self.base() == NO_CHILD {
                 DecodedNode::Blank
             } else {
-                DecodedNode::Root(self.base)
+                DecodedNode::Root(self.base())
             }
-        } else if self.base == NO_CHILD {
-            DecodedNode::Term(self.check, self.id)
-        } else if self.id == NO_ITEM {
-            DecodedNode::Sec(self.check, self.base, None)
+        } else if self.base() == NO_CHILD {
+            DecodedNode::Term(self.check(), self.id())
+        } else if self.id() == NO_ITEM {
+            DecodedNode::Sec(self.check(), self.base(), None)
         } else {
-            DecodedNode::Sec(self.check, self.base, Some(self.id))
+            DecodedNode::Sec(self.check(), self.base(), Some(self.id()))
         }
     }
 }
@@ -80,27 +182,11 @@ impl Into<DecodedNode> for Node {
 impl From<DecodedNode> for Node {
     fn from(dnode: DecodedNode) -> Self {
         match dnode {
-            DecodedNode::Root(base) => Node {
-                base,
-                check: NO_PARENT,
-                id: 0,
-            },
-            DecodedNode::Term(check, id) => Node {
-                base: NO_CHILD,
-                check,
-                id,
-            },
-            DecodedNode::Sec(check, base, None) => Node {
-                base,
-                check,
-                id: NO_ITEM,
-            },
-            DecodedNode::Sec(check, base, Some(id)) => Node { base, check, id },
-            DecodedNode::Blank => Node {
-                base: NO_CHILD,
-                check: NO_PARENT,
-                id: NO_ITEM,
-            },
+            DecodedNode::Root(base) => Node::node(NO_PARENT, base, 0),
+            DecodedNode::Term(check, id) => Node::node(check, NO_CHILD, id),
+            DecodedNode::Sec(check, base, None) => Node::node(check, base, NO_ITEM),
+            DecodedNode::Sec(check, base, Some(id)) => Node::node(check, base, id),
+            DecodedNode::Blank => Node::node(NO_PARENT, NO_CHILD, NO_ITEM),
         }
     }
 }
@@ -114,12 +200,12 @@ mod node_test {
         let root_decoded = DecodedNode::Root(129);
         let root_raw = Node {
             base: 129,
-            check: NO_PARENT,
+            check: u32::MAX,
             id: 0,
         };
         let term_decoded = DecodedNode::Term(2158, 87);
         let term_raw = Node {
-            base: NO_CHILD,
+            base: u32::MAX,
             check: 2158,
             id: 87,
         };
@@ -127,7 +213,7 @@ mod node_test {
         let sec_no_property_raw = Node {
             base: 59182,
             check: 52128,
-            id: NO_ITEM,
+            id: u32::MAX,
         };
         let sec_has_property_decoded = DecodedNode::Sec(711475, 365123, Some(214));
         let sec_has_property_raw = Node {
@@ -161,44 +247,392 @@ mod node_test {
     }
 }
 
+// Map from byte strings to values in a double array.
+// A key holds its values in the order of insertion.
+// Empty keys are not supported because root can not hold values.
 #[derive(Serialize, Deserialize)]
-pub struct Trie<T: Serialize> {
-    capacities: Vec<u8>,
-    cache: Vec<usize>,
+pub struct Trie<T> {
+    // derived from tree, so rebuilt instead of stored
+    #[serde(skip)]
+    vacancy: Vacancy,
     // 圧縮済みの遷移表
     tree: Vec<Node>,
-    // 辞書本体
-    storage: Vec<Vec<T>>,
+    // 辞書本体. values of all keys
+    arena: Arena<T>,
+    // ids of removed keys, reused by insert
+    vacant: Vec<usize>,
+    // labels of transitions for bytes of keys
+    alphabet: Alphabet,
+    // Single-path suffixes of keys below their leaves, compared on lookup instead of nodes.
+    tail: Vec<u8>,
+    // Tail of the key of id is tail[tails[id].range()], empty unless its node is a leaf.
+    // Cut off after the last key with a tail, so a trie without tails costs nothing.
+    tails: Vec<Span>,
+    // Bytes of tail dropped by insert and remove, which no key refers to.
+    // Counted at the first drop after deserialization. The tail is compacted
+    // once they outnumber the bytes in use.
+    #[serde(skip)]
+    dead: Option<usize>,
+}
+
+// Labels of transitions for bytes. With frequent bytes on small labels, children of a node
+// gather in a few cells of a block, which packs the tree densely.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Alphabet {
+    // label of each byte, or empty for the identity
+    codes: Vec<u8>,
+}
+
+impl Alphabet {
+    // Labels in descending order of counts[byte]. NUL keeps 0, which is the first partition
+    // of Builder as it is for the identity.
+    pub(crate) fn by_frequency(counts: &[usize; 256]) -> Self {
+        let mut bytes: Vec<usize> = (1..ROW_LEN).collect();
+        // stable, so ties are in the order of bytes
+        bytes.sort_by_key(|byte| std::cmp::Reverse(counts[*byte]));
+        let mut codes = vec![0; ROW_LEN];
+        for (code, byte) in bytes.into_iter().enumerate() {
+            codes[byte] = code as u8 + 1;
+        }
+        Alphabet { codes }
+    }
+
+    fn label(&self, octet: u8) -> usize {
+        label(&self.codes, octet)
+    }
+}
+
+// shared with mapped dictionaries, which store codes of Alphabet as they are
+fn label(codes: &[u8], octet: u8) -> usize {
+    if codes.is_empty() {
+        octet as usize
+    } else {
+        codes[octet as usize] as usize
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+struct Span {
+    offset: u32,
+    len: u32,
+}
+
+impl Span {
+    fn new(offset: usize, len: usize) -> Self {
+        Span {
+            offset: narrow(offset),
+            len: narrow(len),
+        }
+    }
+
+    fn range(&self) -> std::ops::Range<usize> {
+        let offset = self.offset as usize;
+        offset..offset + self.len as usize
+    }
+}
+
+// Values of every id. They are in one sequence as built and as stored, where values of id
+// are values[spans[id].range()]. Once values not at the end of the sequence are changed,
+// every id holds its own values, so that insert and remove do not move the others.
+// They are put in one sequence again when stored.
+enum Arena<T> {
+    Flat(Vec<T>, Vec<Span>),
+    // and the number of values
+    Nested(Vec<Vec<T>>, usize),
+}
+
+impl<T> Default for Arena<T> {
+    fn default() -> Self {
+        Arena::Flat(Vec::new(), Vec::new())
+    }
+}
+
+impl<T> Arena<T> {
+    // number of ids
+    fn len(&self) -> usize {
+        match self {
+            Arena::Flat(_, spans) => spans.len(),
+            Arena::Nested(nested, _) => nested.len(),
+        }
+    }
+
+    // number of values of all ids
+    fn count(&self) -> usize {
+        match self {
+            Arena::Flat(values, _) => values.len(),
+            Arena::Nested(_, count) => *count,
+        }
+    }
+
+    fn get(&self, id: usize) -> &[T] {
+        self.values().get(id)
+    }
+
+    fn get_mut(&mut self, id: usize) -> &mut [T] {
+        match self {
+            Arena::Flat(values, spans) => &mut values[spans[id].range()],
+            Arena::Nested(nested, _) => &mut nested[id],
+        }
+    }
+
+    fn values(&self) -> Values<'_, T> {
+        match self {
+            Arena::Flat(values, spans) => Values::Spans(values, spans),
+            Arena::Nested(nested, _) => Values::Nested(nested),
+        }
+    }
+
+    // new id without values
+    fn add(&mut self) -> usize {
+        match self {
+            Arena::Flat(values, spans) => spans.push(Span::new(values.len(), 0)),
+            Arena::Nested(nested, _) => nested.push(Vec::new()),
+        }
+        self.len() - 1
+    }
+
+    fn push(&mut self, id: usize, value: T) {
+        if let Arena::Flat(values, spans) = self {
+            let span = spans[id];
+            // empty values are at the end wherever they are
+            if span.len == 0 || span.range().end == values.len() {
+                let offset = if span.len == 0 {
+                    values.len()
+                } else {
+                    span.offset as usize
+                };
+                spans[id] = Span::new(offset, span.len as usize + 1);
+                values.push(value);
+                return;
+            }
+        }
+        let (nested, count) = self.nest();
+        nested[id].push(value);
+        *count += 1;
+    }
+
+    fn take(&mut self, id: usize) -> Vec<T> {
+        if let Arena::Flat(values, spans) = self {
+            let span = spans[id];
+            if span.len == 0 {
+                return Vec::new();
+            }
+            if span.range().end == values.len() {
+                spans[id] = Span::default();
+                return values.split_off(span.offset as usize);
+            }
+        }
+        let (nested, count) = self.nest();
+        let taken = std::mem::take(&mut nested[id]);
+        *count -= taken.len();
+        taken
+    }
+
+    fn nest(&mut self) -> (&mut Vec<Vec<T>>, &mut usize) {
+        if let Arena::Flat(values, spans) = self {
+            let mut order: Vec<usize> = (0..spans.len()).collect();
+            order.sort_by_key(|id| spans[*id].offset);
+            let mut nested: Vec<Vec<T>> = spans.iter().map(|_| Vec::new()).collect();
+            let count = values.len();
+            let mut values = std::mem::take(values).into_iter();
+            // values of ids tile the sequence
+            for id in order {
+                nested[id] = values.by_ref().take(spans[id].len as usize).collect();
+            }
+            *self = Arena::Nested(nested, count);
+        }
+        match self {
+            Arena::Nested(nested, count) => (nested, count),
+            Arena::Flat(..) => unreachable!(),
+        }
+    }
+
+    fn into_flat(self) -> (Vec<T>, Vec<Span>) {
+        match self {
+            Arena::Flat(values, spans) => (values, spans),
+            Arena::Nested(nested, count) => {
+                let mut values = Vec::with_capacity(count);
+                let mut spans = Vec::with_capacity(nested.len());
+                for vals in nested {
+                    spans.push(Span::new(values.len(), vals.len()));
+                    values.extend(vals);
+                }
+                (values, spans)
+            }
+        }
+    }
+
+    // Append ids of other after the ids of self
+    fn append(&mut self, mut other: Arena<T>) {
+        match self {
+            Arena::Flat(values, spans) => {
+                let (others, other_spans) = other.into_flat();
+                let offset = values.len();
+                spans.extend(
+                    other_spans
+                        .iter()
+                        .map(|span| Span::new(span.offset as usize + offset, span.len as usize)),
+                );
+                values.extend(others);
+            }
+            Arena::Nested(nested, count) => {
+                let (others, other_count) = other.nest();
+                nested.append(others);
+                *count += *other_count;
+            }
+        }
+    }
+}
+
+// stored as the values and spans of Flat
+impl<T: serde::Serialize> serde::Serialize for Arena<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Arena::Flat(values, spans) => (values, spans).serialize(serializer),
+            Arena::Nested(nested, count) => {
+                let mut offset = 0;
+                let spans: Vec<Span> = nested
+                    .iter()
+                    .map(|vals| {
+                        offset += vals.len();
+                        Span::new(offset - vals.len(), vals.len())
+                    })
+                    .collect();
+                (Concat(nested, *count), spans).serialize(serializer)
+            }
+        }
+    }
+}
+
+impl<'de, T: serde::Deserialize<'de>> serde::Deserialize<'de> for Arena<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (values, spans) = <(Vec<T>, Vec<Span>)>::deserialize(deserializer)?;
+        Ok(Arena::Flat(values, spans))
+    }
+}
+
+// values of nested ids in one sequence, and their number
+struct Concat<'a, T>(&'a [Vec<T>], usize);
+
+impl<'a, T: serde::Serialize> serde::Serialize for Concat<'a, T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeSeq;
+        // the length is told beforehand as bincode requires
+        let mut seq = serializer.serialize_seq(Some(self.1))?;
+        for value in self.0.iter().flatten() {
+            seq.serialize_element(value)?;
+        }
+        seq.end()
+    }
+}
+
+// Node of dictionary format version 1, whose indices were usize
+#[derive(Serialize, Deserialize)]
+pub(crate) struct WideNode {
+    pub(crate) base: usize,
+    pub(crate) check: usize,
+    pub(crate) id: usize,
+}
+
+// Trie of dictionary format version 1, whose values were allocated for each key.
+// capacities and cache were hints for insert, which are rebuilt as vacancy.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct NestedTrie<T> {
+    pub(crate) capacities: Vec<u8>,
+    pub(crate) cache: Vec<usize>,
+    pub(crate) tree: Vec<WideNode>,
+    pub(crate) storage: Vec<Vec<T>>,
+}
+
+impl<T> NestedTrie<T> {
+    // None if the trie is too large for u32 indices
+    pub(crate) fn migrate(self) -> Option<Trie<T>> {
+        let fit = |n: usize| n == usize::MAX || n < MAX_CELLS;
+        let values_len: usize = self.storage.iter().map(Vec::len).sum();
+        if self.tree.len() > MAX_CELLS || self.storage.len() >= MAX_CELLS || !fit(values_len) {
+            return None;
+        }
+        let mut tree = Vec::with_capacity(self.tree.len());
+        for node in self.tree {
+            if !(fit(node.base) && fit(node.check) && fit(node.id)) {
+                return None;
+            }
+            tree.push(Node {
+                base: narrow(node.base),
+                check: narrow(node.check),
+                id: narrow(node.id),
+            });
+        }
+        let mut values = Vec::with_capacity(values_len);
+        let mut spans = Vec::with_capacity(self.storage.len());
+        for vals in self.storage {
+            spans.push(Span::new(values.len(), vals.len()));
+            values.extend(vals);
+        }
+        Some(Trie {
+            vacancy: Vacancy::default(),
+            tree,
+            arena: Arena::Flat(values, spans),
+            vacant: Vec::new(),
+            alphabet: Alphabet::default(),
+            tail: Vec::new(),
+            tails: Vec::new(),
+            dead: None,
+        })
+    }
 }
 
 const ROW_LEN: usize = 256;
 type Row = [Node; ROW_LEN];
 
-impl<T: Serialize> Default for Trie<T> {
+impl<T> Default for Trie<T> {
     fn default() -> Trie<T> {
         let mut tree = vec![Node::blank(); 256];
         tree[0] = Node::root(0);
         Trie {
-            cache: vec![0; 256],
-            capacities: vec![254],
+            vacancy: Vacancy::default(),
             tree,
-            storage: Vec::new(),
+            arena: Arena::default(),
+            vacant: Vec::new(),
+            alphabet: Alphabet::default(),
+            tail: Vec::new(),
+            tails: Vec::new(),
+            dead: None,
         }
     }
 }
 
-impl<T: Serialize> Trie<T> {
+#[derive(Fail, Debug, PartialEq)]
+pub enum InsertError {
+    #[fail(display = "empty key")]
+    EmptyKey,
+    #[fail(display = "trie is full: its indices would exceed 32 bits")]
+    Full,
+}
+
+impl<T> Trie<T> {
+    pub fn new() -> Self {
+        Trie::default()
+    }
+
+    // builds a trie from sorted keys faster and more densely than insert
+    pub fn builder() -> Builder<T> {
+        Builder::new()
+    }
+}
+
+impl<T> Trie<T> {
     // Ok(idx)
     // Err((passed times, last idx))
     fn explore(&self, way: &[u8]) -> Result<usize, (usize, usize)> {
         let mut here = 0usize;
         for (octet_count, octet) in way.iter().enumerate() {
             let check = here;
-            if self.tree[here].base == NO_CHILD {
+            if self.tree[here].base() == NO_CHILD {
                 return Err((octet_count, check));
             }
-            here = self.tree[here].base ^ (*octet as usize);
-            if self.tree[here].check != check {
+            here = self.tree[here].base() ^ self.alphabet.label(*octet);
+            if self.tree[here].check() != check {
                 return Err((octet_count, check));
             }
         }
@@ -225,10 +659,14 @@ mod test_explore {
         tree[7] = Node::sec(2, 4, Some(3));
         tree[5] = Node::term(7, 4);
         let trie = Trie {
-            cache: vec![0; 256],
-            capacities: vec![249, 255],
+            vacancy: Vacancy::default(),
             tree,
-            storage: Vec::new() as Vec<Vec<String>>,
+            arena: Arena::<String>::default(),
+            vacant: Vec::new(),
+            alphabet: Alphabet::default(),
+            tail: Vec::new(),
+            tails: Vec::new(),
+            dead: None,
         };
         assert_eq!(trie.explore(&[1]), Ok(1));
         assert_eq!(trie.explore(&[2]), Ok(2));
@@ -241,38 +679,160 @@ mod test_explore {
     }
 }
 
-impl<T: Serialize> Trie<T> {
+const NO_CELL: usize = usize::MAX;
+
+fn is_blank(node: &Node) -> bool {
+    node.check() == NO_PARENT && node.base() == NO_CHILD
+}
+
+// Blank cells of each block linked in a circular doubly linked list,
+// so that base search visits only blank cells.
+#[derive(Default)]
+struct Vacancy {
+    prev: Vec<usize>,
+    next: Vec<usize>,
+    // a blank cell of each block or NO_CELL
+    head: Vec<usize>,
+    // number of blank cells of each block
+    count: Vec<usize>,
+    // blocks before cache[cnt] are skipped when searching base for cnt children
+    cache: Vec<usize>,
+}
+
+impl Vacancy {
+    fn build(tree: &[Node]) -> Self {
+        let mut vacancy = Vacancy {
+            prev: vec![NO_CELL; tree.len()],
+            next: vec![NO_CELL; tree.len()],
+            head: vec![NO_CELL; tree.len() / ROW_LEN],
+            count: vec![0; tree.len() / ROW_LEN],
+            cache: vec![0; ROW_LEN + 1],
+        };
+        for (idx, node) in tree.iter().enumerate() {
+            if is_blank(node) {
+                vacancy.link(idx);
+            }
+        }
+        vacancy
+    }
+
+    fn link(&mut self, idx: usize) {
+        let block = idx / ROW_LEN;
+        let head = self.head[block];
+        if head == NO_CELL {
+            self.head[block] = idx;
+            self.prev[idx] = idx;
+            self.next[idx] = idx;
+        } else {
+            // append to the tail
+            let tail = self.prev[head];
+            self.next[tail] = idx;
+            self.prev[idx] = tail;
+            self.next[idx] = head;
+            self.prev[head] = idx;
+        }
+        self.count[block] += 1;
+        // the block may have room for larger rows now. cache is ascending.
+        for cnt in (0..=self.count[block]).rev() {
+            if self.cache[cnt] <= block {
+                break;
+            }
+            self.cache[cnt] = block;
+        }
+    }
+
+    fn unlink(&mut self, idx: usize) {
+        let block = idx / ROW_LEN;
+        let next = self.next[idx];
+        if next == idx {
+            self.head[block] = NO_CELL;
+        } else {
+            let prev = self.prev[idx];
+            self.next[prev] = next;
+            self.prev[next] = prev;
+            if self.head[block] == idx {
+                self.head[block] = next;
+            }
+        }
+        self.count[block] -= 1;
+    }
+
+    fn expand(&mut self, len: usize) {
+        let half = self.prev.len();
+        self.prev.resize(len, NO_CELL);
+        self.next.resize(len, NO_CELL);
+        self.head.resize(len / ROW_LEN, NO_CELL);
+        self.count.resize(len / ROW_LEN, 0);
+        for idx in half..len {
+            self.link(idx);
+        }
+    }
+}
+
+impl<T> Trie<T> {
+    // Vacancy is not stored. Build it at the first modification after deserialization.
+    fn sync_vacancy(&mut self) {
+        if self.vacancy.prev.len() != self.tree.len() {
+            self.vacancy = Vacancy::build(&self.tree);
+        }
+    }
+
+    // Every write which may turn a cell blank or not must pass here.
+    fn set(&mut self, idx: usize, node: Node) {
+        self.sync_vacancy();
+        match (is_blank(&self.tree[idx]), is_blank(&node)) {
+            (true, false) => self.vacancy.unlink(idx),
+            (false, true) => self.vacancy.link(idx),
+            _ => (),
+        }
+        self.tree[idx] = node;
+    }
+
     // To reallocate base and expand tree if need to do.
-    // FIXME bottleneck
-    fn reallocate_base(&mut self, target: &[bool; 256], cnt: u8) -> usize {
-        for block_idx in self.cache[cnt as usize]..self.capacities.len() {
-            if self.capacities[block_idx] >= cnt {
-                for innser_offset in 0..256 {
-                    let mut safe = true;
-                    let offset = (block_idx << 8) | innser_offset;
-                    for (target_idx, target) in target.iter().enumerate() {
-                        if *target
-                            && DecodedNode::Blank
-                                != Into::<DecodedNode>::into(self.tree[offset ^ target_idx])
-                        {
-                            safe = false;
-                            break;
-                        }
-                    }
-                    if safe {
-                        for i in (cnt as usize)..self.cache.len() {
-                            self.cache[i] = std::cmp::max(self.cache[i], block_idx);
-                        }
-                        return offset;
+    // Candidates are blank cells of blocks having cnt blank cells at least,
+    // placing the smallest target on them.
+    fn reallocate_base(&mut self, target: &[bool; 256], cnt: usize) -> usize {
+        self.sync_vacancy();
+        let mut labels = [0usize; ROW_LEN];
+        let mut len = 0;
+        for (label, target) in target.iter().enumerate() {
+            if *target {
+                labels[len] = label;
+                len += 1;
+            }
+        }
+        if len == 0 {
+            return 0;
+        }
+        let (first, rest) = (labels[0], &labels[1..len]);
+        for block_idx in self.vacancy.cache[cnt]..self.vacancy.count.len() {
+            if self.vacancy.count[block_idx] < cnt {
+                continue;
+            }
+            let head = self.vacancy.head[block_idx];
+            let mut cell = head;
+            loop {
+                let offset = cell ^ first;
+                if rest
+                    .iter()
+                    .all(|label| is_blank(&self.tree[offset ^ label]))
+                {
+                    for i in cnt..self.vacancy.cache.len() {
+                        self.vacancy.cache[i] = std::cmp::max(self.vacancy.cache[i], block_idx);
                     }
+                    return offset;
+                }
+                cell = self.vacancy.next[cell];
+                if cell == head {
+                    break;
                 }
             }
         }
         let half = self.tree.len();
         // expand tree
         self.tree.resize(half * 2, Node::blank());
-        self.capacities.resize(self.capacities.len() * 2, 255);
-        // search base straddling border of allocated area.
+        self.vacancy.expand(half * 2);
+        // every cell of new area is blank
         half
     }
 }
@@ -280,18 +840,27 @@ impl<T: Serialize> Trie<T> {
 #[cfg(test)]
 mod test_reallocate_base {
     use super::*;
+
+    fn with_tree(tree: Vec<Node>) -> Trie<String> {
+        Trie {
+            vacancy: Vacancy::default(),
+            tree,
+            arena: Arena::default(),
+            vacant: Vec::new(),
+            alphabet: Alphabet::default(),
+            tail: Vec::new(),
+            tails: Vec::new(),
+            dead: None,
+        }
+    }
+
     #[test]
     fn test_reallocate_base() {
         let mut mask = [false; 256];
         mask[0] = true;
         let mut tree = vec![Node::term(0, 0); 512];
         tree[6] = Node::blank();
-        let mut trie: Trie<String> = Trie {
-            cache: vec![0; 256],
-            capacities: vec![1, 0],
-            tree,
-            storage: Vec::new(),
-        };
+        let mut trie = with_tree(tree);
         assert_eq!(trie.reallocate_base(&mask, 1), 0 ^ 6);
 
         mask[0] = false;
@@ -300,34 +869,63 @@ mod test_reallocate_base {
 
         mask[47] = true;
         mask[99] = true;
-        trie.tree = vec![Node::blank(); 512];
-        trie.tree[47] = Node::term(0, 0);
-        trie.tree[1 ^ 99] = Node::term(0, 0);
-        trie.capacities = vec![253, 255];
-        assert_eq!(trie.reallocate_base(&mask, 2), 2);
+        let mut tree = vec![Node::blank(); 512];
+        tree[47] = Node::term(0, 0);
+        tree[1 ^ 99] = Node::term(0, 0);
+        // base 47 places 47 on 0 and 99 on 47 ^ 99
+        assert_eq!(with_tree(tree.clone()).reallocate_base(&mask, 2), 47);
+        tree[47 ^ 99] = Node::term(0, 0);
+        assert_eq!(with_tree(tree).reallocate_base(&mask, 2), 1 ^ 47);
 
         mask[47] = false;
         mask[99] = false;
         mask[0] = true;
-        trie.tree = vec![Node::term(0, 0); 512];
-        trie.tree[511] = Node::blank();
-        trie.capacities = vec![0, 1];
+        let mut tree = vec![Node::term(0, 0); 512];
+        tree[511] = Node::blank();
+        let mut trie = with_tree(tree);
         assert_eq!(trie.reallocate_base(&mask, 1), 511);
         assert_eq!(trie.tree.len(), 512);
 
-        trie.tree = vec![Node::term(0, 0); 512];
-        trie.capacities = vec![0, 0];
+        trie.set(511, Node::term(0, 0));
         assert_eq!(trie.reallocate_base(&mask, 1), 512);
         assert_eq!(trie.tree.len(), 1024);
+        assert_eq!(trie.vacancy.count, vec![0, 0, 256, 256]);
+    }
+
+    #[test]
+    fn test_vacancy() {
+        let mut trie = with_tree(vec![Node::blank(); 512]);
+        trie.set(3, Node::term(0, 0));
+        trie.set(300, Node::term(0, 0));
+        trie.set(3, Node::blank());
+        trie.set(4, Node::term(0, 0));
+        let blanks = |trie: &Trie<String>, block: usize| {
+            let head = trie.vacancy.head[block];
+            let mut cells = vec![head];
+            while trie.vacancy.next[*cells.last().unwrap()] != head {
+                cells.push(trie.vacancy.next[*cells.last().unwrap()]);
+            }
+            cells.sort();
+            cells
+        };
+        assert_eq!(
+            blanks(&trie, 0),
+            (0..256).filter(|idx| *idx != 4).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            blanks(&trie, 1),
+            (256..512).filter(|idx| *idx != 300).collect::<Vec<_>>()
+        );
+        assert_eq!(trie.vacancy.count, vec![255, 255]);
     }
 }
 
-impl<T: Serialize> Trie<T> {
+impl<T> Trie<T> {
     fn read_row(&self, parent_idx: usize) -> Row {
         let mut buf: Row = [Node::blank(); 256];
-        let base = self.tree[parent_idx].base;
+        let base = self.tree[parent_idx].base();
         for (i, buf_i) in buf.iter_mut().enumerate() {
-            if self.tree[base ^ i].check == parent_idx {
+            if self.tree[base ^ i].check() == parent_idx {
                 *buf_i = self.tree[base ^ i];
             }
         }
@@ -335,19 +933,19 @@ impl<T: Serialize> Trie<T> {
     }
 
     fn erase_row(&mut self, parent_idx: usize) {
-        let base = self.tree[parent_idx].base;
+        let base = self.tree[parent_idx].base();
         for i in 0..256 {
-            if self.tree[base ^ i].check == parent_idx {
-                self.tree[base ^ i] = Node::blank();
+            if self.tree[base ^ i].check() == parent_idx {
+                self.set(base ^ i, Node::blank());
             }
         }
     }
 
     fn count_children(&self, parent_idx: usize) -> usize {
         let mut cnt = 0usize;
-        let base = self.tree[parent_idx].base;
+        let base = self.tree[parent_idx].base();
         for i in 0..256 {
-            if self.tree[base ^ i].check == parent_idx {
+            if self.tree[base ^ i].check() == parent_idx {
                 cnt += 1;
             }
         }
@@ -366,10 +964,14 @@ mod test_read_erase_row {
         tree[2] = Node::term(0, 0);
         tree[64] = Node::term(1, 0);
         let trie: Trie<String> = Trie {
-            cache: vec![0; 256],
-            capacities: vec![251, 255],
+            vacancy: Vacancy::default(),
             tree,
-            storage: Vec::new(),
+            arena: Arena::default(),
+            vacant: Vec::new(),
+            alphabet: Alphabet::default(),
+            tail: Vec::new(),
+            tails: Vec::new(),
+            dead: None,
         };
 
         let row1 = trie.read_row(0).to_vec();
@@ -392,10 +994,14 @@ mod test_read_erase_row {
         tree[2] = Node::term(0, 0);
         tree[64] = Node::term(1, 0);
         let mut trie: Trie<String> = Trie {
-            cache: vec![0; 256],
-            capacities: vec![251, 255],
+            vacancy: Vacancy::default(),
             tree,
-            storage: Vec::new(),
+            arena: Arena::default(),
+            vacant: Vec::new(),
+            alphabet: Alphabet::default(),
+            tail: Vec::new(),
+            tails: Vec::new(),
+            dead: None,
         };
 
         trie.erase_row(0);
@@ -406,7 +1012,7 @@ mod test_read_erase_row {
     }
 }
 
-impl<T: Serialize> Trie<T> {
+impl<T> Trie<T> {
     // This function forcely overwrite tree
     // 存在しなかったのにrowに入っているとfromを誤認する
     fn paste(&mut self, row: Row, addition: Row, from: usize) -> usize {
@@ -414,34 +1020,42 @@ impl<T: Serialize> Trie<T> {
         let mut mask = [false; 256];
         let mut cnt = 0;
         for i in 0..256 {
-            if row[i].check != NO_PARENT
-                || row[i].base != NO_CHILD
-                || addition[i].check != NO_PARENT
-                || addition[i].base != NO_CHILD
+            if row[i].check() != NO_PARENT
+                || row[i].base() != NO_CHILD
+                || addition[i].check() != NO_PARENT
+                || addition[i].base() != NO_CHILD
             {
                 mask[i] = true;
                 cnt += 1;
             }
         }
         let to = self.reallocate_base(&mask, cnt);
-        self.capacities[to >> 8] -= cnt;
-        // place
+        // collect children before placement.
+        // a brother may be placed on the old cell of another brother.
+        let mut children = Vec::new();
         for (i, row) in row.iter().enumerate() {
-            if row.check != NO_PARENT {
-                // place bro
-                self.tree[to ^ i] = *row;
-                // update children's check
+            if row.check() != NO_PARENT && row.base() != NO_CHILD {
                 for j in 0..256 {
-                    if row.base != NO_CHILD && self.tree[row.base ^ j].check == from ^ i {
-                        self.tree[row.base ^ j].check = to ^ i;
+                    if self.tree[row.base() ^ j].check() == from ^ i {
+                        children.push((row.base() ^ j, to ^ i));
                     }
                 }
             }
         }
+        // place bro
+        for (i, row) in row.iter().enumerate() {
+            if row.check() != NO_PARENT {
+                self.set(to ^ i, *row);
+            }
+        }
+        // update children's check
+        for (child, parent) in children {
+            self.tree[child].set_check(parent);
+        }
         // additional placement without updation of children's check
         for (i, addition) in addition.iter().enumerate() {
-            if addition.check != NO_PARENT || addition.base != NO_CHILD {
-                self.tree[to ^ i] = *addition;
+            if addition.check() != NO_PARENT || addition.base() != NO_CHILD {
+                self.set(to ^ i, *addition);
             }
         }
         to
@@ -463,10 +1077,14 @@ mod test_paste {
         row[2] = Node::term(1, 0);
 
         let mut trie: Trie<String> = Trie {
-            cache: vec![0; 256],
-            capacities: vec![253, 255],
+            vacancy: Vacancy::default(),
             tree,
-            storage: Vec::new(),
+            arena: Arena::default(),
+            vacant: Vec::new(),
+            alphabet: Alphabet::default(),
+            tail: Vec::new(),
+            tails: Vec::new(),
+            dead: None,
         };
 
         assert_eq!(trie.paste(row, [Node::default(); 256], 4), 0);
@@ -481,10 +1099,14 @@ mod test_paste {
         let mut tree2 = vec![Node::blank(); 512];
         tree2[0] = Node::root(0);
         let mut trie2: Trie<String> = Trie {
-            cache: vec![0; 256],
-            capacities: vec![251, 255],
+            vacancy: Vacancy::default(),
             tree: tree2,
-            storage: Vec::new(),
+            arena: Arena::default(),
+            vacant: Vec::new(),
+            alphabet: Alphabet::default(),
+            tail: Vec::new(),
+            tails: Vec::new(),
+            dead: None,
         };
         let mut row = [Node::blank(); 256];
         row[0] = Node::sec(0, 0, None);
@@ -496,33 +1118,33 @@ mod test_paste {
     }
 }
 
-impl<T: Serialize> Trie<T> {
+impl<T> Trie<T> {
     fn insert_by_push_out(&mut self, target_idx: usize, parent_idx: usize) -> usize {
         let parent = self.tree[parent_idx];
         let target = self.tree[target_idx];
 
-        if target.check == NO_PARENT {
-            if target.base == NO_CHILD {
+        if target.check() == NO_PARENT {
+            if target.base() == NO_CHILD {
                 return target_idx;
             } else {
                 unreachable!();
             }
         }
 
-        let old_base = if parent.check < self.tree.len() {
-            self.tree[parent.check].base
+        let old_base = if parent.check() < self.tree.len() {
+            self.tree[parent.check()].base()
         } else {
             NO_CHILD
         };
-        let row = self.read_row(target.check);
-        self.erase_row(target.check);
+        let row = self.read_row(target.check());
+        self.erase_row(target.check());
         let parent_moved = self.tree[parent_idx] == Node::blank();
         // insert dummy
-        self.tree[target_idx] = Node::node(0, NO_CHILD, NO_ITEM);
+        self.set(target_idx, Node::node(0, NO_CHILD, NO_ITEM));
         // replace parent
-        let new_base = self.paste(row, [Node::blank(); 256], self.tree[target.check].base);
+        let new_base = self.paste(row, [Node::blank(); 256], self.tree[target.check()].base());
         // update parent of target
-        self.tree[target.check].base = new_base;
+        self.tree[target.check()].set_base(new_base);
         // if parent was included in target of push_out
         // 親のcheckが変わっただけでもここの判定に入ってしまう
         if parent_moved {
@@ -541,273 +1163,1856 @@ impl<T: Serialize> Trie<T> {
         let row = self.read_row(parent_idx);
         self.erase_row(parent_idx);
         let mut addition = [Node::blank(); 256];
-        addition[parent.base ^ target_idx] = Node::node(parent_idx, NO_CHILD, NO_ITEM);
-        let new_base = self.paste(row, addition, parent.base);
-        self.tree[parent_idx].base = new_base;
-        target_idx ^ parent.base ^ new_base
-    }
-}
-
-impl<T: Serialize> Trie<T> {
-    pub fn add(&mut self, way: &[u8], cargo: T) -> Result<(), ()> {
-        let mut parent_idx = 0;
-        for octet in way {
-            if self.tree[parent_idx].base == NO_CHILD {
-                self.tree[parent_idx].base = 0;
-            }
-            let child_idx = self.tree[parent_idx].base ^ (*octet as usize);
-            let child = self.tree[child_idx];
-            if child.check == NO_PARENT {
-                if child.base == NO_CHILD {
-                    self.tree[child_idx] = Node::node(parent_idx, NO_CHILD, NO_ITEM);
-                    parent_idx = child_idx;
-                }
-                // root case
-                else {
-                    parent_idx = self.insert_by_slide_brothers(child_idx, parent_idx);
-                }
-            } else if child.check == parent_idx {
-                parent_idx = child_idx;
+        addition[parent.base() ^ target_idx] = Node::node(parent_idx, NO_CHILD, NO_ITEM);
+        let new_base = self.paste(row, addition, parent.base());
+        self.tree[parent_idx].set_base(new_base);
+        target_idx ^ parent.base() ^ new_base
+    }
+}
+
+impl<T> Trie<T> {
+    // Append value to the values of key
+    pub fn insert(&mut self, way: &[u8], cargo: T) -> Result<(), InsertError> {
+        if way.is_empty() {
+            return Err(InsertError::EmptyKey);
+        }
+        // an insert places at most a row of nodes for each octet of way and the tail it splits
+        if !Sizes::of(self).grown(way.len() + 2, way.len()).fits() {
+            return Err(InsertError::Full);
+        }
+        let mut here = 0;
+        for (depth, octet) in way.iter().enumerate() {
+            let (child, created) = self.child_or_insert(here, *octet);
+            here = child;
+            let rest = &way[depth + 1..];
+            if created {
+                // the rest of key is the tail of the new leaf
+                let id = self.push_value(NO_ITEM, cargo);
+                self.set_tail(id, rest);
+                self.tree[here].set_id(id);
+                return Ok(());
+            }
+            let tail = tail(&self.tree, self.tails(), here);
+            if tail == rest {
+                break;
+            }
+            if !tail.is_empty() {
+                // nodes of the tail may be moved, so way is followed again
+                self.untail(here, rest);
+                return self.insert(way, cargo);
+            }
+        }
+        let id = self.push_value(self.tree[here].id(), cargo);
+        self.tree[here].set_id(id);
+        Ok(())
+    }
+
+    // Child of parent_idx by octet and whether it is made now.
+    // The parent may be moved to make room for the child.
+    fn child_or_insert(&mut self, parent_idx: usize, octet: u8) -> (usize, bool) {
+        if self.tree[parent_idx].base() == NO_CHILD {
+            self.tree[parent_idx].set_base(0);
+        }
+        let child_idx = self.tree[parent_idx].base() ^ self.alphabet.label(octet);
+        let child = self.tree[child_idx];
+        if child.check() == NO_PARENT {
+            if child.base() == NO_CHILD {
+                self.set(child_idx, Node::node(parent_idx, NO_CHILD, NO_ITEM));
+                (child_idx, true)
             }
-            // conflict case
+            // root case
             else {
-                let brother_num = self.count_children(parent_idx);
-                let stranger_num = self.count_children(child.check);
-                parent_idx = if brother_num > stranger_num {
-                    self.insert_by_push_out(child_idx, parent_idx)
-                } else {
-                    self.insert_by_slide_brothers(child_idx, parent_idx)
-                };
+                (self.insert_by_slide_brothers(child_idx, parent_idx), true)
             }
+        } else if child.check() == parent_idx {
+            (child_idx, false)
+        }
+        // conflict case
+        else {
+            let brother_num = self.count_children(parent_idx);
+            let stranger_num = self.count_children(child.check());
+            let idx = if brother_num > stranger_num {
+                self.insert_by_push_out(child_idx, parent_idx)
+            } else {
+                self.insert_by_slide_brothers(child_idx, parent_idx)
+            };
+            (idx, true)
+        }
+    }
+
+    // Move the tail of leaf idx into nodes as far as it is shared with rest and one octet more,
+    // so that rest can branch from it
+    fn untail(&mut self, idx: usize, rest: &[u8]) {
+        let id = self.tree[idx].id();
+        let span = self.tails[id];
+        let common = self.tail[span.range()]
+            .iter()
+            .zip(rest)
+            .take_while(|(octet, rest)| octet == rest)
+            .count();
+        let moved = std::cmp::min(common + 1, span.len as usize);
+        self.tree[idx].set_id(NO_ITEM);
+        let mut here = idx;
+        for pos in span.range().take(moved) {
+            here = self.child_or_insert(here, self.tail[pos]).0;
         }
-        self.tree[parent_idx].id = if self.tree[parent_idx].id == NO_ITEM {
-            self.storage.push(vec![cargo]);
-            self.storage.len() - 1
+        self.tails[id] = if moved == span.len as usize {
+            Span::default()
         } else {
-            self.storage[self.tree[parent_idx].id].push(cargo);
-            self.tree[parent_idx].id
+            Span::new(span.offset as usize + moved, span.len as usize - moved)
         };
-        Ok(())
+        self.tree[here].set_id(id);
+        self.drop_tail(moved);
     }
-}
 
-impl<T: Serialize> Trie<T> {
-    pub fn find(&self, way: &[u8]) -> Result<&[T], ()> {
-        match self.explore(way) {
-            Ok(idx) => match Into::<DecodedNode>::into(self.tree[idx]) {
-                DecodedNode::Blank => Err(()),
-                DecodedNode::Root(_) => Err(()),
-                DecodedNode::Term(_, id) => Ok(&self.storage[id][..]),
-                DecodedNode::Sec(_, _, Some(id)) => Ok(&self.storage[id][..]),
-                DecodedNode::Sec(_, _, None) => Err(()),
-            },
-            Err(_) => Err(()),
+    // Move every tail into nodes as tries of format version 1
+    #[cfg(test)]
+    pub(crate) fn expand_tails(&mut self) {
+        while let Some(idx) =
+            (0..self.tree.len()).find(|idx| !tail(&self.tree, self.tails(), *idx).is_empty())
+        {
+            self.untail(idx, &[]);
         }
+        self.tail.clear();
+        self.tails.clear();
     }
-}
 
-#[cfg(test)]
-mod test_add_find {
-    use super::*;
-    #[test]
-    fn test_add_find() {
-        let mut trie: Trie<String> = Trie::default();
-        trie.add(&[2, 1], String::from("21")).unwrap();
-        trie.add(&[1, 1], String::from("11")).unwrap();
-        trie.add(&[1, 2, 3], String::from("123")).unwrap();
-        trie.add(&[0], String::from("0")).unwrap();
-        trie.add(&[0, 0], String::from("00")).unwrap();
-        trie.add(&[1, 2], String::from("12")).unwrap();
-        trie.add(&[1, 2, 0], String::from("120")).unwrap();
-        trie.add(&[3, 1, 3], String::from("313")).unwrap();
-        trie.add(&[1, 6, 1], String::from("161")).unwrap();
-        trie.add(&[0, 1], String::from("01")).unwrap();
-        trie.add(&[2, 0], String::from("20")).unwrap();
-
-        assert_eq!(trie.find(&[0]), Ok(&[String::from("0")][..]));
-        assert_eq!(trie.find(&[0]), Ok(&[String::from("0")][..]));
-        assert_eq!(trie.find(&[0, 0]), Ok(&[String::from("00")][..]));
-        assert_eq!(trie.find(&[1, 2, 3]), Ok(&[String::from("123")][..]));
-        assert_eq!(trie.find(&[1, 2]), Ok(&[String::from("12")][..]));
-        assert_eq!(trie.find(&[1, 2, 0]), Ok(&[String::from("120")][..]));
-        assert_eq!(trie.find(&[3, 1, 3]), Ok(&[String::from("313")][..]));
-        assert_eq!(trie.find(&[1, 6, 1]), Ok(&[String::from("161")][..]));
-        assert_eq!(trie.find(&[0, 1]), Ok(&[String::from("01")][..]));
-        assert_eq!(trie.find(&[2, 0]), Ok(&[String::from("20")][..]));
-        assert_eq!(trie.find(&[2, 1]), Ok(&[String::from("21")][..]));
-        assert_eq!(trie.find(&[1]), Err(()));
-        assert_eq!(trie.find(&[7, 4]), Err(()));
+    fn set_tail(&mut self, id: usize, tail: &[u8]) {
+        if id < self.tails.len() && self.tails[id].len != 0 {
+            let dropped = self.tails[id].len as usize;
+            self.tails[id] = Span::default();
+            self.drop_tail(dropped);
+        }
+        if tail.is_empty() {
+            return;
+        }
+        if id >= self.tails.len() {
+            self.tails.resize(id + 1, Span::default());
+        }
+        self.tails[id] = Span::new(self.tail.len(), tail.len());
+        self.tail.extend_from_slice(tail);
     }
 
-    #[test]
-    fn test_add() {
-        let mut trie: Trie<String> = Trie::default();
-        trie.add("張り込め".as_bytes(), String::from("張り込め"))
-            .unwrap();
-        trie.add(
-            "ニッカーボッカー".as_bytes(),
-            String::from("ニッカーボッカー"),
-        )
-        .unwrap();
-        trie.add("証城寺".as_bytes(), String::from("証城寺"))
-            .unwrap();
-        trie.add("差し昇っ".as_bytes(), String::from("差し登っ"))
+    // Count bytes of tail no longer referred to, and compact the tail if they are the most
+    fn drop_tail(&mut self, dropped: usize) {
+        let dead = match self.dead {
+            Some(dead) => dead + dropped,
+            None => {
+                let live: usize = self.tails.iter().map(|span| span.len as usize).sum();
+                self.tail.len() - live
+            }
+        };
+        self.dead = Some(dead);
+        if 2 * dead > self.tail.len() {
+            let mut tail = Vec::with_capacity(self.tail.len() - dead);
+            for span in self.tails.iter_mut().filter(|span| span.len != 0) {
+                let range = span.range();
+                *span = Span::new(tail.len(), range.len());
+                tail.extend_from_slice(&self.tail[range]);
+            }
+            self.tail = tail;
+            self.dead = Some(0);
+        }
+    }
+
+    fn tails(&self) -> Values<'_, u8> {
+        Values::Spans(&self.tail, &self.tails)
+    }
+
+    // Append cargo to values of id and return id. A new id is given for NO_ITEM.
+    fn push_value(&mut self, id: usize, cargo: T) -> usize {
+        let id = if id == NO_ITEM {
+            match self.vacant.pop() {
+                Some(id) => id,
+                None => self.arena.add(),
+            }
+        } else {
+            id
+        };
+        self.arena.push(id, cargo);
+        id
+    }
+
+    fn values_of(&self, id: usize) -> &[T] {
+        self.arena.get(id)
+    }
+}
+
+impl<T> Trie<T> {
+    // The deepest node along way and its depth
+    fn descend(&self, way: &[u8]) -> (usize, usize) {
+        match self.explore(way) {
+            Ok(idx) => (idx, way.len()),
+            Err((depth, idx)) => (idx, depth),
+        }
+    }
+
+    // (idx, id) of the node of key holding values. The rest of key must be the tail of the node.
+    fn locate(&self, way: &[u8]) -> Option<(usize, usize)> {
+        let (idx, depth) = self.descend(way);
+        if tail(&self.tree, self.tails(), idx) != &way[depth..] {
+            return None;
+        }
+        match Into::<DecodedNode>::into(self.tree[idx]) {
+            DecodedNode::Term(_, id) if id != NO_ITEM => Some((idx, id)),
+            DecodedNode::Sec(_, _, Some(id)) => Some((idx, id)),
+            _ => None,
+        }
+    }
+
+    // values of key in the order of insertion
+    pub fn get(&self, way: &[u8]) -> Option<&[T]> {
+        self.locate(way).map(|(_, id)| self.values_of(id))
+    }
+
+    pub fn get_mut(&mut self, way: &[u8]) -> Option<&mut [T]> {
+        let (_, id) = self.locate(way)?;
+        Some(self.arena.get_mut(id))
+    }
+
+    pub fn contains_key(&self, way: &[u8]) -> bool {
+        self.locate(way).is_some()
+    }
+}
+
+impl<T> Trie<T> {
+    // Remove key and return its values
+    pub fn remove(&mut self, way: &[u8]) -> Option<Vec<T>> {
+        self.remove_value(way, |_| true)
+    }
+
+    // Remove values of key which satisfy predicate. The key is removed when no value is left.
+    pub fn remove_value<F: Fn(&T) -> bool>(&mut self, way: &[u8], predicate: F) -> Option<Vec<T>> {
+        let (idx, id) = self.locate(way)?;
+        let (removed, left): (Vec<T>, Vec<T>) = self
+            .arena
+            .take(id)
+            .into_iter()
+            .partition(|value| predicate(value));
+        if left.is_empty() {
+            self.vacant.push(id);
+            self.set_tail(id, &[]);
+            self.tree[idx].set_id(NO_ITEM);
+            self.prune(idx);
+        }
+        for value in left {
+            self.arena.push(id, value);
+        }
+        Some(removed)
+    }
+
+    // Free idx and its ancestors while they have neither value nor child
+    fn prune(&mut self, mut idx: usize) {
+        while idx != 0 && self.tree[idx].id() == NO_ITEM {
+            if self.tree[idx].base() != NO_CHILD && self.count_children(idx) > 0 {
+                break;
+            }
+            let parent = self.tree[idx].check();
+            self.set(idx, Node::blank());
+            if self.count_children(parent) == 0 && parent != 0 {
+                self.tree[parent].set_base(NO_CHILD);
+            }
+            idx = parent;
+        }
+    }
+}
+
+impl<T> Trie<T> {
+    // every key and its values in ascending order of key
+    pub fn iter(&self) -> Iter<'_, T> {
+        self.iter_from(vec![(0, Vec::new())])
+    }
+
+    // keys starting with prefix (including prefix itself) in ascending order
+    pub fn keys_with_prefix(&self, prefix: &[u8]) -> Iter<'_, T> {
+        let (here, depth) = self.descend(prefix);
+        // prefix may end in the tail of a leaf
+        if depth == prefix.len()
+            || tail(&self.tree, self.tails(), here).starts_with(&prefix[depth..])
+        {
+            self.iter_from(vec![(here, prefix[..depth].to_vec())])
+        } else {
+            self.iter_from(Vec::new())
+        }
+    }
+
+    fn iter_from(&self, stack: Vec<(usize, Vec<u8>)>) -> Iter<'_, T> {
+        self.view().iter_from(stack)
+    }
+
+    // number of keys
+    pub fn len(&self) -> usize {
+        self.arena.len() - self.vacant.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<'a, T> IntoIterator for &'a Trie<T> {
+    type Item = (Vec<u8>, &'a [T]);
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+impl<T> Trie<T> {
+    // Insert pairs in order, stopping at the first error such as an empty key
+    pub fn try_extend<K, I>(&mut self, iter: I) -> Result<(), InsertError>
+    where
+        K: AsRef<[u8]>,
+        I: IntoIterator<Item = (K, T)>,
+    {
+        for (key, value) in iter {
+            self.insert(key.as_ref(), value)?;
+        }
+        Ok(())
+    }
+
+    // Sorted and passed to Builder. Values of the same key keep their order.
+    pub fn try_from_iter<K, I>(iter: I) -> Result<Self, BuildError>
+    where
+        K: AsRef<[u8]>,
+        I: IntoIterator<Item = (K, T)>,
+    {
+        let mut pairs: Vec<(K, T)> = iter.into_iter().collect();
+        Trie::sort_dict(&mut pairs);
+        let mut builder = Builder::new();
+        for (key, value) in pairs {
+            builder.push(key.as_ref(), value)?;
+        }
+        Ok(builder.build())
+    }
+}
+
+// depth first search over the double array
+pub struct Iter<'a, T> {
+    tree: &'a [Node],
+    values: Values<'a, T>,
+    tails: Values<'a, u8>,
+    codes: &'a [u8],
+    // nodes to visit and their keys. the top has the smallest key.
+    stack: Vec<(usize, Vec<u8>)>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = (Vec<u8>, &'a [T]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((here, key)) = self.stack.pop() {
+            let base = self.tree[here].base();
+            if base != NO_CHILD {
+                // pushed in reverse to pop smaller octets first
+                for octet in (0..=255u8).rev() {
+                    let child = base ^ label(self.codes, octet);
+                    if child < self.tree.len() && self.tree[child].check() == here {
+                        let mut child_key = key.clone();
+                        child_key.push(octet);
+                        self.stack.push((child, child_key));
+                    }
+                }
+            }
+            match Into::<DecodedNode>::into(self.tree[here]) {
+                DecodedNode::Term(_, id) if id != NO_ITEM => {
+                    let mut key = key;
+                    key.extend_from_slice(self.tails.get_or_empty(id));
+                    return Some((key, self.values.get(id)));
+                }
+                DecodedNode::Sec(_, _, Some(id)) => return Some((key, self.values.get(id))),
+                _ => (),
+            }
+        }
+        None
+    }
+}
+
+// Tail of the key of leaf idx, empty for the other nodes
+fn tail<'a>(tree: &[Node], tails: Values<'a, u8>, idx: usize) -> &'a [u8] {
+    match Into::<DecodedNode>::into(tree[idx]) {
+        DecodedNode::Term(_, id) if id != NO_ITEM => tails.get_or_empty(id),
+        _ => &[],
+    }
+}
+
+impl<T> Trie<T> {
+    // every prefix of way stored in the trie as (length of prefix, values), shortest first
+    pub fn common_prefix_search<'a, 'b>(&'a self, way: &'b [u8]) -> CommonPrefixSearch<'a, 'b, T> {
+        self.view().common_prefix_search(way)
+    }
+
+    // read-only access shared with mapped dictionaries
+    pub fn view(&self) -> TrieView<'_, T> {
+        TrieView {
+            tree: &self.tree,
+            values: self.arena.values(),
+            tails: self.tails(),
+            codes: &self.alphabet.codes,
+        }
+    }
+
+    // transition table, values and tail of each id (up to the last tail)
+    // and labels of bytes (empty for the identity)
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn raw_parts(&self) -> (&[Node], Vec<&[T]>, Vec<&[u8]>, &[u8]) {
+        let values = (0..self.arena.len()).map(|id| self.values_of(id)).collect();
+        let tails = (0..self.tails.len())
+            .map(|id| self.tails().get(id))
+            .collect();
+        (&self.tree, values, tails, &self.alphabet.codes)
+    }
+}
+
+// Read-only trie over borrowed memory, either of a Trie or of a mapped dictionary
+pub struct TrieView<'a, T> {
+    tree: &'a [Node],
+    values: Values<'a, T>,
+    tails: Values<'a, u8>,
+    codes: &'a [u8],
+}
+
+// derive would require T: Copy
+impl<'a, T> Clone for TrieView<'a, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, T> Copy for TrieView<'a, T> {}
+
+impl<'a, T> TrieView<'a, T> {
+    // Values of id are values[ranges[id]..ranges[id + 1]], and so is its tail of tail and tails.
+    pub(crate) fn new(
+        tree: &'a [Node],
+        values: &'a [T],
+        ranges: &'a [u32],
+        tail: &'a [u8],
+        tails: &'a [u32],
+        codes: &'a [u8],
+    ) -> Self {
+        TrieView {
+            tree,
+            values: Values::Flat(values, ranges),
+            tails: Values::Flat(tail, tails),
+            codes,
+        }
+    }
+
+    // every key and its values in ascending order of key
+    pub fn iter(&self) -> Iter<'a, T> {
+        self.iter_from(vec![(0, Vec::new())])
+    }
+
+    fn iter_from(&self, stack: Vec<(usize, Vec<u8>)>) -> Iter<'a, T> {
+        Iter {
+            tree: self.tree,
+            values: self.values,
+            tails: self.tails,
+            codes: self.codes,
+            stack,
+        }
+    }
+
+    // every prefix of way stored in the trie as (length of prefix, values), shortest first
+    pub fn common_prefix_search<'b>(&self, way: &'b [u8]) -> CommonPrefixSearch<'a, 'b, T> {
+        CommonPrefixSearch {
+            tree: self.tree,
+            values: self.values,
+            tails: self.tails,
+            codes: self.codes,
+            way,
+            here: 0,
+            depth: 0,
+        }
+    }
+
+    // Keys accepted by automaton with their final states in ascending order of key
+    pub fn search<A: Automaton>(&self, automaton: A) -> Search<'a, T, A> {
+        let root = Walk {
+            key: Vec::new(),
+            complete: 0,
+            state: automaton.start(),
+        };
+        Search {
+            tree: self.tree,
+            values: self.values,
+            tails: self.tails,
+            codes: self.codes,
+            automaton,
+            stack: vec![(0, root)],
+        }
+    }
+}
+
+// Whether base, check and id of every node stay within tree and ids, and so walking tree
+// never indexes out of it. Checked on nodes read from a file, which verify relies on.
+pub(crate) fn in_range(tree: &[Node], ids: usize) -> bool {
+    tree.len().is_multiple_of(ROW_LEN)
+        && tree.iter().all(|node| {
+            let check = node.check();
+            let base = node.base();
+            let id = node.id();
+            (check == NO_PARENT || check < tree.len())
+                && (base == NO_CHILD || base < tree.len())
+                // id of root and blank cells is not read
+                && (check == NO_PARENT || id == NO_ITEM || id < ids)
+        })
+}
+
+enum Values<'a, T> {
+    Spans(&'a [T], &'a [Span]),
+    Flat(&'a [T], &'a [u32]),
+    Nested(&'a [Vec<T>]),
+}
+
+// derive would require T: Copy
+impl<'a, T> Clone for Values<'a, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, T> Copy for Values<'a, T> {}
+
+impl<'a, T> Values<'a, T> {
+    fn get(&self, id: usize) -> &'a [T] {
+        match *self {
+            Values::Spans(values, spans) => &values[spans[id].range()],
+            Values::Flat(values, ranges) => &values[ranges[id] as usize..ranges[id + 1] as usize],
+            Values::Nested(nested) => &nested[id],
+        }
+    }
+
+    // number of ids
+    fn len(&self) -> usize {
+        match *self {
+            Values::Spans(_, spans) => spans.len(),
+            Values::Flat(_, ranges) => ranges.len().saturating_sub(1),
+            Values::Nested(nested) => nested.len(),
+        }
+    }
+
+    // number of values, including ones no id refers to
+    fn count(&self) -> usize {
+        match *self {
+            Values::Spans(values, _) | Values::Flat(values, _) => values.len(),
+            Values::Nested(nested) => nested.iter().map(Vec::len).sum(),
+        }
+    }
+
+    // values, spans or ranges, and vectors
+    fn bytes(&self) -> usize {
+        let ids = match *self {
+            Values::Spans(_, spans) => std::mem::size_of_val(spans),
+            Values::Flat(_, ranges) => std::mem::size_of_val(ranges),
+            Values::Nested(nested) => std::mem::size_of_val(nested),
+        };
+        ids + std::mem::size_of::<T>() * self.count()
+    }
+
+    // for tails, which are cut off after the last key with a tail
+    fn get_or_empty(&self, id: usize) -> &'a [T] {
+        if id < self.len() {
+            self.get(id)
+        } else {
+            &[]
+        }
+    }
+}
+
+pub struct CommonPrefixSearch<'a, 'b, T> {
+    tree: &'a [Node],
+    values: Values<'a, T>,
+    tails: Values<'a, u8>,
+    codes: &'a [u8],
+    way: &'b [u8],
+    here: usize,
+    depth: usize,
+}
+
+impl<'a, 'b, T> Iterator for CommonPrefixSearch<'a, 'b, T> {
+    type Item = (usize, &'a [T]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.depth < self.way.len() {
+            let base = self.tree[self.here].base();
+            if base == NO_CHILD {
+                self.depth = self.way.len();
+                return None;
+            }
+            let next = base ^ label(self.codes, self.way[self.depth]);
+            if next >= self.tree.len() || self.tree[next].check() != self.here {
+                self.depth = self.way.len();
+                return None;
+            }
+            self.here = next;
+            self.depth += 1;
+            match Into::<DecodedNode>::into(self.tree[next]) {
+                // a leaf, whose tail must follow in way
+                DecodedNode::Term(_, id) if id != NO_ITEM => {
+                    let tail = self.tails.get_or_empty(id);
+                    let depth = self.depth;
+                    self.depth = self.way.len();
+                    if self.way[depth..].starts_with(tail) {
+                        return Some((depth + tail.len(), self.values.get(id)));
+                    }
+                    return None;
+                }
+                DecodedNode::Sec(_, _, Some(id)) => return Some((self.depth, self.values.get(id))),
+                _ => (),
+            }
+        }
+        None
+    }
+}
+
+// Automaton run over the UTF-8 characters of keys by Trie::search. A byte which does not
+// form a character of a key is given as None.
+pub trait Automaton {
+    type State: Clone;
+
+    fn start(&self) -> Self::State;
+
+    fn step(&self, state: &Self::State, ch: Option<char>) -> Self::State;
+
+    fn is_match(&self, state: &Self::State) -> bool;
+
+    // false if no key continuing from state can match, which prunes the search
+    fn can_match(&self, state: &Self::State) -> bool;
+}
+
+impl<A: Automaton> Automaton for &A {
+    type State = A::State;
+
+    fn start(&self) -> Self::State {
+        (*self).start()
+    }
+
+    fn step(&self, state: &Self::State, ch: Option<char>) -> Self::State {
+        (*self).step(state, ch)
+    }
+
+    fn is_match(&self, state: &Self::State) -> bool {
+        (*self).is_match(state)
+    }
+
+    fn can_match(&self, state: &Self::State) -> bool {
+        (*self).can_match(state)
+    }
+}
+
+impl<T> Trie<T> {
+    // Keys accepted by automaton with their final states in ascending order of key
+    pub fn search<A: Automaton>(&self, automaton: A) -> Search<'_, T, A> {
+        self.view().search(automaton)
+    }
+
+    // Keys within Levenshtein distance of query, counted in UTF-8 characters, and their
+    // distances in ascending order of key. A byte which does not form a character of a key
+    // is a character unequal to any of query.
+    pub fn fuzzy_search(&self, query: &str, distance: usize) -> FuzzySearch<'_, T> {
+        FuzzySearch(self.search(Levenshtein {
+            query: query.chars().collect(),
+            distance,
+        }))
+    }
+}
+
+// Prefix of a key and the state of an automaton after its complete characters
+#[derive(Clone)]
+struct Walk<S> {
+    key: Vec<u8>,
+    // length of the characters of key consumed by state
+    complete: usize,
+    state: S,
+}
+
+// length of the UTF-8 character led by octet. a broken one is a character of its own.
+fn char_len(octet: u8) -> usize {
+    match octet {
+        0xC0..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF7 => 4,
+        _ => 1,
+    }
+}
+
+impl<S: Clone> Walk<S> {
+    fn push<A: Automaton<State = S>>(&mut self, octet: u8, automaton: &A) {
+        self.key.push(octet);
+        let pending = &self.key[self.complete..];
+        if pending.len() == char_len(pending[0]) {
+            let ch = std::str::from_utf8(pending)
+                .ok()
+                .and_then(|chars| chars.chars().next());
+            self.advance(ch, automaton);
+        }
+    }
+
+    fn advance<A: Automaton<State = S>>(&mut self, ch: Option<char>, automaton: &A) {
+        self.state = automaton.step(&self.state, ch);
+        self.complete = self.key.len();
+    }
+
+    // state of key, whose broken character at the end is given as one
+    fn finish<A: Automaton<State = S>>(&mut self, automaton: &A) {
+        if self.complete < self.key.len() {
+            self.advance(None, automaton);
+        }
+    }
+}
+
+// depth first search pruned by the automaton
+pub struct Search<'a, T, A: Automaton> {
+    tree: &'a [Node],
+    values: Values<'a, T>,
+    tails: Values<'a, u8>,
+    codes: &'a [u8],
+    automaton: A,
+    // nodes to visit. the top has the smallest key.
+    stack: Vec<(usize, Walk<A::State>)>,
+}
+
+impl<'a, T, A: Automaton> Iterator for Search<'a, T, A> {
+    type Item = (Vec<u8>, A::State, &'a [T]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        'nodes: while let Some((here, mut walk)) = self.stack.pop() {
+            let base = self.tree[here].base();
+            if base != NO_CHILD {
+                // pushed in reverse to pop smaller octets first
+                for octet in (0..=255u8).rev() {
+                    let child = base ^ label(self.codes, octet);
+                    if child < self.tree.len() && self.tree[child].check() == here {
+                        let mut child_walk = walk.clone();
+                        child_walk.push(octet, &self.automaton);
+                        if self.automaton.can_match(&child_walk.state) {
+                            self.stack.push((child, child_walk));
+                        }
+                    }
+                }
+            }
+            let id = match Into::<DecodedNode>::into(self.tree[here]) {
+                DecodedNode::Term(_, id) if id != NO_ITEM => id,
+                DecodedNode::Sec(_, _, Some(id)) => id,
+                _ => continue,
+            };
+            for octet in self.tails.get_or_empty(id) {
+                walk.push(*octet, &self.automaton);
+                if !self.automaton.can_match(&walk.state) {
+                    continue 'nodes;
+                }
+            }
+            walk.finish(&self.automaton);
+            if self.automaton.is_match(&walk.state) {
+                return Some((walk.key, walk.state, self.values.get(id)));
+            }
+        }
+        None
+    }
+}
+
+// Levenshtein automaton whose state is a row of the edit distance table, where row[j] is the
+// distance between the characters read and the first j characters of query
+struct Levenshtein {
+    query: Vec<char>,
+    distance: usize,
+}
+
+impl Automaton for Levenshtein {
+    type State = Vec<usize>;
+
+    fn start(&self) -> Vec<usize> {
+        (0..=self.query.len()).collect()
+    }
+
+    fn step(&self, prev: &Vec<usize>, ch: Option<char>) -> Vec<usize> {
+        let mut row = Vec::with_capacity(prev.len());
+        row.push(prev[0] + 1);
+        for (j, query_ch) in self.query.iter().enumerate() {
+            let replace = prev[j] + if ch == Some(*query_ch) { 0 } else { 1 };
+            let cost = replace.min(prev[j + 1] + 1).min(row[j] + 1);
+            row.push(cost);
+        }
+        row
+    }
+
+    fn is_match(&self, row: &Vec<usize>) -> bool {
+        *row.last().unwrap() <= self.distance
+    }
+
+    // no key below is closer than the minimum of row
+    fn can_match(&self, row: &Vec<usize>) -> bool {
+        *row.iter().min().unwrap() <= self.distance
+    }
+}
+
+pub struct FuzzySearch<'a, T>(Search<'a, T, Levenshtein>);
+
+impl<'a, T> Iterator for FuzzySearch<'a, T> {
+    type Item = (Vec<u8>, usize, &'a [T]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0
+            .next()
+            .map(|(key, row, values)| (key, *row.last().unwrap(), values))
+    }
+}
+
+#[cfg(test)]
+mod test_add_find {
+    use super::*;
+    #[test]
+    fn test_add_find() {
+        let mut trie: Trie<String> = Trie::default();
+        trie.insert(&[2, 1], String::from("21")).unwrap();
+        trie.insert(&[1, 1], String::from("11")).unwrap();
+        trie.insert(&[1, 2, 3], String::from("123")).unwrap();
+        trie.insert(&[0], String::from("0")).unwrap();
+        trie.insert(&[0, 0], String::from("00")).unwrap();
+        trie.insert(&[1, 2], String::from("12")).unwrap();
+        trie.insert(&[1, 2, 0], String::from("120")).unwrap();
+        trie.insert(&[3, 1, 3], String::from("313")).unwrap();
+        trie.insert(&[1, 6, 1], String::from("161")).unwrap();
+        trie.insert(&[0, 1], String::from("01")).unwrap();
+        trie.insert(&[2, 0], String::from("20")).unwrap();
+
+        assert_eq!(trie.get(&[0]), Some(&[String::from("0")][..]));
+        assert_eq!(trie.get(&[0]), Some(&[String::from("0")][..]));
+        assert_eq!(trie.get(&[0, 0]), Some(&[String::from("00")][..]));
+        assert_eq!(trie.get(&[1, 2, 3]), Some(&[String::from("123")][..]));
+        assert_eq!(trie.get(&[1, 2]), Some(&[String::from("12")][..]));
+        assert_eq!(trie.get(&[1, 2, 0]), Some(&[String::from("120")][..]));
+        assert_eq!(trie.get(&[3, 1, 3]), Some(&[String::from("313")][..]));
+        assert_eq!(trie.get(&[1, 6, 1]), Some(&[String::from("161")][..]));
+        assert_eq!(trie.get(&[0, 1]), Some(&[String::from("01")][..]));
+        assert_eq!(trie.get(&[2, 0]), Some(&[String::from("20")][..]));
+        assert_eq!(trie.get(&[2, 1]), Some(&[String::from("21")][..]));
+        assert_eq!(trie.get(&[1]), None);
+        assert_eq!(trie.get(&[7, 4]), None);
+    }
+
+    #[test]
+    fn test_add() {
+        let mut trie: Trie<String> = Trie::default();
+        trie.insert("張り込め".as_bytes(), String::from("張り込め"))
+            .unwrap();
+        trie.insert(
+            "ニッカーボッカー".as_bytes(),
+            String::from("ニッカーボッカー"),
+        )
+        .unwrap();
+        trie.insert("証城寺".as_bytes(), String::from("証城寺"))
             .unwrap();
-        trie.add("抜け出せれ".as_bytes(), String::from("抜け出せれ"))
+        trie.insert("差し昇っ".as_bytes(), String::from("差し登っ"))
             .unwrap();
-        trie.add("たい".as_bytes(), String::from("たい")).unwrap();
-        trie.add("アオガエル".as_bytes(), String::from("アオガエル"))
+        trie.insert("抜け出せれ".as_bytes(), String::from("抜け出せれ"))
             .unwrap();
-        trie.add("長府浜浦".as_bytes(), String::from("長府浜浦"))
+        trie.insert("たい".as_bytes(), String::from("たい"))
             .unwrap();
-        trie.add("中佃".as_bytes(), String::from("中佃")).unwrap();
-        trie.add("幻視".as_bytes(), String::from("幻視")).unwrap();
-        trie.add("小船木".as_bytes(), String::from("小船木"))
+        trie.insert("アオガエル".as_bytes(), String::from("アオガエル"))
             .unwrap();
-        trie.add("浅黒かれ".as_bytes(), String::from("浅黒かれ"))
+        trie.insert("長府浜浦".as_bytes(), String::from("長府浜浦"))
             .unwrap();
-        trie.add("扁かろ".as_bytes(), String::from("扁かろ"))
+        trie.insert("中佃".as_bytes(), String::from("中佃"))
             .unwrap();
-        trie.add("咲き乱れ".as_bytes(), String::from("咲き乱れ"))
+        trie.insert("幻視".as_bytes(), String::from("幻視"))
+            .unwrap();
+        trie.insert("小船木".as_bytes(), String::from("小船木"))
+            .unwrap();
+        trie.insert("浅黒かれ".as_bytes(), String::from("浅黒かれ"))
+            .unwrap();
+        trie.insert("扁かろ".as_bytes(), String::from("扁かろ"))
+            .unwrap();
+        trie.insert("咲き乱れ".as_bytes(), String::from("咲き乱れ"))
             .unwrap();
 
         assert_eq!(
-            trie.find("張り込め".as_bytes()),
-            Ok(&[String::from("張り込め")][..])
+            trie.get("張り込め".as_bytes()),
+            Some(&[String::from("張り込め")][..])
+        );
+        assert_eq!(
+            trie.get("ニッカーボッカー".as_bytes()),
+            Some(&[String::from("ニッカーボッカー")][..])
+        );
+        assert_eq!(
+            trie.get("証城寺".as_bytes()),
+            Some(&[String::from("証城寺")][..])
+        );
+        assert_eq!(
+            trie.get("差し昇っ".as_bytes()),
+            Some(&[String::from("差し登っ")][..])
+        );
+        assert_eq!(
+            trie.get("抜け出せれ".as_bytes()),
+            Some(&[String::from("抜け出せれ")][..])
+        );
+        assert_eq!(
+            trie.get("たい".as_bytes()),
+            Some(&[String::from("たい")][..])
+        );
+        assert_eq!(
+            trie.get("アオガエル".as_bytes()),
+            Some(&[String::from("アオガエル")][..])
+        );
+        assert_eq!(
+            trie.get("長府浜浦".as_bytes()),
+            Some(&[String::from("長府浜浦")][..])
+        );
+        assert_eq!(
+            trie.get("中佃".as_bytes()),
+            Some(&[String::from("中佃")][..])
+        );
+        assert_eq!(
+            trie.get("幻視".as_bytes()),
+            Some(&[String::from("幻視")][..])
+        );
+        assert_eq!(
+            trie.get("小船木".as_bytes()),
+            Some(&[String::from("小船木")][..])
+        );
+        assert_eq!(
+            trie.get("浅黒かれ".as_bytes()),
+            Some(&[String::from("浅黒かれ")][..])
         );
         assert_eq!(
-            trie.find("ニッカーボッカー".as_bytes()),
-            Ok(&[String::from("ニッカーボッカー")][..])
+            trie.get("扁かろ".as_bytes()),
+            Some(&[String::from("扁かろ")][..])
         );
         assert_eq!(
-            trie.find("証城寺".as_bytes()),
-            Ok(&[String::from("証城寺")][..])
+            trie.get("咲き乱れ".as_bytes()),
+            Some(&[String::from("咲き乱れ")][..])
         );
+    }
+}
+
+#[cfg(test)]
+mod test_remove {
+    use super::*;
+    use crate::testutil::xorshift;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_remove() {
+        let mut trie: Trie<String> = Trie::default();
+        trie.insert(&[1, 2], String::from("12")).unwrap();
+        trie.insert(&[1, 2, 3], String::from("123")).unwrap();
+        trie.insert(&[1, 2, 3], String::from("123'")).unwrap();
+        assert_eq!(trie.remove(&[1]), None);
+        assert_eq!(trie.remove(&[1, 2]), Some(vec![String::from("12")]));
+        assert_eq!(trie.get(&[1, 2]), None);
+        assert_eq!(
+            trie.remove_value(&[1, 2, 3], |value| value.ends_with('\'')),
+            Some(vec![String::from("123'")])
+        );
+        assert_eq!(trie.get(&[1, 2, 3]), Some(&[String::from("123")][..]));
+        assert_eq!(trie.remove(&[1, 2, 3]), Some(vec![String::from("123")]));
+        assert_eq!(trie.remove(&[1, 2, 3]), None);
+        assert!(trie.is_empty());
+        // every node except root is freed
+        assert_eq!(
+            trie.tree
+                .iter()
+                .filter(|node| **node != Node::blank())
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_arena() {
+        let mut trie: Trie<u32> = Trie::default();
+        trie.insert(&[1], 0).unwrap();
+        trie.insert(&[2], 1).unwrap();
+        trie.insert(&[2], 2).unwrap();
+        trie.remove(&[2]).unwrap();
+        trie.insert(&[3], 3).unwrap();
+        // values at the end are changed in place
+        assert!(matches!(trie.arena, Arena::Flat(..)));
+        trie.insert(&[1], 4).unwrap();
+        assert!(matches!(trie.arena, Arena::Nested(..)));
+        trie.remove_value(&[1], |value| *value == 0).unwrap();
+        assert_eq!(trie.verify(), Ok(()));
+        let stored: Trie<u32> = bincode::deserialize(&bincode::serialize(&trie).unwrap()).unwrap();
+        assert!(matches!(stored.arena, Arena::Flat(..)));
+        assert_eq!(stored.verify(), Ok(()));
+        assert_eq!(stored.get(&[1]), Some(&[4][..]));
+        assert_eq!(stored.get(&[3]), Some(&[3][..]));
+        assert!(stored.iter().eq(trie.iter()));
+
+        let span = Span {
+            offset: u32::MAX - 1,
+            len: 2,
+        };
+        assert_eq!(span.range().end, u32::MAX as usize + 1);
+    }
+
+    #[test]
+    fn test_model() {
+        let mut trie: Trie<u32> = Trie::default();
+        let mut model: BTreeMap<Vec<u8>, Vec<u32>> = BTreeMap::new();
+        let mut rand = xorshift(0x2545_f491_4f6c_dd1d);
+        for step in 0..20000u32 {
+            let len = 1 + rand(4) as usize;
+            // small alphabet for shared prefixes and a few octets far apart
+            let key: Vec<u8> = (0..len)
+                .map(|_| [0u8, 1, 2, 3, 127, 128, 254, 255][rand(8) as usize])
+                .collect();
+            match rand(3) {
+                0 => {
+                    trie.insert(&key, step).unwrap();
+                    model.entry(key.clone()).or_default().push(step);
+                }
+                1 => {
+                    assert_eq!(trie.remove(&key), model.remove(&key));
+                }
+                _ => {
+                    let expected = match model.get_mut(&key) {
+                        Some(values) => {
+                            let (removed, left) = values.iter().partition(|v| *v % 2 == 0);
+                            *values = left;
+                            if values.is_empty() {
+                                model.remove(&key);
+                            }
+                            Some(removed)
+                        }
+                        None => None,
+                    };
+                    assert_eq!(trie.remove_value(&key, |v| v % 2 == 0), expected);
+                }
+            }
+            assert_eq!(trie.get(&key), model.get(&key).map(|values| &values[..]));
+            if step % 500 == 0 {
+                assert_eq!(trie.verify(), Ok(()));
+            }
+        }
+        assert_eq!(trie.verify(), Ok(()));
+        assert_eq!(trie.len(), model.len());
+        // blank cells are tracked through every modification
+        assert_eq!(trie.vacancy.count, Vacancy::build(&trie.tree).count);
+        assert!(trie
+            .iter()
+            .map(|(key, values)| (key, values.to_vec()))
+            .eq(model.clone().into_iter()));
+
+        for key in model.keys() {
+            trie.remove(key).unwrap();
+        }
+        assert!(trie.is_empty());
         assert_eq!(
-            trie.find("差し昇っ".as_bytes()),
-            Ok(&[String::from("差し登っ")][..])
+            trie.tree
+                .iter()
+                .filter(|node| **node != Node::blank())
+                .count(),
+            1
         );
+    }
+}
+
+#[cfg(test)]
+mod test_iter {
+    use super::*;
+
+    fn trie() -> Trie<String> {
+        let mut trie: Trie<String> = Trie::default();
+        trie.insert(&[2, 1], String::from("21")).unwrap();
+        trie.insert(&[1, 2, 3], String::from("123")).unwrap();
+        trie.insert(&[0], String::from("0")).unwrap();
+        trie.insert(&[1, 2], String::from("12")).unwrap();
+        trie.insert(&[1, 2], String::from("12'")).unwrap();
+        trie.insert(&[1, 3], String::from("13")).unwrap();
+        trie.insert(&[255, 0], String::from("2550")).unwrap();
+        trie
+    }
+
+    #[test]
+    fn test_iter() {
+        let trie = trie();
         assert_eq!(
-            trie.find("抜け出せれ".as_bytes()),
-            Ok(&[String::from("抜け出せれ")][..])
+            trie.iter().collect::<Vec<_>>(),
+            vec![
+                (vec![0], &[String::from("0")][..]),
+                (vec![1, 2], &[String::from("12"), String::from("12'")][..]),
+                (vec![1, 2, 3], &[String::from("123")][..]),
+                (vec![1, 3], &[String::from("13")][..]),
+                (vec![2, 1], &[String::from("21")][..]),
+                (vec![255, 0], &[String::from("2550")][..]),
+            ]
         );
+        assert_eq!(trie.len(), 6);
+        let empty = Trie::<String>::default();
+        assert_eq!(empty.iter().count(), 0);
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn test_keys_with_prefix() {
+        let trie = trie();
+        let keys = |prefix: &[u8]| {
+            trie.keys_with_prefix(prefix)
+                .map(|(key, _)| key)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(keys(&[1]), vec![vec![1, 2], vec![1, 2, 3], vec![1, 3]]);
+        assert_eq!(keys(&[1, 2]), vec![vec![1, 2], vec![1, 2, 3]]);
+        assert_eq!(keys(&[1, 2, 3, 4]), Vec::<Vec<u8>>::new());
+        assert_eq!(keys(&[3]), Vec::<Vec<u8>>::new());
+        assert_eq!(keys(&[]).len(), 6);
+    }
+}
+
+#[cfg(test)]
+mod test_map {
+    use super::*;
+
+    #[test]
+    fn test_map() {
+        let mut trie = Trie::new();
+        assert_eq!(trie.insert(b"", 0), Err(InsertError::EmptyKey));
+        assert!(trie.is_empty());
+        trie.insert(b"ab", 1).unwrap();
+        trie.insert(b"ab", 2).unwrap();
+        trie.insert(b"a", 3).unwrap();
+        assert!(trie.contains_key(b"a"));
+        assert!(!trie.contains_key(b"b"));
+        assert_eq!(trie.get(b"ab"), Some(&[1, 2][..]));
+        trie.get_mut(b"ab").unwrap()[1] = 4;
+        assert_eq!(trie.get(b"ab"), Some(&[1, 4][..]));
+        assert_eq!(trie.get_mut(b"abc"), None);
+        assert_eq!(trie.remove(b"a"), Some(vec![3]));
+        assert_eq!(trie.remove(b"a"), None);
+
+        trie.try_extend(vec![("b", 5), ("ab", 6)]).unwrap();
+        let collected = Trie::try_from_iter(vec![("ab", 1), ("b", 5), ("ab", 4), ("ab", 6)]);
+        assert!((&trie).into_iter().eq(collected.unwrap().iter()));
+        let mut builder = Trie::builder();
+        builder.push(b"ab", 1).unwrap();
+        assert_eq!(builder.build().get(b"ab"), Some(&[1][..]));
+    }
+
+    #[test]
+    fn test_empty_key() {
+        let mut trie = Trie::new();
         assert_eq!(
-            trie.find("たい".as_bytes()),
-            Ok(&[String::from("たい")][..])
+            trie.try_extend(vec![("a", 0), ("", 1), ("b", 2)]),
+            Err(InsertError::EmptyKey)
         );
+        // pairs before the empty key are inserted
+        assert_eq!(trie.len(), 1);
         assert_eq!(
-            trie.find("アオガエル".as_bytes()),
-            Ok(&[String::from("アオガエル")][..])
+            Trie::try_from_iter(vec![("a", 0), ("", 1)]).err(),
+            Some(BuildError::EmptyKey)
         );
+    }
+}
+
+#[cfg(test)]
+mod test_common_prefix_search {
+    use super::*;
+    #[test]
+    fn test_common_prefix_search() {
+        let mut trie: Trie<String> = Trie::default();
+        trie.insert(&[1], String::from("1")).unwrap();
+        trie.insert(&[1, 2, 3], String::from("123")).unwrap();
+        trie.insert(&[1, 2, 3], String::from("123'")).unwrap();
+        trie.insert(&[1, 2, 3, 4, 5], String::from("12345"))
+            .unwrap();
+        trie.insert(&[2], String::from("2")).unwrap();
+
         assert_eq!(
-            trie.find("長府浜浦".as_bytes()),
-            Ok(&[String::from("長府浜浦")][..])
+            trie.common_prefix_search(&[1, 2, 3, 4, 5, 6])
+                .collect::<Vec<_>>(),
+            vec![
+                (1, &[String::from("1")][..]),
+                (3, &[String::from("123"), String::from("123'")][..]),
+                (5, &[String::from("12345")][..]),
+            ]
         );
         assert_eq!(
-            trie.find("中佃".as_bytes()),
-            Ok(&[String::from("中佃")][..])
+            trie.common_prefix_search(&[1, 2, 4]).collect::<Vec<_>>(),
+            vec![(1, &[String::from("1")][..])]
         );
+        assert_eq!(trie.common_prefix_search(&[3, 1]).count(), 0);
+        assert_eq!(trie.common_prefix_search(&[]).count(), 0);
+    }
+
+    #[test]
+    fn test_view() {
+        let mut trie: Trie<String> = Trie::default();
+        trie.insert(&[1], String::from("1")).unwrap();
+        trie.insert(&[1, 2, 3], String::from("123")).unwrap();
+        trie.insert(&[1, 2, 3], String::from("123'")).unwrap();
+        let (tree, storage, tails, codes) = trie.raw_parts();
+        let values: Vec<String> = storage
+            .iter()
+            .flat_map(|vals| vals.iter())
+            .cloned()
+            .collect();
+        let mut ranges = vec![0];
+        for vals in storage {
+            ranges.push(ranges.last().unwrap() + vals.len() as u32);
+        }
+        let tail: Vec<u8> = tails.concat();
+        let mut offsets = vec![0];
+        for bytes in tails {
+            offsets.push(offsets.last().unwrap() + bytes.len() as u32);
+        }
+        let view = TrieView::new(tree, &values, &ranges, &tail, &offsets, codes);
+        // [2, 3] is a tail
+        for way in &[&[1, 2, 3, 4][..], &[1, 2, 4]] {
+            assert_eq!(
+                view.common_prefix_search(way).collect::<Vec<_>>(),
+                trie.common_prefix_search(way).collect::<Vec<_>>()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_tail {
+    use super::*;
+
+    fn sample() -> Trie<u32> {
+        Trie::try_from_iter(vec![
+            ("abcde", 0),
+            ("abcxy", 1),
+            ("abcxy", 2),
+            ("b", 3),
+            ("bcd", 4),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn test_build() {
+        let trie = sample();
+        assert_eq!(trie.verify(), Ok(()));
+        // root, a, ab, abc, abcd, abcx, b and bc
+        assert_eq!(trie.stats().nodes, 8);
+        assert_eq!(trie.tail, b"eyd".to_vec());
+        assert_eq!(trie.get(b"abcxy"), Some(&[1, 2][..]));
+        assert_eq!(trie.get(b"abcx"), None);
+        assert_eq!(trie.get(b"abcxyz"), None);
+        assert_eq!(trie.get(b"bcd"), Some(&[4][..]));
+        assert_eq!(trie.get(b"bc"), None);
         assert_eq!(
-            trie.find("幻視".as_bytes()),
-            Ok(&[String::from("幻視")][..])
+            trie.common_prefix_search(b"bcde").collect::<Vec<_>>(),
+            vec![(1, &[3][..]), (3, &[4][..])]
         );
+        assert_eq!(trie.common_prefix_search(b"abcxz").count(), 0);
         assert_eq!(
-            trie.find("小船木".as_bytes()),
-            Ok(&[String::from("小船木")][..])
+            trie.iter().map(|(key, _)| key).collect::<Vec<_>>(),
+            vec![
+                b"abcde".to_vec(),
+                b"abcxy".to_vec(),
+                b"b".to_vec(),
+                b"bcd".to_vec()
+            ]
         );
+        // prefixes ending in a tail
+        let keys = |prefix: &[u8]| {
+            trie.keys_with_prefix(prefix)
+                .map(|(key, _)| key)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(keys(b"abcx"), vec![b"abcxy".to_vec()]);
+        assert_eq!(keys(b"bcd"), vec![b"bcd".to_vec()]);
+        assert_eq!(keys(b"bce"), Vec::<Vec<u8>>::new());
+        assert_eq!(keys(b"bcde"), Vec::<Vec<u8>>::new());
+    }
+
+    #[test]
+    fn test_split() {
+        let mut trie = sample();
+        // a prefix of a tail, a key branching in a tail and a key longer than a tail
+        trie.insert(b"abcx", 5).unwrap();
+        trie.insert(b"abcdz", 6).unwrap();
+        trie.insert(b"bcdef", 7).unwrap();
+        trie.insert(b"abcxy", 8).unwrap();
+        assert_eq!(trie.verify(), Ok(()));
         assert_eq!(
-            trie.find("浅黒かれ".as_bytes()),
-            Ok(&[String::from("浅黒かれ")][..])
+            trie.iter()
+                .map(|(key, values)| (key, values.to_vec()))
+                .collect::<Vec<_>>(),
+            vec![
+                (b"abcde".to_vec(), vec![0]),
+                (b"abcdz".to_vec(), vec![6]),
+                (b"abcx".to_vec(), vec![5]),
+                (b"abcxy".to_vec(), vec![1, 2, 8]),
+                (b"b".to_vec(), vec![3]),
+                (b"bcd".to_vec(), vec![4]),
+                (b"bcdef".to_vec(), vec![7]),
+            ]
         );
+        assert_eq!(trie.remove(b"abcxy"), Some(vec![1, 2, 8]));
+        assert_eq!(trie.remove(b"bcd"), Some(vec![4]));
+        assert_eq!(trie.get(b"bcdef"), Some(&[7][..]));
+        assert_eq!(trie.verify(), Ok(()));
+
+        let mut expanded = sample();
+        expanded.expand_tails();
+        assert!(expanded.tail.is_empty());
+        assert_eq!(expanded.verify(), Ok(()));
+        assert!(expanded.iter().eq(sample().iter()));
+    }
+
+    #[test]
+    fn test_compact() {
+        let keys: Vec<Vec<u8>> = (0..64u8).map(|i| vec![i, i, i, i, i, i, i, i]).collect();
+        let mut trie = Trie::try_from_iter(keys.iter().map(|key| (key, key[0]))).unwrap();
+        assert_eq!(trie.tail.len(), 64 * 7);
+        // bytes dropped by remove are reclaimed once they outnumber the rest
+        for key in &keys[..32] {
+            assert_eq!(trie.remove(key), Some(vec![key[0]]));
+        }
+        assert_eq!(trie.tail.len(), 64 * 7);
+        trie.remove(&keys[32]);
+        assert_eq!(trie.tail.len(), 31 * 7);
+        // and so are bytes moved into nodes by insert
+        for key in &keys[33..] {
+            trie.insert(&key[..7], key[0]).unwrap();
+        }
+        assert!(trie.tail.len() <= 2 * 31);
+        assert_eq!(trie.verify(), Ok(()));
+        for key in &keys[33..] {
+            assert_eq!(trie.get(key), Some(&[key[0]][..]));
+            assert_eq!(trie.get(&key[..7]), Some(&[key[0]][..]));
+        }
+        assert_eq!(trie.get(&keys[0]), None);
+    }
+}
+
+#[cfg(test)]
+mod test_fuzzy {
+    use super::*;
+    use crate::testutil::xorshift;
+
+    fn levenshtein(a: &str, b: &str) -> usize {
+        let b: Vec<char> = b.chars().collect();
+        let mut row: Vec<usize> = (0..=b.len()).collect();
+        for ch in a.chars() {
+            let mut next = vec![row[0] + 1];
+            for (j, other) in b.iter().enumerate() {
+                let replace = row[j] + if ch == *other { 0 } else { 1 };
+                next.push(replace.min(row[j + 1] + 1).min(next[j] + 1));
+            }
+            row = next;
+        }
+        row[b.len()]
+    }
+
+    #[test]
+    fn test_fuzzy_search() {
+        let trie: Trie<u32> = Trie::try_from_iter(vec![
+            ("東京", 0),
+            ("東京都", 1),
+            ("京都", 2),
+            ("東北", 3),
+            ("東京", 4),
+            ("tokyo", 5),
+        ])
+        .unwrap();
+        let search = |query: &str, distance: usize| {
+            trie.fuzzy_search(query, distance)
+                .map(|(key, distance, values)| {
+                    (String::from_utf8(key).unwrap(), distance, values.to_vec())
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(search("東京", 0), vec![("東京".to_owned(), 0, vec![0, 4])]);
+        // a character is one edit even if it is three bytes
         assert_eq!(
-            trie.find("扁かろ".as_bytes()),
-            Ok(&[String::from("扁かろ")][..])
+            search("東京", 1),
+            vec![
+                ("東京".to_owned(), 0, vec![0, 4]),
+                ("東京都".to_owned(), 1, vec![1]),
+                ("東北".to_owned(), 1, vec![3]),
+            ]
         );
         assert_eq!(
-            trie.find("咲き乱れ".as_bytes()),
-            Ok(&[String::from("咲き乱れ")][..])
+            search("京", 1),
+            vec![
+                ("京都".to_owned(), 1, vec![2]),
+                ("東京".to_owned(), 1, vec![0, 4])
+            ]
         );
+        assert_eq!(search("tokio", 1), vec![("tokyo".to_owned(), 1, vec![5])]);
+        // keys of two characters at most
+        assert_eq!(search("", 2).len(), 3);
+    }
+
+    #[test]
+    fn test_model() {
+        let mut rand = xorshift(0x7f4a_7c15_9e37_79b9);
+        // one, two and three bytes, and a prefix of another in UTF-8
+        let chars = ['a', 'b', 'é', 'è', 'あ', 'い'];
+        let word = |rand: &mut dyn FnMut(u64) -> u64| {
+            let len = 1 + rand(5) as usize;
+            (0..len)
+                .map(|_| chars[rand(chars.len() as u64) as usize])
+                .collect::<String>()
+        };
+        let words: Vec<String> = (0..300).map(|_| word(&mut rand)).collect();
+        let mut trie: Trie<usize> = Trie::try_from_iter(
+            words
+                .iter()
+                .enumerate()
+                .map(|(i, word)| (word.as_bytes(), i)),
+        )
+        .unwrap();
+        // tails split by insert
+        trie.insert("abあい".as_bytes(), words.len()).unwrap();
+        let mut keys: Vec<String> = words.clone();
+        keys.push("abあい".to_owned());
+        keys.sort();
+        keys.dedup();
+        for _ in 0..50 {
+            let query = word(&mut rand);
+            for distance in 0..3 {
+                let expected: Vec<(String, usize)> = keys
+                    .iter()
+                    .map(|key| (key.clone(), levenshtein(key, &query)))
+                    .filter(|(_, d)| *d <= distance)
+                    .collect();
+                let found: Vec<(String, usize)> = trie
+                    .fuzzy_search(&query, distance)
+                    .map(|(key, d, _)| (String::from_utf8(key).unwrap(), d))
+                    .collect();
+                assert_eq!(found, expected);
+            }
+        }
+    }
+}
+
+#[derive(Fail, Debug, PartialEq)]
+pub enum VerifyError {
+    #[fail(display = "length of tree is not a multiple of {}: {}", _0, _1)]
+    Length(usize, usize),
+    #[fail(display = "root is not at 0")]
+    Root,
+    #[fail(display = "node {} is not a child of its parent {}", idx, parent)]
+    Misplaced { idx: usize, parent: usize },
+    #[fail(display = "node {} is not reachable from root", idx)]
+    Orphan { idx: usize },
+    #[fail(display = "node {} has base {} but no child", idx, base)]
+    Childless { idx: usize, base: usize },
+    #[fail(display = "node {} has neither child nor value", idx)]
+    DeadEnd { idx: usize },
+    #[fail(display = "node {} refers to values {}, which are missing", idx, id)]
+    DanglingId { idx: usize, id: usize },
+    #[fail(display = "values {} are referred by no key", id)]
+    Unreachable { id: usize },
+    #[fail(display = "tail of values {} is out of tail or above children", id)]
+    Tail { id: usize },
+    #[fail(display = "values {} overlap others or leave a gap in arena", id)]
+    Arena { id: usize },
+    #[fail(display = "blank cells of block {} are not linked", block)]
+    Vacancy { block: usize },
+    #[fail(display = "cache of blank cells is not ascending within tree")]
+    Cache,
+    #[fail(display = "labels of bytes are not a permutation")]
+    Alphabet,
+}
+
+// Shape of a trie reported by stats
+#[derive(Debug, PartialEq)]
+pub struct Stats {
+    keys: usize,
+    // cells which are not blank, including root
+    nodes: usize,
+    cells: usize,
+    // depths[d] is the number of nodes d octets below root
+    depths: Vec<usize>,
+    // bytes of tails, including ones dropped by insert and remove until compacted
+    tail: usize,
+    // tree, spans, values and tails. heap memory owned by values is not counted.
+    bytes: usize,
+}
+
+impl Stats {
+    pub fn fill_ratio(&self) -> f64 {
+        self.nodes as f64 / self.cells as f64
     }
 }
 
-impl<T: Serialize + Clone> Trie<T> {
-    fn sort_dict(src: &mut Vec<(&[u8], T)>) {
-        src.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "keys\t{}", self.keys)?;
+        writeln!(f, "nodes\t{}", self.nodes)?;
+        writeln!(f, "cells\t{}", self.cells)?;
+        writeln!(f, "fill ratio\t{:.4}", self.fill_ratio())?;
+        writeln!(f, "tail\t{}", self.tail)?;
+        writeln!(f, "bytes\t{}", self.bytes)?;
+        for (depth, count) in self.depths.iter().enumerate() {
+            writeln!(f, "depth {}\t{}", depth, count)?;
+        }
+        Ok(())
     }
+}
 
-    // expect sorted
-    fn get_domain<'a, 'b>(
-        src: &'a [(&'b [u8], T)],
-        select: usize,
-        target: u8,
-    ) -> &'a [(&'b [u8], T)] {
-        let mut begin = None;
-        let mut end = None;
+// depth of every cell reachable from root, or usize::MAX
+fn cell_depths(tree: &[Node]) -> Vec<usize> {
+    let mut depths = vec![usize::MAX; tree.len()];
+    depths[0] = 0;
+    let mut queue = VecDeque::new();
+    queue.push_back(0);
+    while let Some(idx) = queue.pop_front() {
+        let base = tree[idx].base();
+        if base == NO_CHILD {
+            continue;
+        }
+        for label in 0..ROW_LEN {
+            let child = base ^ label;
+            if child < tree.len() && tree[child].check() == idx && depths[child] == usize::MAX {
+                depths[child] = depths[idx] + 1;
+                queue.push_back(child);
+            }
+        }
+    }
+    depths
+}
+
+impl Stats {
+    // nodes, cells and depths are counted on tree
+    fn new(tree: &[Node], keys: usize, tail: usize, bytes: usize) -> Self {
+        let mut depths = Vec::new();
+        for depth in cell_depths(tree) {
+            if depth == usize::MAX {
+                continue;
+            }
+            if depths.len() <= depth {
+                depths.resize(depth + 1, 0);
+            }
+            depths[depth] += 1;
+        }
+        Stats {
+            keys,
+            nodes: tree.iter().filter(|node| !is_blank(node)).count(),
+            cells: tree.len(),
+            depths,
+            tail,
+            bytes,
+        }
+    }
+}
 
-        for (i, src) in src.iter().enumerate() {
-            if src.0[select] == target && src.0.len() > select + 1 && begin == None {
-                begin = Some(i);
+impl<'a, T> TrieView<'a, T> {
+    // Check the invariants of nodes and return the node referring to each id, or NO_CELL.
+    // Tails must be within tail beforehand.
+    fn verify_nodes(&self) -> Result<Vec<usize>, VerifyError> {
+        let tree = self.tree;
+        let len = tree.len();
+        if len == 0 || !len.is_multiple_of(ROW_LEN) {
+            return Err(VerifyError::Length(ROW_LEN, len));
+        }
+        let root = tree[0];
+        if root.check() != NO_PARENT || root.base() == NO_CHILD {
+            return Err(VerifyError::Root);
+        }
+        let mut labels = self.codes.to_vec();
+        labels.sort_unstable();
+        labels.dedup();
+        if !self.codes.is_empty() && labels.len() != ROW_LEN {
+            return Err(VerifyError::Alphabet);
+        }
+        let mut children = vec![0usize; len];
+        for (idx, node) in tree.iter().enumerate().skip(1) {
+            if is_blank(node) {
+                continue;
+            }
+            let parent = node.check();
+            let misplaced = parent >= len
+                || tree[parent].base() == NO_CHILD
+                || tree[parent].base() ^ idx >= ROW_LEN;
+            if misplaced {
+                return Err(VerifyError::Misplaced { idx, parent });
+            }
+            children[parent] += 1;
+        }
+        let depths = cell_depths(tree);
+        let ids = self.values.len();
+        let mut referred = vec![NO_CELL; ids];
+        for (idx, node) in tree.iter().enumerate().skip(1) {
+            if is_blank(node) {
+                continue;
+            }
+            if depths[idx] == usize::MAX {
+                return Err(VerifyError::Orphan { idx });
+            }
+            if node.base() != NO_CHILD && children[idx] == 0 {
+                return Err(VerifyError::Childless {
+                    idx,
+                    base: node.base(),
+                });
+            }
+            let id = node.id();
+            if id == NO_ITEM {
+                if node.base() == NO_CHILD {
+                    return Err(VerifyError::DeadEnd { idx });
+                }
+                continue;
             }
-            if begin != None && end == None && src.0[select] != target {
-                end = Some(i);
+            if id >= ids || referred[id] != NO_CELL || self.values.get(id).is_empty() {
+                return Err(VerifyError::DanglingId { idx, id });
             }
+            if node.base() != NO_CHILD && !self.tails.get_or_empty(id).is_empty() {
+                return Err(VerifyError::Tail { id });
+            }
+            referred[id] = idx;
         }
-        match (begin, end) {
-            (Some(begin), Some(end)) => &src[begin..end],
-            (Some(begin), None) => &src[begin..],
-            _ => unreachable!(),
+        if self.tails.len() > ids {
+            return Err(VerifyError::Tail { id: ids });
         }
+        Ok(referred)
     }
 
-    pub fn add_static(&mut self, src: &[(&[u8], T)], select: usize, parent_idx: usize) -> usize {
-        let mut row = [Node::default(); 256];
-        let mut mask = [false; 256];
-        let mut update = [false; 256];
-        let mut before = -1i16;
-        let mut cnt = 0u8;
+    // Check the invariants of nodes, values and tails.
+    // An id without values is left by a removed key and referred by no node.
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        let referred = self.verify_nodes()?;
+        match (0..referred.len())
+            .find(|id| referred[*id] == NO_CELL && !self.values.get(*id).is_empty())
+        {
+            Some(id) => Err(VerifyError::Unreachable { id }),
+            None => Ok(()),
+        }
+    }
 
-        for (way, cargo) in src {
-            mask[way[select] as usize] = true;
-            if way[select] as i16 > before {
-                cnt += 1;
-                before = way[select] as i16;
-                row[way[select] as usize] = Node::term(parent_idx, NO_ITEM);
+    pub fn stats(&self) -> Stats {
+        let keys = (0..self.values.len())
+            .filter(|id| !self.values.get(*id).is_empty())
+            .count();
+        Stats::new(
+            self.tree,
+            keys,
+            self.tails.count(),
+            std::mem::size_of_val(self.tree) + self.values.bytes() + self.tails.bytes(),
+        )
+    }
+}
+
+impl<T> Trie<T> {
+    // Check the invariants of tree, values and blank cells, which the dynamic insert and remove
+    // and the builder are expected to keep. Every node is visited.
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        if let Some(id) =
+            (0..self.tails.len()).find(|id| self.tails[*id].range().end > self.tail.len())
+        {
+            return Err(VerifyError::Tail { id });
+        }
+        let referred = self.view().verify_nodes()?;
+        let ids = self.arena.len();
+        let mut vacant = vec![false; ids];
+        for id in &self.vacant {
+            let dangling = *id >= ids
+                || referred[*id] != NO_CELL
+                || vacant[*id]
+                || !self.values_of(*id).is_empty()
+                || *id < self.tails.len() && self.tails[*id].len != 0;
+            if dangling {
+                return Err(VerifyError::DanglingId {
+                    idx: NO_CELL,
+                    id: *id,
+                });
             }
-            if way.len() <= select + 1 {
-                if row[way[select] as usize].id != NO_ITEM {
-                    self.storage[row[way[select] as usize].id as usize].push(cargo.clone());
-                } else {
-                    self.storage.push(vec![cargo.clone()]);
-                    row[way[select] as usize].id = self.storage.len() - 1;
+            vacant[*id] = true;
+        }
+        if let Some(id) = (0..ids).find(|id| referred[*id] == NO_CELL && !vacant[*id]) {
+            return Err(VerifyError::Unreachable { id });
+        }
+        if let Arena::Flat(values, spans) = &self.arena {
+            // values of keys tile the sequence
+            let mut ids: Vec<usize> = (0..ids).filter(|id| referred[*id] != NO_CELL).collect();
+            ids.sort_by_key(|id| spans[*id].offset);
+            let mut end = 0;
+            for id in ids {
+                if spans[id].offset as usize != end {
+                    return Err(VerifyError::Arena { id });
                 }
-            } else {
-                update[way[select] as usize] = true;
+                end = spans[id].range().end;
+            }
+            if end != values.len() {
+                return Err(VerifyError::Arena { id: NO_ITEM });
             }
         }
+        self.verify_vacancy()
+    }
 
-        let base = self.reallocate_base(&mask, cnt);
-        for i in 0..256 {
-            if mask[i] {
-                self.tree[i ^ base] = row[i];
-            }
+    // blank cells are linked only after a modification
+    fn verify_vacancy(&self) -> Result<(), VerifyError> {
+        let vacancy = &self.vacancy;
+        if vacancy.prev.len() != self.tree.len() {
+            return Ok(());
         }
-        for (i, update) in update.iter().enumerate() {
-            if *update {
-                let idx = i ^ base;
-                self.tree[idx].base =
-                    self.add_static(Self::get_domain(src, select, i as u8), select + 1, idx);
+        for block in 0..self.tree.len() / ROW_LEN {
+            let blank = (block * ROW_LEN..(block + 1) * ROW_LEN)
+                .filter(|idx| is_blank(&self.tree[*idx]))
+                .count();
+            let mut linked = 0;
+            let head = vacancy.head[block];
+            if head != NO_CELL {
+                let mut cell = head;
+                loop {
+                    let next = vacancy.next[cell];
+                    let broken = cell / ROW_LEN != block
+                        || !is_blank(&self.tree[cell])
+                        || vacancy.prev[next] != cell
+                        || linked == ROW_LEN;
+                    if broken {
+                        return Err(VerifyError::Vacancy { block });
+                    }
+                    linked += 1;
+                    cell = next;
+                    if cell == head {
+                        break;
+                    }
+                }
+            }
+            if linked != blank || vacancy.count[block] != blank {
+                return Err(VerifyError::Vacancy { block });
             }
         }
-        base
+        let blocks = self.tree.len() / ROW_LEN;
+        let ascending = vacancy.cache.windows(2).all(|pair| pair[0] <= pair[1]);
+        if vacancy.cache.len() != ROW_LEN + 1 || !ascending || vacancy.cache[ROW_LEN] > blocks {
+            return Err(VerifyError::Cache);
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats::new(
+            &self.tree,
+            self.len(),
+            self.tail.len(),
+            std::mem::size_of::<Node>() * self.tree.len()
+                + self.arena.values().bytes()
+                + self.tails().bytes()
+                + std::mem::size_of::<usize>() * self.vacant.len(),
+        )
     }
+}
+
+#[cfg(test)]
+mod test_verify {
+    use super::*;
 
-    pub fn static_construction(src: &mut Vec<(&[u8], T)>) -> Trie<T> {
-        Trie::sort_dict(src);
+    fn sample() -> Trie<u32> {
         let mut trie = Trie::default();
-        trie.tree[0] = Node::root(0);
-        trie.tree[0].base = trie.add_static(src, 0, 0);
+        trie.insert(&[1, 2], 0).unwrap();
+        trie.insert(&[1, 2, 3], 1).unwrap();
+        trie.insert(&[1, 2, 3], 2).unwrap();
+        trie.insert(&[4], 3).unwrap();
         trie
     }
+
+    #[test]
+    fn test_verify() {
+        let trie = sample();
+        assert_eq!(trie.verify(), Ok(()));
+        assert_eq!(Trie::<u32>::default().verify(), Ok(()));
+        let mut removed = sample();
+        removed.remove(&[1, 2]).unwrap();
+        assert_eq!(removed.verify(), Ok(()));
+
+        let one = trie.explore(&[1]).unwrap();
+        let one_two = trie.explore(&[1, 2]).unwrap();
+        let four = trie.explore(&[4]).unwrap();
+
+        // a leaf as parent
+        let leaf = trie.explore(&[1, 2, 3]).unwrap();
+        let mut broken = sample();
+        broken.tree[four].set_check(leaf);
+        assert_eq!(
+            broken.verify(),
+            Err(VerifyError::Misplaced {
+                idx: four,
+                parent: leaf
+            })
+        );
+
+        // a cycle cut off from root
+        let mut broken = sample();
+        broken.tree[one].set_check(one_two);
+        broken.tree[one_two].set_base(one_two ^ 1);
+        assert_eq!(broken.verify(), Err(VerifyError::Orphan { idx: one }));
+
+        let mut broken = sample();
+        broken.tree[four].set_base(0);
+        assert_eq!(
+            broken.verify(),
+            Err(VerifyError::Childless { idx: four, base: 0 })
+        );
+
+        let mut broken = sample();
+        broken.tree[four].set_id(NO_ITEM);
+        assert_eq!(broken.verify(), Err(VerifyError::DeadEnd { idx: four }));
+
+        let mut broken = sample();
+        broken.tree[four].set_id(0);
+        assert_eq!(
+            broken.verify(),
+            Err(VerifyError::DanglingId { idx: four, id: 0 })
+        );
+
+        let mut broken = sample();
+        broken.arena.add();
+        assert_eq!(broken.verify(), Err(VerifyError::Unreachable { id: 3 }));
+
+        // [1, 2] has a child
+        let mut broken = sample();
+        broken.tails[0] = Span::new(0, 1);
+        assert_eq!(broken.verify(), Err(VerifyError::Tail { id: 0 }));
+
+        let mut broken = sample();
+        broken.tails.resize(3, Span::default());
+        broken.tails[2] = Span::new(0, 2);
+        assert_eq!(broken.verify(), Err(VerifyError::Tail { id: 2 }));
+
+        let mut broken = sample();
+        broken.tails.resize(4, Span::default());
+        assert_eq!(broken.verify(), Err(VerifyError::Tail { id: 3 }));
+
+        let mut broken = sample();
+        if let Arena::Flat(values, _) = &mut broken.arena {
+            values.push(4);
+        }
+        assert_eq!(broken.verify(), Err(VerifyError::Arena { id: NO_ITEM }));
+
+        let mut broken = sample();
+        broken.vacancy.count[0] += 1;
+        assert_eq!(broken.verify(), Err(VerifyError::Vacancy { block: 0 }));
+
+        let mut broken = sample();
+        broken.vacancy.cache[0] = 1;
+        assert_eq!(broken.verify(), Err(VerifyError::Cache));
+
+        let mut broken = sample();
+        broken.alphabet.codes = vec![0; ROW_LEN];
+        assert_eq!(broken.verify(), Err(VerifyError::Alphabet));
+    }
+
+    #[test]
+    fn test_stats() {
+        let stats = sample().stats();
+        assert_eq!(stats.keys, 3);
+        assert_eq!(stats.nodes, 5);
+        assert_eq!(stats.cells, 256);
+        assert_eq!(stats.depths, vec![1, 2, 1, 1]);
+        // [2] of [1, 2] is moved into nodes when [1, 2, 3] is inserted, and dropped from tail
+        assert_eq!(stats.tail, 0);
+        assert_eq!(stats.bytes, 12 * 256 + 8 * 3 + 4 * 4 + 8);
+        assert!((stats.fill_ratio() - 5.0 / 256.0).abs() < 1e-9);
+    }
+}
+
+impl<T> Trie<T> {
+    // stable, so values of the same key keep their order
+    fn sort_dict<K: AsRef<[u8]>>(src: &mut [(K, T)]) {
+        src.sort_by(|a, b| a.0.as_ref().cmp(b.0.as_ref()));
+    }
 }
 #[cfg(test)]
 mod test_static_construction {
@@ -832,50 +3037,9 @@ mod test_static_construction {
         );
     }
 
-    #[test]
-    fn test_get_domain() {
-        let src = [
-            (&[0, 0, 0][..], "000"),
-            (&[0, 1, 2][..], "012"),
-            (&[0, 1, 3][..], "013"),
-            (&[0, 1, 3][..], "013"),
-            (&[0, 1, 3, 4][..], "0134"),
-            (&[1, 0][..], "10"),
-            (&[1, 1][..], "11"),
-            (&[2, 1, 2][..], "212"),
-        ];
-        assert_eq!(
-            Trie::get_domain(&src[..], 0, 0),
-            &[
-                (&[0, 0, 0][..], "000"),
-                (&[0, 1, 2][..], "012"),
-                (&[0, 1, 3][..], "013"),
-                (&[0, 1, 3][..], "013"),
-                (&[0, 1, 3, 4][..], "0134"),
-            ][..]
-        );
-        assert_eq!(
-            Trie::get_domain(Trie::get_domain(&src[..], 0, 0), 1, 1),
-            &[
-                (&[0, 1, 2][..], "012"),
-                (&[0, 1, 3][..], "013"),
-                (&[0, 1, 3][..], "013"),
-                (&[0, 1, 3, 4][..], "0134"),
-            ][..]
-        );
-        assert_eq!(
-            Trie::get_domain(&src[..], 0, 1),
-            &[(&[1, 0][..], "10"), (&[1, 1][..], "11"),][..]
-        );
-        assert_eq!(
-            Trie::get_domain(&src[..], 0, 2),
-            &[(&[2, 1, 2][..], "212"),][..]
-        );
-    }
-
     #[test]
     fn test_add_static() {
-        let trie = Trie::static_construction(&mut vec![
+        let trie = Trie::try_from_iter(vec![
             ("咲き乱れ".as_bytes(), String::from("咲き乱れ")),
             ("張り込め".as_bytes(), String::from("張り込め")),
             ("1".as_bytes(), String::from("1")),
@@ -895,65 +3059,814 @@ mod test_static_construction {
             ("小船木".as_bytes(), String::from("小船木")),
             ("差し昇っ".as_bytes(), String::from("差し登っ")),
             ("浅黒かれ".as_bytes(), String::from("浅黒かれ")),
-        ]);
+        ])
+        .unwrap();
 
         assert_eq!(
-            trie.find("張り込め".as_bytes()),
-            Ok(&[String::from("張り込め")][..])
+            trie.get("張り込め".as_bytes()),
+            Some(&[String::from("張り込め")][..])
+        );
+        assert_eq!(trie.get("1".as_bytes()), Some(&[String::from("1")][..]));
+        assert_eq!(trie.get("1月".as_bytes()), Some(&[String::from("1月")][..]));
+        assert_eq!(
+            trie.get("ニッカーボッカー".as_bytes()),
+            Some(&[String::from("ニッカーボッカー")][..])
+        );
+        assert_eq!(
+            trie.get("証城寺".as_bytes()),
+            Some(&[String::from("証城寺")][..])
+        );
+        assert_eq!(
+            trie.get("差し昇っ".as_bytes()),
+            Some(&[String::from("差し登っ")][..])
+        );
+        assert_eq!(
+            trie.get("抜け出せれ".as_bytes()),
+            Some(&[String::from("抜け出せれ")][..])
+        );
+        assert_eq!(
+            trie.get("たい".as_bytes()),
+            Some(&[String::from("たい")][..])
+        );
+        assert_eq!(
+            trie.get("アオガエル".as_bytes()),
+            Some(&[String::from("アオガエル")][..])
+        );
+        assert_eq!(
+            trie.get("長府浜浦".as_bytes()),
+            Some(&[String::from("長府浜浦")][..])
+        );
+        assert_eq!(
+            trie.get("中佃".as_bytes()),
+            Some(&[String::from("中佃")][..])
+        );
+        assert_eq!(
+            trie.get("幻視".as_bytes()),
+            Some(&[String::from("幻視")][..])
+        );
+        assert_eq!(
+            trie.get("小船木".as_bytes()),
+            Some(&[String::from("小船木")][..])
         );
-        assert_eq!(trie.find("1".as_bytes()), Ok(&[String::from("1")][..]));
-        assert_eq!(trie.find("1月".as_bytes()), Ok(&[String::from("1月")][..]));
         assert_eq!(
-            trie.find("ニッカーボッカー".as_bytes()),
-            Ok(&[String::from("ニッカーボッカー")][..])
+            trie.get("浅黒かれ".as_bytes()),
+            Some(&[String::from("浅黒かれ")][..])
         );
         assert_eq!(
-            trie.find("証城寺".as_bytes()),
-            Ok(&[String::from("証城寺")][..])
+            trie.get("扁かろ".as_bytes()),
+            Some(&[String::from("扁かろ")][..])
         );
         assert_eq!(
-            trie.find("差し昇っ".as_bytes()),
-            Ok(&[String::from("差し登っ")][..])
+            trie.get("咲き乱れ".as_bytes()),
+            Some(&[String::from("咲き乱れ")][..])
+        );
+    }
+}
+
+#[derive(Fail, Debug, PartialEq)]
+pub enum BuildError {
+    #[fail(display = "keys are not sorted: {:?} after {:?}", key, last)]
+    Unsorted { last: Vec<u8>, key: Vec<u8> },
+    #[fail(display = "empty key")]
+    EmptyKey,
+    #[fail(display = "duplicate key: {:?}", key)]
+    DuplicateKey { key: Vec<u8> },
+    #[fail(display = "trie is full: its indices would exceed 32 bits")]
+    Full,
+}
+
+// What Builder does with a key pushed again
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Duplicates {
+    // values are appended in the order of push, e.g. homographs of a dictionary
+    Append,
+    Reject,
+}
+
+// Node along the last key whose children may still increase
+struct OpenNode {
+    id: usize,
+    children: Vec<ClosedNode>,
+}
+
+// Node whose subtree is placed in the tree except for the node itself
+struct ClosedNode {
+    label: u8,
+    base: usize,
+    id: usize,
+    // labels of children, whose check is fixed when the node is placed
+    labels: Vec<u8>,
+    // rest of the key of a leaf
+    tail: Vec<u8>,
+}
+
+// Build a trie from keys in ascending order without holding all of them.
+// Keys of each first octet make a partition, which is built into a trie of its own
+// and appended to the tree block by block. So partitions are independent of each other,
+// and build_parallel builds them on threads into the same trie as push and build.
+pub struct Builder<T> {
+    trie: Trie<T>,
+    duplicates: Duplicates,
+    last: Vec<u8>,
+    // partition of the first octet of last
+    current: Option<Subtree<T>>,
+}
+
+impl<T> Default for Builder<T> {
+    fn default() -> Self {
+        Builder {
+            trie: Trie::default(),
+            duplicates: Duplicates::Append,
+            last: Vec::new(),
+            current: None,
+        }
+    }
+}
+
+impl<T> Builder<T> {
+    pub fn new() -> Self {
+        Builder::default()
+    }
+
+    pub fn duplicates(mut self, duplicates: Duplicates) -> Self {
+        self.duplicates = duplicates;
+        self
+    }
+
+    // Keys are still pushed in ascending order of bytes
+    pub(crate) fn alphabet(mut self, alphabet: Alphabet) -> Self {
+        self.trie.alphabet = alphabet;
+        self
+    }
+
+    // Input is validated before anything is changed, so the builder is usable after an error.
+    // The root can not hold a value, so empty keys are rejected.
+    pub fn push(&mut self, key: &[u8], value: T) -> Result<(), BuildError> {
+        self.check(key)?;
+        let mut sizes = Sizes::of(&self.trie).plus(ROOT_BLOCK);
+        if let Some(subtree) = &self.current {
+            sizes = sizes.plus(subtree.grown(&key[1..]));
+        }
+        // key may begin a partition of its own
+        if !sizes
+            .plus(Sizes::default().grown(key.len() + 1, key.len()))
+            .fits()
+        {
+            return Err(BuildError::Full);
+        }
+        if self.accept(key) {
+            if let Some(subtree) = self.current.take() {
+                self.append(subtree.finish());
+            }
+            self.current = Some(Subtree::new(&self.trie.alphabet, key[0]));
+        }
+        self.current.as_mut().unwrap().push(&key[1..], value);
+        Ok(())
+    }
+
+    // push has checked that the last partition fits
+    pub fn build(mut self) -> Trie<T> {
+        if let Some(subtree) = self.current.take() {
+            self.append(subtree.finish());
+        }
+        self.trie
+    }
+
+    // Validate key following the last one
+    fn check(&self, key: &[u8]) -> Result<(), BuildError> {
+        if key.is_empty() {
+            return Err(BuildError::EmptyKey);
+        }
+        if key < &self.last[..] {
+            return Err(BuildError::Unsorted {
+                last: self.last.clone(),
+                key: key.to_vec(),
+            });
+        }
+        // last is empty only before the first key
+        if key == &self.last[..] && self.duplicates == Duplicates::Reject {
+            return Err(BuildError::DuplicateKey { key: key.to_vec() });
+        }
+        Ok(())
+    }
+
+    // Make key checked last. true if it begins a new partition.
+    fn accept(&mut self, key: &[u8]) -> bool {
+        let fresh = self.last.first() != Some(&key[0]);
+        self.last.clear();
+        self.last.extend_from_slice(key);
+        fresh
+    }
+
+    // Whether partition can be appended without an index exceeding u32
+    fn fits(&self, partition: &Partition<T>) -> bool {
+        !partition.full
+            && Sizes::of(&self.trie)
+                .plus(ROOT_BLOCK)
+                .plus(Sizes::of(&partition.trie))
+                .fits()
+    }
+
+    // Append the tree of partition to the end of tree. The offset is a multiple of ROW_LEN,
+    // so that base ^ label is moved by the same offset as base.
+    fn append(&mut self, partition: Partition<T>) {
+        // no value is stored before the first partition, which decides the base of root.
+        // children of root are in the first block unless one of them would be root itself.
+        if self.trie.arena.len() == 0 && partition.label == 0 {
+            self.trie.tree.resize(ROW_LEN * 2, Node::blank());
+            self.trie.tree[0] = Node::root(ROW_LEN);
+        }
+        let offset = self.trie.tree.len();
+        let id_offset = self.trie.arena.len();
+        let tail_offset = self.trie.tail.len();
+        let Partition {
+            label,
+            trie,
+            base,
+            id,
+            ..
+        } = partition;
+        let shift = |idx: usize, offset: usize| {
+            if idx == usize::MAX {
+                idx
+            } else {
+                idx + offset
+            }
+        };
+        let parent = self.trie.tree[0].base() ^ label as usize;
+        // cells after the last used block are left
+        let used = trie
+            .tree
+            .iter()
+            .rposition(|node| !is_blank(node))
+            .map_or(0, |idx| idx / ROW_LEN + 1);
+        self.trie.tree.extend(
+            trie.tree[..used * ROW_LEN]
+                .iter()
+                .enumerate()
+                .map(|(idx, node)| {
+                    if idx == 0 || is_blank(node) {
+                        // the root of partition is moved to parent
+                        Node::blank()
+                    } else if node.check() == 0 {
+                        Node::node(
+                            parent,
+                            shift(node.base(), offset),
+                            shift(node.id(), id_offset),
+                        )
+                    } else {
+                        Node::node(
+                            node.check() + offset,
+                            shift(node.base(), offset),
+                            shift(node.id(), id_offset),
+                        )
+                    }
+                }),
         );
+        self.trie.tree[parent] = Node::node(0, shift(base, offset), shift(id, id_offset));
+        self.trie.arena.append(trie.arena);
+        if !trie.tails.is_empty() {
+            self.trie.tails.resize(id_offset, Span::default());
+        }
+        self.trie.tails.extend(trie.tails.iter().map(|span| {
+            if span.len == 0 {
+                *span
+            } else {
+                Span::new(span.offset as usize + tail_offset, span.len as usize)
+            }
+        }));
+        self.trie.tail.extend(trie.tail);
+    }
+}
+
+// cells added to the tree by the first partition for the children of root
+const ROOT_BLOCK: Sizes = Sizes {
+    cells: ROW_LEN,
+    ids: 0,
+    values: 0,
+    tail: 0,
+};
+
+// number of keys queued for a partition being built on another thread
+const PARTITION_QUEUE_LEN: usize = 1024;
+
+impl<T: Send> Builder<T> {
+    // Build the same trie as push and build from keys in ascending order,
+    // building partitions on threads. They are appended in the order of first octets.
+    pub fn build_parallel<I>(mut self, pairs: I, threads: usize) -> Result<Trie<T>, BuildError>
+    where
+        I: IntoIterator<Item = (Vec<u8>, T)>,
+    {
+        if threads <= 1 {
+            for (key, value) in pairs {
+                self.push(&key, value)?;
+            }
+            return Ok(self.build());
+        }
+        type Keys<T> = mpsc::Receiver<(Vec<u8>, T)>;
+        let (done_tx, done_rx) = mpsc::channel::<Partition<T>>();
+        // first octets of partitions not appended yet
+        let mut order = VecDeque::new();
+        let mut pending = HashMap::new();
+        let mut drain = |builder: &mut Self, order: &mut VecDeque<u8>, block: bool| {
+            while let Some(label) = order.front() {
+                if let Some(partition) = pending.remove(label) {
+                    if !builder.fits(&partition) {
+                        return Err(BuildError::Full);
+                    }
+                    builder.append(partition);
+                    order.pop_front();
+                } else {
+                    let received = if block {
+                        done_rx.recv().ok()
+                    } else {
+                        done_rx.try_recv().ok()
+                    };
+                    match received {
+                        Some(partition) => {
+                            pending.insert(partition.label, partition);
+                        }
+                        None => break,
+                    }
+                }
+            }
+            Ok(())
+        };
+        let (job_tx, job_rx) = mpsc::channel::<(u8, Keys<T>)>();
+        let job_rx = Mutex::new(job_rx);
+        let alphabet = self.trie.alphabet.clone();
+        let dispatched: Result<(), BuildError> = thread::scope(|scope| {
+            // workers stop when the dispatch ends, even by an error
+            let job_tx = job_tx;
+            for _ in 0..threads {
+                let done_tx = done_tx.clone();
+                let job_rx = &job_rx;
+                let alphabet = &alphabet;
+                scope.spawn(move || loop {
+                    let (octet, keys) = match job_rx.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    let mut subtree = Subtree::new(alphabet, octet);
+                    for (key, value) in keys {
+                        subtree.push(&key[1..], value);
+                    }
+                    let _ = done_tx.send(subtree.finish());
+                });
+            }
+            let mut current: Option<mpsc::SyncSender<(Vec<u8>, T)>> = None;
+            for (key, value) in pairs {
+                self.check(&key)?;
+                if self.accept(&key) {
+                    let (tx, rx) = mpsc::sync_channel(PARTITION_QUEUE_LEN);
+                    // the previous partition is closed before waiting for a worker
+                    current = Some(tx);
+                    job_tx.send((key[0], rx)).unwrap();
+                    order.push_back(alphabet.label(key[0]) as u8);
+                    drain(&mut self, &mut order, false)?;
+                }
+                current.as_ref().unwrap().send((key, value)).unwrap();
+            }
+            Ok(())
+        });
+        drop(done_tx);
+        dispatched?;
+        drain(&mut self, &mut order, true)?;
+        Ok(self.trie)
+    }
+}
+
+// Trie of keys sharing the first octet, which is removed from keys
+struct Partition<T> {
+    // of the first octet
+    label: u8,
+    trie: Trie<T>,
+    // base and id of the root of trie
+    base: usize,
+    id: usize,
+    // keys were dropped as an index would exceed u32
+    full: bool,
+}
+
+// Builder of a partition from keys validated by Builder.
+// Only nodes along the last key are kept aside. A subtree is placed bottom-up
+// as soon as a key out of it arrives. A path down to a single leaf is cut off into
+// the tail of the leaf.
+struct Subtree<T> {
+    label: u8,
+    trie: Trie<T>,
+    codes: Vec<u8>,
+    // the last key without the first octet
+    last: Vec<u8>,
+    // stack[d] is the node of last[..d]
+    stack: Vec<OpenNode>,
+    full: bool,
+}
+
+impl<T> Subtree<T> {
+    fn new(alphabet: &Alphabet, octet: u8) -> Self {
+        Subtree {
+            label: alphabet.label(octet) as u8,
+            trie: Trie::default(),
+            codes: alphabet.codes.clone(),
+            last: Vec::new(),
+            stack: vec![OpenNode {
+                id: NO_ITEM,
+                children: Vec::new(),
+            }],
+            full: false,
+        }
+    }
+
+    // Bounds of the lengths of the trie after key is pushed and the partition is finished.
+    // A row of nodes is placed for each octet of the last key and key and the root at most.
+    fn grown(&self, key: &[u8]) -> Sizes {
+        let octets = key.len() + self.last.len();
+        Sizes::of(&self.trie).grown(octets + 1, octets)
+    }
+
+    // The key may be empty, whose value is held by the root of partition.
+    // Keys are dropped once an index could exceed u32, which Builder checks beforehand.
+    fn push(&mut self, key: &[u8], value: T) {
+        if self.full || !self.grown(key).fits() {
+            self.full = true;
+            return;
+        }
+        let common = key
+            .iter()
+            .zip(&self.last)
+            .take_while(|(octet, last)| octet == last)
+            .count();
+        self.close(common);
+        self.last.truncate(common);
+        for octet in &key[common..] {
+            self.last.push(*octet);
+            self.stack.push(OpenNode {
+                id: NO_ITEM,
+                children: Vec::new(),
+            });
+        }
+        let top = self.stack.last_mut().unwrap();
+        top.id = self.trie.push_value(top.id, value);
+    }
+
+    fn finish(mut self) -> Partition<T> {
+        self.close(0);
+        let root = self.stack.pop().unwrap();
+        let root = self.shut(0, root);
+        if !root.tail.is_empty() {
+            self.trie.set_tail(root.id, &root.tail);
+        }
+        Partition {
+            label: self.label,
+            trie: self.trie,
+            base: root.base,
+            id: root.id,
+            full: self.full,
+        }
+    }
+
+    // Place nodes deeper than depth
+    fn close(&mut self, depth: usize) {
+        while self.stack.len() > depth + 1 {
+            let node = self.stack.pop().unwrap();
+            let closed = self.shut(self.stack.len(), node);
+            self.stack.last_mut().unwrap().children.push(closed);
+        }
+    }
+
+    // Place children of node of the given depth, or cut off its only child into a tail
+    fn shut(&mut self, depth: usize, node: OpenNode) -> ClosedNode {
+        let label = match depth {
+            0 => self.label,
+            _ => label(&self.codes, self.last[depth - 1]) as u8,
+        };
+        match node.children.as_slice() {
+            [] => ClosedNode {
+                label,
+                base: NO_CHILD,
+                id: node.id,
+                labels: Vec::new(),
+                tail: Vec::new(),
+            },
+            // the child was closed with node, so its octet is still in last
+            [child] if node.id == NO_ITEM && child.base == NO_CHILD => {
+                let mut tail = Vec::with_capacity(child.tail.len() + 1);
+                tail.push(self.last[depth]);
+                tail.extend_from_slice(&child.tail);
+                ClosedNode {
+                    label,
+                    base: NO_CHILD,
+                    id: child.id,
+                    labels: Vec::new(),
+                    tail,
+                }
+            }
+            children => ClosedNode {
+                label,
+                base: self.place(children),
+                id: node.id,
+                labels: children.iter().map(|child| child.label).collect(),
+                tail: Vec::new(),
+            },
+        }
+    }
+
+    // Place children and return their base
+    fn place(&mut self, children: &[ClosedNode]) -> usize {
+        let mut mask = [false; 256];
+        for child in children {
+            mask[child.label as usize] = true;
+        }
+        let base = self.trie.reallocate_base(&mask, children.len());
+        for child in children {
+            let idx = base ^ child.label as usize;
+            // check is 0 until the parent is placed. it is right for children of root.
+            self.trie.set(idx, Node::node(0, child.base, child.id));
+            if !child.tail.is_empty() {
+                self.trie.set_tail(child.id, &child.tail);
+            }
+            for label in &child.labels {
+                self.trie.tree[child.base ^ *label as usize].set_check(idx);
+            }
+        }
+        base
+    }
+}
+
+#[cfg(test)]
+mod test_builder {
+    use super::*;
+    use crate::testutil::xorshift;
+
+    #[test]
+    fn test_builder() {
+        let mut keys: Vec<&[u8]> = vec![
+            &[1],
+            &[1, 2, 3],
+            &[1, 2, 3],
+            &[1, 2, 4, 5],
+            &[1, 3],
+            &[2],
+            &[2, 0, 0, 0],
+            &[255, 255],
+        ];
+        keys.sort();
+        let mut builder = Builder::new();
+        for (i, key) in keys.iter().enumerate() {
+            builder.push(key, i).unwrap();
+        }
+        let trie = builder.build();
         assert_eq!(
-            trie.find("抜け出せれ".as_bytes()),
-            Ok(&[String::from("抜け出せれ")][..])
+            trie.iter().collect::<Vec<_>>(),
+            vec![
+                (vec![1], &[0][..]),
+                (vec![1, 2, 3], &[1, 2][..]),
+                (vec![1, 2, 4, 5], &[3][..]),
+                (vec![1, 3], &[4][..]),
+                (vec![2], &[5][..]),
+                (vec![2, 0, 0, 0], &[6][..]),
+                (vec![255, 255], &[7][..]),
+            ]
         );
+        assert_eq!(trie.get(&[1, 2]), None);
         assert_eq!(
-            trie.find("たい".as_bytes()),
-            Ok(&[String::from("たい")][..])
+            trie.common_prefix_search(&[1, 2, 3, 4]).collect::<Vec<_>>(),
+            vec![(1, &[0][..]), (3, &[1, 2][..])]
         );
+
+        let mut trie = trie;
+        trie.insert(&[1, 2], 8).unwrap();
+        assert_eq!(trie.get(&[1, 2]), Some(&[8][..]));
+        assert_eq!(trie.get(&[1, 2, 4, 5]), Some(&[3][..]));
+        assert!(Builder::<usize>::new().build().is_empty());
+    }
+
+    #[test]
+    fn test_builder_error() {
+        let mut builder = Builder::new();
+        builder.push(&[1, 2], 0).unwrap();
         assert_eq!(
-            trie.find("アオガエル".as_bytes()),
-            Ok(&[String::from("アオガエル")][..])
+            builder.push(&[1], 1),
+            Err(BuildError::Unsorted {
+                last: vec![1, 2],
+                key: vec![1],
+            })
         );
+        assert_eq!(builder.push(&[], 1), Err(BuildError::EmptyKey));
+        // the builder is still usable
+        builder.push(&[1, 3], 2).unwrap();
+        assert_eq!(builder.build().len(), 2);
+    }
+
+    // values counted up to the limit of indices without being held
+    fn filled<T>(trie: &mut Trie<T>, room: usize) {
+        trie.arena = Arena::Nested(Vec::new(), MAX_CELLS - room);
+    }
+
+    #[test]
+    fn test_full() {
+        let mut trie = Trie::new();
+        filled(&mut trie, 2);
+        trie.insert(&[1], ()).unwrap();
+        assert_eq!(trie.insert(&[2], ()), Err(InsertError::Full));
+        assert_eq!(trie.get(&[2]), None);
+
+        let mut builder = Builder::new();
+        filled(&mut builder.trie, 3);
+        builder.push(&[1], ()).unwrap();
+        assert_eq!(builder.push(&[2], ()), Err(BuildError::Full));
+        // the builder is still usable
+        assert_eq!(builder.build().get(&[1]), Some(&[()][..]));
+
+        let mut builder = Builder::new();
+        filled(&mut builder.trie, 2);
+        let pairs = vec![(vec![1], ()), (vec![2], ())];
         assert_eq!(
-            trie.find("長府浜浦".as_bytes()),
-            Ok(&[String::from("長府浜浦")][..])
+            builder.build_parallel(pairs, 2).err(),
+            Some(BuildError::Full)
         );
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let mut builder = Builder::new().duplicates(Duplicates::Reject);
+        builder.push(&[1], 0).unwrap();
+        builder.push(&[1, 2], 1).unwrap();
         assert_eq!(
-            trie.find("中佃".as_bytes()),
-            Ok(&[String::from("中佃")][..])
+            builder.push(&[1, 2], 2),
+            Err(BuildError::DuplicateKey { key: vec![1, 2] })
         );
+        let trie = builder.build();
+        assert_eq!(trie.get(&[1, 2]), Some(&[1][..]));
+
+        let trie = Trie::try_from_iter(vec![(&[3][..], 0), (&[1], 1), (&[3], 2)]);
+        assert_eq!(trie.unwrap().get(&[3]), Some(&[0, 2][..]));
         assert_eq!(
-            trie.find("幻視".as_bytes()),
-            Ok(&[String::from("幻視")][..])
+            Trie::try_from_iter(vec![(&[1][..], 0), (&[], 1)]).err(),
+            Some(BuildError::EmptyKey)
         );
+    }
+
+    #[test]
+    fn test_build_parallel() {
+        let mut rand = xorshift(0x0bad_cafe_dead_beef);
+        let mut src: Vec<(Vec<u8>, usize)> = (0..3000)
+            .map(|i| {
+                let len = 1 + rand(6) as usize;
+                ((0..len).map(|_| rand(256) as u8).collect(), i)
+            })
+            .collect();
+        // a partition of 0 moves children of root out of the block of root
+        src.push((vec![0], 3000));
+        src.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut builder = Builder::new();
+        for (key, value) in src.clone() {
+            builder.push(&key, value).unwrap();
+        }
+        let trie = builder.build();
+        assert_eq!(trie.verify(), Ok(()));
+        assert_eq!(trie.get(&[0]), Some(&[3000][..]));
+        assert!(trie
+            .iter()
+            .flat_map(|(key, values)| values.iter().map(move |value| (key.clone(), *value)))
+            .eq(src.iter().cloned()));
+        let expected = bincode::serialize(&trie).unwrap();
+        for threads in &[0, 1, 2, 4, 300] {
+            let parallel = Builder::new().build_parallel(src.clone(), *threads);
+            assert_eq!(bincode::serialize(&parallel.unwrap()).unwrap(), expected);
+        }
+
+        let mut unsorted = src.clone();
+        unsorted.swap(1000, 2000);
         assert_eq!(
-            trie.find("小船木".as_bytes()),
-            Ok(&[String::from("小船木")][..])
+            Builder::new().build_parallel(unsorted, 4).err(),
+            Some(BuildError::Unsorted {
+                last: src[2000].0.clone(),
+                key: src[1001].0.clone(),
+            })
         );
+    }
+
+    #[test]
+    fn test_alphabet() {
+        let mut counts = [0; 256];
+        counts[b'b' as usize] = 3;
+        counts[b'a' as usize] = 2;
+        counts[0] = 5;
+        let alphabet = Alphabet::by_frequency(&counts);
+        assert_eq!(alphabet.label(0), 0);
+        assert_eq!(alphabet.label(b'b'), 1);
+        assert_eq!(alphabet.label(b'a'), 2);
+        assert_eq!(alphabet.label(1), 3);
+
+        let keys: Vec<&[u8]> = vec![&[0], &[0, 255], b"a", b"ab", b"b", b"ba", &[255, 0]];
+        let mut builder = Builder::new().alphabet(alphabet.clone());
+        for (i, key) in keys.iter().enumerate() {
+            builder.push(key, i).unwrap();
+        }
+        let mut trie = builder.build();
+        assert_eq!(trie.verify(), Ok(()));
+        let pairs: Vec<(Vec<u8>, usize)> = keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| (key.to_vec(), i))
+            .collect();
+        let parallel = Builder::new()
+            .alphabet(alphabet)
+            .build_parallel(pairs.clone(), 2)
+            .unwrap();
         assert_eq!(
-            trie.find("浅黒かれ".as_bytes()),
-            Ok(&[String::from("浅黒かれ")][..])
+            bincode::serialize(&parallel).unwrap(),
+            bincode::serialize(&trie).unwrap()
         );
+        // in ascending order of bytes, not of labels
+        assert!(trie
+            .iter()
+            .map(|(key, values)| (key, values[0]))
+            .eq(pairs.into_iter()));
+        assert_eq!(trie.get(b"ab"), Some(&[3][..]));
         assert_eq!(
-            trie.find("扁かろ".as_bytes()),
-            Ok(&[String::from("扁かろ")][..])
+            trie.common_prefix_search(b"bac").collect::<Vec<_>>(),
+            vec![(1, &[4][..]), (2, &[5][..])]
         );
+        trie.insert(b"abc", 7).unwrap();
+        trie.insert(&[128], 8).unwrap();
+        assert_eq!(trie.remove(b"a"), Some(vec![2]));
+        assert_eq!(trie.get(b"abc"), Some(&[7][..]));
         assert_eq!(
-            trie.find("咲き乱れ".as_bytes()),
-            Ok(&[String::from("咲き乱れ")][..])
+            trie.keys_with_prefix(b"a")
+                .map(|(key, _)| key)
+                .collect::<Vec<_>>(),
+            vec![b"ab".to_vec(), b"abc".to_vec()]
         );
+        assert_eq!(trie.verify(), Ok(()));
+    }
+
+    // random keys over a small alphabet so that prefixes and duplicates are frequent
+    #[test]
+    fn test_model() {
+        use std::collections::BTreeMap;
+        let mut rand = xorshift(0x1234_5678_9abc_def1);
+        for round in 0..200 {
+            let mut src: Vec<(Vec<u8>, u32)> = (0..rand(100))
+                .map(|i| {
+                    let len = rand(5) as usize;
+                    let key = (0..len)
+                        .map(|_| [0u8, 1, 2, 128, 255][rand(5) as usize])
+                        .collect();
+                    (key, i as u32)
+                })
+                .collect();
+            // most rounds are valid
+            if round % 4 != 0 {
+                src.retain(|(key, _)| !key.is_empty());
+            }
+            let mut model: BTreeMap<Vec<u8>, Vec<u32>> = BTreeMap::new();
+            for (key, value) in &src {
+                model.entry(key.clone()).or_default().push(*value);
+            }
+            let built = Trie::try_from_iter(src.clone());
+            // partitions built on threads make the same trie
+            src.sort_by(|a, b| a.0.cmp(&b.0));
+            let parallel = Builder::new().build_parallel(src, 3);
+            let trie = match built {
+                Ok(trie) => trie,
+                Err(err) => {
+                    assert_eq!(err, BuildError::EmptyKey);
+                    assert_eq!(parallel.err(), Some(BuildError::EmptyKey));
+                    assert!(model.contains_key(&Vec::new()));
+                    continue;
+                }
+            };
+            assert_eq!(
+                bincode::serialize(&parallel.unwrap()).unwrap(),
+                bincode::serialize(&trie).unwrap()
+            );
+            assert!(!model.contains_key(&Vec::new()));
+            assert_eq!(trie.verify(), Ok(()));
+            assert_eq!(trie.len(), model.len());
+            assert!(trie
+                .iter()
+                .map(|(key, values)| (key, values.to_vec()))
+                .eq(model.clone().into_iter()));
+            for _ in 0..20 {
+                let len = rand(6) as usize;
+                let query: Vec<u8> = (0..len)
+                    .map(|_| [0u8, 1, 2, 128, 255][rand(5) as usize])
+                    .collect();
+                assert_eq!(
+                    trie.get(&query),
+                    model.get(&query).map(|values| &values[..])
+                );
+                let expected: Vec<(usize, &[u32])> = (1..=query.len())
+                    .filter_map(|len| model.get(&query[..len]).map(|values| (len, &values[..])))
+                    .collect();
+                assert_eq!(
+                    trie.common_prefix_search(&query).collect::<Vec<_>>(),
+                    expected
+                );
+            }
+        }
     }
 }
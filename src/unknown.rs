@@ -0,0 +1,446 @@
+use super::parser::{split_by_comma, Word};
+use serde_derive::{Deserialize, Serialize};
+use std::io;
+use std::io::{BufRead, Read};
+
+// Unknown words as MeCab makes them where the dictionary does not cover the input.
+// char.def gives categories of characters and unk.def gives words of each category.
+// see https://taku910.github.io/mecab/unk.html
+
+// characters of UCS2 are mapped. the others are of DEFAULT.
+const CHARS: usize = 0x10000;
+// an entry of chars has bits of its categories below PRIMARY_SHIFT and the first of them above
+const PRIMARY_SHIFT: u32 = 24;
+const KINDS: u32 = (1 << PRIMARY_SHIFT) - 1;
+// a longer run of a category is not grouped into one word, as MeCab does
+const MAX_GROUPING: usize = 24;
+
+// repr(C) to be read from mapped dictionaries in place
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[repr(C)]
+pub(crate) struct Category {
+    // 1 if unknown words are made even where the dictionary has words
+    pub(crate) invoke: u32,
+    // 1 if a run of characters of the category makes a word
+    pub(crate) group: u32,
+    // words of 1 to length characters are made
+    pub(crate) length: u32,
+    // words of the category are words[begin..end]
+    pub(crate) begin: u32,
+    pub(crate) end: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Unknown<T> {
+    // categories of each character. empty if no unknown words are made.
+    chars: Vec<u32>,
+    // DEFAULT first
+    categories: Vec<Category>,
+    words: Vec<Word<T>>,
+}
+
+impl<T> Default for Unknown<T> {
+    fn default() -> Self {
+        Unknown {
+            chars: Vec::new(),
+            categories: Vec::new(),
+            words: Vec::new(),
+        }
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// "0x3041" or "0x3041..0x309F"
+fn parse_range(src: &str) -> Option<(usize, usize)> {
+    let code = |src: &str| {
+        let code = usize::from_str_radix(src.strip_prefix("0x")?, 16).ok()?;
+        if code < CHARS {
+            Some(code)
+        } else {
+            None
+        }
+    };
+    let mut bounds = src.splitn(2, "..");
+    let lo = code(bounds.next()?)?;
+    let hi = match bounds.next() {
+        Some(hi) => code(hi)?,
+        None => lo,
+    };
+    if lo <= hi {
+        Some((lo, hi))
+    } else {
+        None
+    }
+}
+
+impl<T> Unknown<T> {
+    // unk.def is read by classifier as a dictionary of MeCab CSV, whose surfaces are categories
+    pub fn from_text<R: Read, F>(
+        char_def: &mut R,
+        unk_def: &mut R,
+        classifier: F,
+    ) -> Result<Self, io::Error>
+    where
+        F: Fn(&[&str]) -> (Vec<u8>, Word<T>),
+    {
+        let mut names: Vec<String> = Vec::new();
+        let mut categories = Vec::new();
+        let mut mappings = Vec::new();
+        for (idx, line) in io::BufReader::new(char_def).lines().enumerate() {
+            let line = line?;
+            let fields: Vec<&str> = line.split('#').next().unwrap().split_whitespace().collect();
+            let broken = || invalid(format!("char.def:{}: broken line", idx + 1));
+            match fields.as_slice() {
+                [] => (),
+                [range, kinds @ ..] if range.starts_with("0x") && !kinds.is_empty() => {
+                    let kinds: Vec<String> = kinds.iter().map(|kind| kind.to_string()).collect();
+                    mappings.push((idx, parse_range(range).ok_or_else(broken)?, kinds))
+                }
+                [name, invoke, group, length] => {
+                    let flag = |src: &str| match src {
+                        "0" => Ok(0),
+                        "1" => Ok(1),
+                        _ => Err(broken()),
+                    };
+                    names.push(String::from(*name));
+                    categories.push(Category {
+                        invoke: flag(invoke)?,
+                        group: flag(group)?,
+                        length: length.parse().map_err(|_| broken())?,
+                        begin: 0,
+                        end: 0,
+                    });
+                }
+                _ => return Err(broken()),
+            }
+        }
+        if names.len() > PRIMARY_SHIFT as usize {
+            return Err(invalid(format!(
+                "char.def: more than {} categories",
+                PRIMARY_SHIFT
+            )));
+        }
+        let default = names
+            .iter()
+            .position(|name| name == "DEFAULT")
+            .ok_or_else(|| invalid(String::from("char.def: DEFAULT is not defined")))?;
+        names[..=default].rotate_right(1);
+        categories[..=default].rotate_right(1);
+
+        let mut chars = vec![1; CHARS];
+        for (idx, (lo, hi), kinds) in mappings {
+            let mut entry = 0;
+            for (nth, kind) in kinds.iter().enumerate() {
+                let category = names.iter().position(|name| name == kind).ok_or_else(|| {
+                    invalid(format!("char.def:{}: unknown category {}", idx + 1, kind))
+                })?;
+                if nth == 0 {
+                    entry |= (category as u32) << PRIMARY_SHIFT;
+                }
+                entry |= 1 << category;
+            }
+            for ch in &mut chars[lo..=hi] {
+                *ch = entry;
+            }
+        }
+
+        let mut words = Vec::new();
+        let mut reader = io::BufReader::new(unk_def);
+        let mut buf = String::new();
+        while reader.read_line(&mut buf)? > 0 {
+            if !buf.trim().is_empty() {
+                let fields = split_by_comma(&buf);
+                let arr: Vec<&str> = fields.iter().map(|field| field.as_ref()).collect();
+                let (key, word) = classifier(&arr);
+                let category = names
+                    .iter()
+                    .position(|name| name.as_bytes() == key.as_slice())
+                    .ok_or_else(|| {
+                        invalid(format!(
+                            "unk.def: unknown category {}",
+                            String::from_utf8_lossy(&key)
+                        ))
+                    })?;
+                words.push((category, word));
+            }
+            buf.clear();
+        }
+        // stable, so that words of a category keep their order
+        words.sort_by_key(|(category, _)| *category);
+        for (idx, (category, _)) in words.iter().enumerate() {
+            let category = &mut categories[*category];
+            if category.begin == category.end {
+                category.begin = idx as u32;
+            }
+            category.end = idx as u32 + 1;
+        }
+        Ok(Unknown {
+            chars,
+            categories,
+            words: words.into_iter().map(|(_, word)| word).collect(),
+        })
+    }
+
+    pub fn view(&self) -> UnknownView<'_, T> {
+        UnknownView::new(&self.chars, &self.categories, &self.words)
+    }
+
+    pub(crate) fn raw_parts(&self) -> (&[u32], &[Category], &[Word<T>]) {
+        (&self.chars, &self.categories, &self.words)
+    }
+}
+
+// Whether chars and categories can be read by UnknownView without bounds checking,
+// where words is the number of unknown words
+pub(crate) fn in_range(chars: &[u32], categories: &[Category], words: usize) -> bool {
+    (chars.is_empty() || chars.len() == CHARS && !categories.is_empty())
+        && chars
+            .iter()
+            .all(|ch| ((ch >> PRIMARY_SHIFT) as usize) < categories.len())
+        && categories
+            .iter()
+            .all(|category| category.begin <= category.end && category.end as usize <= words)
+}
+
+// Unknown words shared by Unknown and mapped dictionaries
+pub struct UnknownView<'a, T> {
+    chars: &'a [u32],
+    categories: &'a [Category],
+    words: &'a [Word<T>],
+}
+
+// derive would require T: Clone
+impl<'a, T> Clone for UnknownView<'a, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, T> Copy for UnknownView<'a, T> {}
+
+impl<'a, T> UnknownView<'a, T> {
+    pub(crate) fn new(chars: &'a [u32], categories: &'a [Category], words: &'a [Word<T>]) -> Self {
+        UnknownView {
+            chars,
+            categories,
+            words,
+        }
+    }
+
+    // no unknown words are made
+    pub fn empty() -> Self {
+        UnknownView::new(&[], &[], &[])
+    }
+
+    // categories of the first character of input and its length.
+    // None if input does not begin with a character of UTF-8.
+    fn decode(&self, input: &[u8]) -> Option<(u32, usize)> {
+        let len = match *input.first()? {
+            0x00..=0x7f => 1,
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => return None,
+        };
+        let ch = std::str::from_utf8(input.get(..len)?)
+            .ok()?
+            .chars()
+            .next()?;
+        // DEFAULT is the first category
+        Some((self.chars.get(ch as usize).cloned().unwrap_or(1), len))
+    }
+
+    // Length of the characters at the beginning of input which share a category but DEFAULT
+    // with U+0020. MeCab skips them before each word, and %pS prints them.
+    pub fn space(&self, input: &[u8]) -> usize {
+        let space = match self.chars.get(0x20) {
+            Some(kinds) if kinds & KINDS & !1 != 0 => kinds & KINDS & !1,
+            _ => return 0,
+        };
+        let mut len = 0;
+        while let Some((kinds, n)) = self.decode(&input[len..]) {
+            if kinds & space == 0 {
+                break;
+            }
+            len += n;
+        }
+        len
+    }
+
+    // Unknown words at the beginning of input and their lengths.
+    // found tells whether the dictionary has words there.
+    pub fn search(&self, input: &[u8], found: bool, f: &mut dyn FnMut(usize, &'a [Word<T>])) {
+        if self.chars.is_empty() {
+            return;
+        }
+        let (first, len) = match self.decode(input) {
+            Some(decoded) => decoded,
+            None => return,
+        };
+        let category = &self.categories[(first >> PRIMARY_SHIFT) as usize];
+        if found && category.invoke == 0 {
+            return;
+        }
+        let words = &self.words[category.begin as usize..category.end as usize];
+        // ends[n] is the end of n + 1 characters of a category of the first
+        let mut ends = vec![len];
+        while ends.len() < std::cmp::max(MAX_GROUPING + 1, category.length as usize) {
+            let end = *ends.last().unwrap();
+            match self.decode(&input[end..]) {
+                Some((kinds, len)) if kinds & first & KINDS != 0 => ends.push(end + len),
+                _ => break,
+            }
+        }
+        let group = if category.group == 1 && ends.len() <= MAX_GROUPING {
+            ends.last().cloned()
+        } else {
+            None
+        };
+        if let Some(end) = group {
+            f(end, words);
+        }
+        for end in ends.iter().take(category.length as usize) {
+            if Some(*end) != group {
+                f(*end, words);
+            }
+        }
+        // a character makes a word where nothing else begins
+        if !found && group.is_none() && category.length == 0 {
+            f(len, words);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_unknown {
+    use super::*;
+    use crate::testutil;
+
+    const CHAR_DEF: &str = "# comment\n\
+                            DEFAULT 0 1 0\n\
+                            KANJI 0 0 2\n\
+                            KATAKANA 1 1 2\n\
+                            NUMERIC 1 1 0\n\
+                            KANJINUMERIC 1 1 0\n\
+                            0x30A1..0x30FE KATAKANA\n\
+                            0x0030..0x0039 NUMERIC\n\
+                            0x4E00..0x9FA5 KANJI\n\
+                            0x4E00 KANJINUMERIC KANJI # 一\n";
+    const UNK_DEF: &str = "DEFAULT,5,5,3274,記号,一般\n\
+                           KANJI,1,1,9361,名詞,一般\n\
+                           KATAKANA,1,1,7209,名詞,一般\n\
+                           KANJI,1,1,11659,名詞,サ変接続\n\
+                           KANJINUMERIC,1,1,16578,名詞,数\n\
+                           NUMERIC,1,1,16360,名詞,数\n";
+
+    fn unknown() -> Unknown<String> {
+        Unknown::from_text(
+            &mut CHAR_DEF.as_bytes(),
+            &mut UNK_DEF.as_bytes(),
+            testutil::classify,
+        )
+        .unwrap()
+    }
+
+    fn search<'a>(
+        unknown: &'a Unknown<String>,
+        input: &str,
+        found: bool,
+    ) -> Vec<(usize, Vec<&'a str>)> {
+        let mut result = Vec::new();
+        unknown
+            .view()
+            .search(input.as_bytes(), found, &mut |len, words| {
+                result.push((len, words.iter().map(|word| word.info.as_str()).collect()))
+            });
+        result
+    }
+
+    #[test]
+    fn test_from_text() {
+        let unknown = unknown();
+        // DEFAULT first and words in the order of unk.def in each category
+        assert_eq!(unknown.categories[0].length, 0);
+        assert_eq!(
+            search(&unknown, "東京", false),
+            vec![
+                (3, vec!["名詞,一般", "名詞,サ変接続"]),
+                (6, vec!["名詞,一般", "名詞,サ変接続"]),
+            ]
+        );
+        let err = Unknown::<String>::from_text(
+            &mut "KANJI 0 0 2\n".as_bytes(),
+            &mut UNK_DEF.as_bytes(),
+            testutil::classify,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(err.to_string(), "char.def: DEFAULT is not defined");
+        let err = Unknown::<String>::from_text(
+            &mut CHAR_DEF.as_bytes(),
+            &mut "ALPHA,1,1,8729,名詞,一般\n".as_bytes(),
+            testutil::classify,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(err.to_string(), "unk.def: unknown category ALPHA");
+        assert!(Unknown::<String>::from_text(
+            &mut "DEFAULT 0 1 0\n0x10000 DEFAULT\n".as_bytes(),
+            &mut UNK_DEF.as_bytes(),
+            testutil::classify,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_search() {
+        let unknown = unknown();
+        // a run of KATAKANA and its prefixes of 1 and 2 characters except the run itself
+        assert_eq!(
+            search(&unknown, "トキョ", true),
+            vec![
+                (9, vec!["名詞,一般"]),
+                (3, vec!["名詞,一般"]),
+                (6, vec!["名詞,一般"]),
+            ]
+        );
+        // KANJI is not invoked where the dictionary has words
+        assert!(search(&unknown, "東京", true).is_empty());
+        // 一 is KANJINUMERIC first and KANJI as well
+        assert_eq!(search(&unknown, "一", false), vec![(3, vec!["名詞,数"])]);
+        // and a run of characters sharing a category with the first is grouped
+        assert_eq!(search(&unknown, "一二", false), vec![(6, vec!["名詞,数"])]);
+        assert_eq!(search(&unknown, "12a", false), vec![(2, vec!["名詞,数"])]);
+        // DEFAULT groups a run of characters of DEFAULT
+        assert_eq!(search(&unknown, "ab1", false), vec![(2, vec!["記号,一般"])]);
+        assert!(search(&unknown, "ab", true).is_empty());
+        // outside of UCS2
+        assert_eq!(search(&unknown, "🍣", false), vec![(4, vec!["記号,一般"])]);
+        // nothing is made without char.def
+        let mut called = false;
+        UnknownView::<String>::empty().search(b"a", false, &mut |_, _| called = true);
+        assert!(!called);
+        // a run longer than MAX_GROUPING makes no grouped word
+        let long = "1".repeat(MAX_GROUPING + 1);
+        assert_eq!(search(&unknown, &long, false), vec![(1, vec!["名詞,数"])]);
+    }
+
+    #[test]
+    fn test_space() {
+        // U+0020 is only of DEFAULT, whose characters are not skipped
+        assert_eq!(unknown().view().space(b" a"), 0);
+        let unknown = Unknown::from_text(
+            &mut "DEFAULT 0 1 0\nSPACE 0 1 0\n0x0020 SPACE\n0x3000 SPACE\n".as_bytes(),
+            &mut "DEFAULT,5,5,3274,記号,一般\nSPACE,9,9,5244,記号,空白\n".as_bytes(),
+            testutil::classify,
+        )
+        .unwrap();
+        assert_eq!(unknown.view().space(" 　a ".as_bytes()), 4);
+        assert_eq!(unknown.view().space(b"a "), 0);
+        assert_eq!(unknown.view().space(b"  "), 2);
+        assert_eq!(UnknownView::<String>::empty().space(b" "), 0);
+    }
+}